@@ -17,7 +17,7 @@ db.createCollection("users", {
         name: { bsonType: "string" },
         email: { bsonType: "string" },
         password_hash: { bsonType: "string" },
-        user_type: { bsonType: "string", enum: ["customer", "provider", "seller"] },
+        user_type: { bsonType: "string", enum: ["customer", "provider", "seller", "admin"] },
         created_at: { bsonType: "date" }
       }
     }
@@ -59,6 +59,7 @@ db.createCollection("products", {
         file_url: { bsonType: "string" },
         icon: { bsonType: "string" },
         rating: { bsonType: "double" },
+        sales: { bsonType: "int" },
         downloads: { bsonType: "int" },
         created_at: { bsonType: "date" }
       }
@@ -1,8 +1,8 @@
-use actix_web::{dev::ServiceRequest, Error, HttpMessage};
-use actix_web::error::ErrorUnauthorized;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
 use serde::{Deserialize, Serialize};
 use std::env;
+use crate::models::User;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
@@ -41,19 +41,14 @@ pub fn verify_jwt(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
     Ok(token_data.claims)
 }
 
-pub fn extract_user_id(req: &ServiceRequest) -> Result<String, Error> {
-    let auth_header = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = auth_header
-        .strip_prefix("Bearer ")
-        .ok_or_else(|| ErrorUnauthorized("Invalid authorization header"))?;
-
-    let claims = verify_jwt(token)
-        .map_err(|_| ErrorUnauthorized("Invalid token"))?;
+pub async fn is_admin(db: &Database, user_id: &str) -> bool {
+    let user_oid = match ObjectId::parse_str(user_id) {
+        Ok(oid) => oid,
+        Err(_) => return false,
+    };
 
-    Ok(claims.sub)
+    matches!(
+        db.collection::<User>("users").find_one(doc! { "_id": user_oid }, None).await,
+        Ok(Some(user)) if user.user_type == "admin"
+    )
 }
@@ -0,0 +1,135 @@
+//! Packs of a seller's products sold for one price.
+
+use mongodb::{Database, bson::{doc, oid::ObjectId, Document}};
+use futures::stream::TryStreamExt;
+use std::collections::HashMap;
+use crate::fx::DisplayCurrency;
+use crate::models::{Bundle, BundleProduct, BundleResponse, Product};
+use crate::money::{Money, MoneyError};
+
+/// Checks a bundle of `products` at `price`: at least two of the seller's own
+/// products, priced in the bundle's currency, together costing at least the bundle.
+pub fn validate(seller_id: &str, price: &Money, products: &[Product]) -> Result<(), String> {
+    if products.len() < 2 {
+        return Err("A bundle needs at least two different products".to_string());
+    }
+    if products.iter().any(|p| p.seller_id != seller_id) {
+        return Err("Bundles can only include your own products".to_string());
+    }
+    if let Some(p) = products.iter().find(|p| p.price.currency != price.currency) {
+        return Err(format!("{} is priced in {}, not {}", p.title, p.price.currency, price.currency));
+    }
+
+    let total = Money::sum(&price.currency, products.iter().map(|p| &p.price)).map_err(|e| e.to_string())?;
+    if price.amount > total.amount {
+        return Err(format!("The bundle price can't be more than its products cost separately ({})", total));
+    }
+
+    Ok(())
+}
+
+/// Splits `price` across `parts` in proportion to them, rounding so the shares
+/// add up to exactly `price`.
+pub fn split(price: &Money, parts: &[Money]) -> Result<Vec<Money>, MoneyError> {
+    let total = Money::sum(&price.currency, parts)?;
+    let mut shares = Vec::with_capacity(parts.len());
+    let mut running = 0_i128;
+    let mut allocated = 0_i64;
+
+    for (i, part) in parts.iter().enumerate() {
+        running += part.amount as i128;
+        let cumulative = if total.amount == 0 {
+            // Nothing to weigh by; split evenly
+            price.amount as i128 * (i as i128 + 1) / parts.len() as i128
+        } else {
+            (price.amount as i128 * running + total.amount as i128 / 2) / total.amount as i128
+        };
+        let cumulative = i64::try_from(cumulative).map_err(|_| MoneyError::Overflow)?;
+        shares.push(Money::new(cumulative - allocated, &price.currency));
+        allocated = cumulative;
+    }
+
+    Ok(shares)
+}
+
+/// The bundle's products in the bundle's order; `None` if any was removed.
+pub async fn products_of(db: &Database, bundle: &Bundle) -> Result<Option<Vec<Product>>, mongodb::error::Error> {
+    let mut products = load_products(db, std::slice::from_ref(bundle)).await?;
+    Ok(bundle
+        .product_ids
+        .iter()
+        .map(|id| products.remove(id))
+        .collect())
+}
+
+/// Bundles matching `filter` with their products, ready to list next to products.
+/// Bundles with a removed product are left out.
+pub async fn catalog(
+    db: &Database,
+    filter: Document,
+    display: Option<&DisplayCurrency>,
+) -> Result<Vec<BundleResponse>, mongodb::error::Error> {
+    let bundles: Vec<Bundle> = db
+        .collection::<Bundle>("bundles")
+        .find(filter, None)
+        .await?
+        .try_collect()
+        .await?;
+    let products = load_products(db, &bundles).await?;
+
+    Ok(bundles
+        .into_iter()
+        .filter_map(|bundle| {
+            let included: Option<Vec<&Product>> = bundle.product_ids.iter().map(|id| products.get(id)).collect();
+            Some(bundle_response(bundle, &included?, display))
+        })
+        .collect())
+}
+
+pub fn bundle_response(bundle: Bundle, products: &[&Product], display: Option<&DisplayCurrency>) -> BundleResponse {
+    let products_total = Money::sum(&bundle.price.currency, products.iter().map(|p| &p.price))
+        .unwrap_or_else(|_| Money::zero(&bundle.price.currency));
+
+    BundleResponse {
+        id: bundle.id,
+        bundle: true,
+        display_price: display.and_then(|d| d.convert(&bundle.price)),
+        products: products
+            .iter()
+            .map(|p| BundleProduct {
+                product_id: p.id.map(|oid| oid.to_hex()).unwrap_or_default(),
+                title: p.title.clone(),
+                price: p.price.clone(),
+            })
+            .collect(),
+        products_total,
+        seller_id: bundle.seller_id,
+        title: bundle.title,
+        description: bundle.description,
+        category: bundle.category,
+        price: bundle.price,
+        icon: bundle.icon,
+        sales: bundle.sales,
+        created_at: bundle.created_at,
+    }
+}
+
+async fn load_products(db: &Database, bundles: &[Bundle]) -> Result<HashMap<String, Product>, mongodb::error::Error> {
+    let product_oids: Vec<ObjectId> = bundles
+        .iter()
+        .flat_map(|b| b.product_ids.iter())
+        .filter_map(|id| ObjectId::parse_str(id).ok())
+        .collect();
+
+    let products: Vec<Product> = db
+        .collection::<Product>("products")
+        .find(doc! { "_id": { "$in": product_oids } }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(products
+        .into_iter()
+        .filter_map(|p| p.id.map(|oid| (oid.to_hex(), p)))
+        .collect())
+}
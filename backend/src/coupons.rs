@@ -0,0 +1,282 @@
+//! Promotional codes sellers take off the price of their products.
+//!
+//! Codes are priced against a product before tax. A purchase then reserves a
+//! use of each code, counted on the coupon and per customer, and gives the use
+//! back if its payment fails.
+
+use mongodb::{Database, bson::{doc, oid::ObjectId, Document}};
+use mongodb::options::UpdateOptions;
+use futures::stream::TryStreamExt;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use crate::db::is_duplicate_key;
+use crate::handlers::niche::niche_categories;
+use crate::models::{AppliedDiscount, Coupon, Product, Purchase};
+use crate::money::{Money, MoneyError};
+
+pub const KIND_PERCENTAGE: &str = "percentage";
+pub const KIND_FIXED: &str = "fixed";
+
+#[derive(Debug)]
+pub enum CouponError {
+    Rejected(String),
+    Database(mongodb::error::Error),
+}
+
+impl fmt::Display for CouponError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CouponError::Rejected(msg) => write!(f, "{}", msg),
+            CouponError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for CouponError {
+    fn from(e: mongodb::error::Error) -> Self {
+        CouponError::Database(e)
+    }
+}
+
+impl From<MoneyError> for CouponError {
+    fn from(e: MoneyError) -> Self {
+        CouponError::Rejected(e.to_string())
+    }
+}
+
+/// Codes are matched case-insensitively and stored upper case.
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_ascii_uppercase()
+}
+
+/// The product's price after `codes`, with each coupon's share of the discount.
+///
+/// Percentage coupons are applied before fixed ones, each to what the previous
+/// left, so the result doesn't depend on the order the codes were entered in.
+pub async fn apply(
+    db: &Database,
+    product: &Product,
+    codes: &[String],
+    customer_id: &str,
+    now: DateTime<Utc>,
+) -> Result<(Money, Vec<AppliedDiscount>), CouponError> {
+    if codes.is_empty() {
+        return Ok((product.price.clone(), Vec::new()));
+    }
+
+    let mut normalized: Vec<String> = Vec::with_capacity(codes.len());
+    for code in codes.iter().map(|c| normalize_code(c)) {
+        if code.is_empty() {
+            return Err(CouponError::Rejected("Coupon codes can't be empty".to_string()));
+        }
+        if normalized.contains(&code) {
+            return Err(CouponError::Rejected(format!("Coupon {} was entered twice", code)));
+        }
+        normalized.push(code);
+    }
+
+    let found: Vec<Coupon> = db
+        .collection::<Coupon>("coupons")
+        .find(doc! { "code": { "$in": &normalized }, "active": true }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut coupons = Vec::with_capacity(normalized.len());
+    for code in &normalized {
+        let coupon = match found.iter().find(|c| &c.code == code) {
+            Some(c) => c,
+            None => return Err(CouponError::Rejected(format!("Coupon {} is not valid", code))),
+        };
+        check(coupon, product, now)?;
+        check_customer_limit(db, coupon, customer_id).await?;
+        coupons.push(coupon);
+    }
+
+    if coupons.len() > 1 {
+        if let Some(alone) = coupons.iter().find(|c| !c.stackable) {
+            return Err(CouponError::Rejected(format!("Coupon {} can't be combined with other coupons", alone.code)));
+        }
+    }
+
+    coupons.sort_by_key(|c| c.kind != KIND_PERCENTAGE);
+
+    let mut price = product.price.clone();
+    let mut discounts = Vec::with_capacity(coupons.len());
+    for coupon in coupons {
+        let amount = match (coupon.kind.as_str(), &coupon.amount_off) {
+            (KIND_PERCENTAGE, _) => price.percentage(coupon.rate.unwrap_or(0.0))?,
+            (_, Some(off)) if off.amount < price.amount => off.clone(),
+            (_, Some(_)) => price.clone(),
+            (_, None) => Money::zero(&price.currency),
+        };
+        price = price.checked_sub(&amount)?;
+        discounts.push(AppliedDiscount {
+            coupon_id: coupon.id.map(|oid| oid.to_hex()).unwrap_or_default(),
+            code: coupon.code.clone(),
+            amount,
+        });
+    }
+
+    Ok((price, discounts))
+}
+
+/// Whether the coupon can be used on `product` at `now`.
+fn check(coupon: &Coupon, product: &Product, now: DateTime<Utc>) -> Result<(), CouponError> {
+    let reject = |reason: &str| Err(CouponError::Rejected(format!("Coupon {} {}", coupon.code, reason)));
+
+    if coupon.starts_at.is_some_and(|at| now < at) {
+        return reject("is not active yet");
+    }
+    if coupon.ends_at.is_some_and(|at| now >= at) {
+        return reject("has expired");
+    }
+    if coupon.max_redemptions.is_some_and(|max| coupon.redemptions >= max) {
+        return reject("has been used up");
+    }
+
+    let product_id = product.id.map(|oid| oid.to_hex()).unwrap_or_default();
+    let in_niche = coupon
+        .niches
+        .iter()
+        .filter_map(|n| niche_categories(n))
+        .any(|categories| categories.contains(&product.category.as_str()));
+    if coupon.seller_id != product.seller_id
+        || (!coupon.product_ids.is_empty() && !coupon.product_ids.contains(&product_id))
+        || (!coupon.categories.is_empty() && !coupon.categories.contains(&product.category))
+        || (!coupon.niches.is_empty() && !in_niche)
+    {
+        return reject("doesn't apply to this product");
+    }
+
+    if let Some(off) = &coupon.amount_off {
+        if off.currency != product.price.currency {
+            return reject(&format!("only applies to prices in {}", off.currency));
+        }
+    }
+
+    Ok(())
+}
+
+async fn check_customer_limit(db: &Database, coupon: &Coupon, customer_id: &str) -> Result<(), CouponError> {
+    let (max, coupon_id) = match (coupon.max_per_customer, coupon.id) {
+        (Some(max), Some(oid)) => (max, oid.to_hex()),
+        _ => return Ok(()),
+    };
+
+    let used = db
+        .collection::<Document>("coupon_redemptions")
+        .find_one(doc! { "coupon_id": &coupon_id, "customer_id": customer_id }, None)
+        .await?
+        .and_then(|d| d.get_i32("count").ok())
+        .unwrap_or(0);
+
+    if used >= max {
+        return Err(CouponError::Rejected(format!("You have already used coupon {}", coupon.code)));
+    }
+    Ok(())
+}
+
+/// Takes a use of each coupon for the customer, all or none.
+///
+/// The limits were checked when pricing, but another purchase may have used
+/// the code since; both counters only move while they are under their limit.
+pub async fn reserve(db: &Database, customer_id: &str, discounts: &[AppliedDiscount]) -> Result<(), CouponError> {
+    for (i, discount) in discounts.iter().enumerate() {
+        if let Err(e) = reserve_one(db, customer_id, discount).await {
+            release(db, customer_id, &discounts[..i]).await;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+async fn reserve_one(db: &Database, customer_id: &str, discount: &AppliedDiscount) -> Result<(), CouponError> {
+    let coupon_oid = match ObjectId::parse_str(&discount.coupon_id) {
+        Ok(oid) => oid,
+        Err(_) => return Err(CouponError::Rejected(format!("Coupon {} is not valid", discount.code))),
+    };
+    let coupons = db.collection::<Coupon>("coupons");
+
+    let coupon = match coupons
+        .find_one_and_update(
+            doc! {
+                "_id": coupon_oid,
+                "active": true,
+                "$or": [
+                    { "max_redemptions": null },
+                    { "$expr": { "$lt": ["$redemptions", "$max_redemptions"] } }
+                ]
+            },
+            doc! { "$inc": { "redemptions": 1 } },
+            None,
+        )
+        .await?
+    {
+        Some(c) => c,
+        None => return Err(CouponError::Rejected(format!("Coupon {} has been used up", discount.code))),
+    };
+
+    // Once a customer's counter reaches the limit the filter misses it and the upsert hits the unique index
+    let mut filter = doc! { "coupon_id": &discount.coupon_id, "customer_id": customer_id };
+    if let Some(max) = coupon.max_per_customer {
+        filter.insert("count", doc! { "$lt": max });
+    }
+    let options = UpdateOptions::builder().upsert(true).build();
+    let counted = db
+        .collection::<Document>("coupon_redemptions")
+        .update_one(filter, doc! { "$inc": { "count": 1 } }, options)
+        .await;
+
+    if let Err(e) = counted {
+        let _ = coupons
+            .update_one(
+                doc! { "_id": coupon_oid, "redemptions": { "$gt": 0 } },
+                doc! { "$inc": { "redemptions": -1 } },
+                None,
+            )
+            .await;
+        if is_duplicate_key(&e) {
+            return Err(CouponError::Rejected(format!("You have already used coupon {}", discount.code)));
+        }
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Gives back the uses a purchase reserved.
+pub async fn release(db: &Database, customer_id: &str, discounts: &[AppliedDiscount]) {
+    for discount in discounts {
+        let coupon_oid = match ObjectId::parse_str(&discount.coupon_id) {
+            Ok(oid) => oid,
+            Err(_) => continue,
+        };
+
+        let released = db
+            .collection::<Coupon>("coupons")
+            .update_one(
+                doc! { "_id": coupon_oid, "redemptions": { "$gt": 0 } },
+                doc! { "$inc": { "redemptions": -1 } },
+                None,
+            )
+            .await;
+        let uncounted = db
+            .collection::<Document>("coupon_redemptions")
+            .update_one(
+                doc! { "coupon_id": &discount.coupon_id, "customer_id": customer_id, "count": { "$gt": 0 } },
+                doc! { "$inc": { "count": -1 } },
+                None,
+            )
+            .await;
+
+        if let Err(e) = released.and(uncounted) {
+            log::warn!("Failed to release coupon {}: {}", discount.code, e);
+        }
+    }
+}
+
+/// Gives back the coupon uses of a purchase whose payment failed.
+pub async fn release_purchase(db: &Database, purchase: &Purchase) {
+    release(db, &purchase.customer_id, &purchase.discounts).await;
+}
@@ -1,8 +1,18 @@
-use mongodb::{Database, IndexModel};
-use mongodb::bson::doc;
-use mongodb::options::IndexOptions;
+use mongodb::{ClientSession, Database, IndexModel};
+use mongodb::bson::{doc, Document};
+use mongodb::options::{IndexOptions, ReplaceOptions};
+use mongodb::error::{ErrorKind, WriteFailure, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use futures::future::BoxFuture;
+use futures::stream::TryStreamExt;
+use chrono::Utc;
+use std::time::Duration;
+
+const MAX_TRANSACTION_ATTEMPTS: u32 = 3;
 
 pub async fn init_db(db: &Database) -> Result<(), mongodb::error::Error> {
+    // Before anything reads amounts as `Money`
+    migrate_prices(db).await?;
+
     // Create indexes for users collection
     let users = db.collection::<crate::models::User>("users");
     let email_index = IndexModel::builder()
@@ -10,6 +20,11 @@ pub async fn init_db(db: &Database) -> Result<(), mongodb::error::Error> {
         .options(IndexOptions::builder().unique(true).build())
         .build();
     users.create_index(email_index, None).await?;
+    let calendar_token_index = IndexModel::builder()
+        .keys(doc! { "calendar_token": 1 })
+        .options(IndexOptions::builder().unique(true).sparse(true).build())
+        .build();
+    users.create_index(calendar_token_index, None).await?;
 
     // Create indexes for services collection
     let services = db.collection::<crate::models::Service>("services");
@@ -17,6 +32,7 @@ pub async fn init_db(db: &Database) -> Result<(), mongodb::error::Error> {
         IndexModel::builder().keys(doc! { "category": 1 }).build(),
         IndexModel::builder().keys(doc! { "location": 1 }).build(),
         IndexModel::builder().keys(doc! { "provider_id": 1 }).build(),
+        IndexModel::builder().keys(doc! { "weighted_rating": -1 }).build(),
     ];
     services.create_indexes(service_indexes, None).await?;
 
@@ -25,14 +41,25 @@ pub async fn init_db(db: &Database) -> Result<(), mongodb::error::Error> {
     let product_indexes = vec![
         IndexModel::builder().keys(doc! { "category": 1 }).build(),
         IndexModel::builder().keys(doc! { "seller_id": 1 }).build(),
+        IndexModel::builder().keys(doc! { "weighted_rating": -1 }).build(),
     ];
     products.create_indexes(product_indexes, None).await?;
 
+    // `downloads` used to count sales; move those counts to `sales` once
+    products
+        .update_many(
+            doc! { "sales": { "$exists": false } },
+            vec![doc! { "$set": { "sales": { "$ifNull": ["$downloads", 0] }, "downloads": 0 } }],
+            None,
+        )
+        .await?;
+
     // Create indexes for bookings collection
     let bookings = db.collection::<crate::models::Booking>("bookings");
     let booking_indexes = vec![
         IndexModel::builder().keys(doc! { "customer_id": 1 }).build(),
         IndexModel::builder().keys(doc! { "service_id": 1 }).build(),
+        IndexModel::builder().keys(doc! { "payment_intent_id": 1 }).build(),
     ];
     bookings.create_indexes(booking_indexes, None).await?;
 
@@ -41,18 +68,559 @@ pub async fn init_db(db: &Database) -> Result<(), mongodb::error::Error> {
     let purchase_indexes = vec![
         IndexModel::builder().keys(doc! { "customer_id": 1 }).build(),
         IndexModel::builder().keys(doc! { "product_id": 1 }).build(),
+        IndexModel::builder().keys(doc! { "payment_intent_id": 1 }).build(),
+        IndexModel::builder()
+            .keys(doc! { "ownership_key": 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "license_key": 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+        IndexModel::builder().keys(doc! { "order_id": 1 }).build(),
     ];
     purchases.create_indexes(purchase_indexes, None).await?;
+    backfill_ownership_keys(db).await?;
+
+    // Create indexes for carts collection
+    let carts = db.collection::<crate::models::Cart>("carts");
+    let cart_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+    ];
+    carts.create_indexes(cart_indexes, None).await?;
+
+    // Create indexes for orders collection
+    let orders = db.collection::<crate::models::Order>("orders");
+    let order_indexes = vec![
+        IndexModel::builder().keys(doc! { "customer_id": 1, "created_at": -1 }).build(),
+        IndexModel::builder().keys(doc! { "payment_intent_id": 1 }).build(),
+    ];
+    orders.create_indexes(order_indexes, None).await?;
+
+    // Create indexes for invoices collection
+    migrate_invoice_booking_index(db).await?;
+    let invoices = db.collection::<crate::models::Invoice>("invoices");
+    let invoice_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "seller_id": 1, "sequence": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder().keys(doc! { "customer_id": 1, "issued_at": -1 }).build(),
+        IndexModel::builder().keys(doc! { "order_id": 1 }).build(),
+        // A booking is invoiced once, even when its webhook and handler race
+        IndexModel::builder()
+            .keys(doc! { "booking_id": 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+    ];
+    invoices.create_indexes(invoice_indexes, None).await?;
+
+    // Create indexes for license activations collection
+    let license_activations = db.collection::<crate::models::LicenseActivation>("license_activations");
+    let license_activation_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "license_key": 1, "machine_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+    ];
+    license_activations.create_indexes(license_activation_indexes, None).await?;
 
-    // Create indexes for reviews collection
+    // Create indexes for reviews collection; each user reviews an item once
+    dedupe_reviews(db).await?;
     let reviews = db.collection::<crate::models::Review>("reviews");
     let review_indexes = vec![
         IndexModel::builder().keys(doc! { "item_id": 1, "item_type": 1 }).build(),
-        IndexModel::builder().keys(doc! { "user_id": 1 }).build(),
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1, "item_type": 1, "item_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
     ];
     reviews.create_indexes(review_indexes, None).await?;
 
+    // Services and products from before rating counters get them from their reviews
+    if crate::reviews::needs_backfill(db).await? {
+        crate::reviews::repair(db).await?;
+    }
+
+    // Create indexes for jobs collection
+    let jobs = db.collection::<crate::models::Job>("jobs");
+    let job_indexes = vec![
+        IndexModel::builder().keys(doc! { "status": 1, "run_at": 1 }).build(),
+        IndexModel::builder()
+            .keys(doc! { "dedupe_key": 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+    ];
+    jobs.create_indexes(job_indexes, None).await?;
+
+    // Create indexes for notifications collection
+    let notifications = db.collection::<crate::models::Notification>("notifications");
+    let notification_index = IndexModel::builder()
+        .keys(doc! { "user_id": 1, "created_at": -1 })
+        .build();
+    notifications.create_index(notification_index, None).await?;
+
+    // Create indexes for payment events collection (the gateway event id is the _id)
+    let payment_events = db.collection::<crate::models::PaymentEvent>("payment_events");
+    let payment_event_indexes = vec![
+        IndexModel::builder().keys(doc! { "status": 1, "received_at": 1 }).build(),
+        IndexModel::builder().keys(doc! { "payment_intent_id": 1 }).build(),
+    ];
+    payment_events.create_indexes(payment_event_indexes, None).await?;
+
+    // Create indexes for idempotency keys collection; each record expires at its
+    // own expires_at, so changing the TTL never conflicts with the index
+    migrate_idempotency_expiry(db).await?;
+    let idempotency_keys = db.collection::<crate::models::IdempotencyRecord>("idempotency_keys");
+    let idempotency_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1, "key": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+            .build(),
+    ];
+    idempotency_keys.create_indexes(idempotency_indexes, None).await?;
+
+    // Create indexes for download events collection
+    let download_events = db.collection::<crate::models::DownloadEvent>("download_events");
+    let download_event_indexes = vec![
+        IndexModel::builder().keys(doc! { "purchase_id": 1 }).build(),
+        IndexModel::builder().keys(doc! { "product_id": 1, "created_at": -1 }).build(),
+    ];
+    download_events.create_indexes(download_event_indexes, None).await?;
+
+    // Create indexes for product versions collection
+    let product_versions = db.collection::<crate::models::ProductVersion>("product_versions");
+    let product_version_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "product_id": 1, "version": -1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+    ];
+    product_versions.create_indexes(product_version_indexes, None).await?;
+
+    // Create indexes for uploaded files collection
+    let files = db.collection::<crate::models::StoredFile>("files");
+    let file_indexes = vec![
+        IndexModel::builder().keys(doc! { "owner_id": 1, "created_at": -1 }).build(),
+        IndexModel::builder().keys(doc! { "sha256": 1 }).build(),
+    ];
+    files.create_indexes(file_indexes, None).await?;
+
+    // Create indexes for refunds collection; a purchase or booking has at most one open request
+    let refunds = db.collection::<crate::models::Refund>("refunds");
+    let refund_indexes = vec![
+        IndexModel::builder().keys(doc! { "customer_id": 1, "created_at": -1 }).build(),
+        IndexModel::builder().keys(doc! { "seller_id": 1, "status": 1 }).build(),
+        IndexModel::builder()
+            .keys(doc! { "kind": 1, "subject_id": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(doc! { "status": "requested" })
+                    .name("one_open_request".to_string())
+                    .build(),
+            )
+            .build(),
+    ];
+    refunds.create_indexes(refund_indexes, None).await?;
+
+    // Create indexes for ledger collection; each sale, refund or payout is posted once
+    let ledger = db.collection::<crate::models::LedgerTransaction>("ledger");
+    let ledger_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "kind": 1, "reference_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder().keys(doc! { "entries.account": 1, "created_at": 1 }).build(),
+    ];
+    ledger.create_indexes(ledger_indexes, None).await?;
+
+    // Purchases completed before the ledger existed still count towards seller balances
+    let backfilled = crate::ledger::backfill_sales(db).await?;
+    if backfilled > 0 {
+        log::info!("Posted {} sales missing from the ledger", backfilled);
+    }
+
+    // Create indexes for payouts collection; a batch pays each seller once per currency
+    let payouts = db.collection::<crate::models::Payout>("payouts");
+    let payout_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "batch_id": 1, "seller_id": 1, "amount.currency": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder().keys(doc! { "seller_id": 1, "created_at": -1 }).build(),
+        IndexModel::builder().keys(doc! { "status": 1 }).build(),
+    ];
+    payouts.create_indexes(payout_indexes, None).await?;
+
+    // Create indexes for bundles collection
+    let bundles = db.collection::<crate::models::Bundle>("bundles");
+    let bundle_indexes = vec![
+        IndexModel::builder().keys(doc! { "category": 1 }).build(),
+        IndexModel::builder().keys(doc! { "seller_id": 1 }).build(),
+    ];
+    bundles.create_indexes(bundle_indexes, None).await?;
+
+    // Create indexes for coupons collection; codes are unique across sellers
+    let coupons = db.collection::<crate::models::Coupon>("coupons");
+    let coupon_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "code": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder().keys(doc! { "seller_id": 1, "created_at": -1 }).build(),
+    ];
+    coupons.create_indexes(coupon_indexes, None).await?;
+
+    // One usage counter per coupon and customer
+    let coupon_redemptions = db.collection::<Document>("coupon_redemptions");
+    coupon_redemptions
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "coupon_id": 1, "customer_id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+
+    // Create indexes for subscription plans collection
+    let subscription_plans = db.collection::<crate::models::SubscriptionPlan>("subscription_plans");
+    subscription_plans
+        .create_index(IndexModel::builder().keys(doc! { "seller_id": 1, "created_at": -1 }).build(), None)
+        .await?;
+
+    // Create indexes for subscriptions collection; live_key allows one live subscription per plan
+    let subscriptions = db.collection::<crate::models::Subscription>("subscriptions");
+    let subscription_indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "live_key": 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+        IndexModel::builder().keys(doc! { "customer_id": 1, "status": 1 }).build(),
+    ];
+    subscriptions.create_indexes(subscription_indexes, None).await?;
+
+    // period_key allows one charge per billing period that hasn't failed
+    let subscription_charges = db.collection::<crate::models::SubscriptionCharge>("subscription_charges");
+    let subscription_charge_indexes = vec![
+        IndexModel::builder().keys(doc! { "subscription_id": 1, "created_at": -1 }).build(),
+        IndexModel::builder()
+            .keys(doc! { "period_key": 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+    ];
+    subscription_charges.create_indexes(subscription_charge_indexes, None).await?;
+
     println!("✅ Database indexes created successfully");
 
     Ok(())
 }
+
+/// Keeps only the newest review from each user for each item, so the unique
+/// index can be built over reviews written before it existed. Older duplicates
+/// are moved to `reviews_archive` with the ID of the review that superseded them.
+async fn dedupe_reviews(db: &Database) -> Result<(), mongodb::error::Error> {
+    let reviews = db.collection::<Document>("reviews");
+    let archive = db.collection::<Document>("reviews_archive");
+    let pipeline = vec![
+        doc! { "$sort": { "created_at": -1 } },
+        doc! { "$group": {
+            "_id": { "user_id": "$user_id", "item_type": "$item_type", "item_id": "$item_id" },
+            "ids": { "$push": "$_id" },
+        } },
+        doc! { "$match": { "ids.1": { "$exists": true } } },
+    ];
+    let duplicates: Vec<Document> = reviews.aggregate(pipeline, None).await?.try_collect().await?;
+    let mut archived = 0;
+
+    for duplicate in duplicates {
+        let ids = duplicate.get_array("ids").cloned().unwrap_or_default();
+        let superseded: Vec<Document> = reviews
+            .find(doc! { "_id": { "$in": &ids[1..] } }, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        for mut review in superseded {
+            let id = match review.get("_id") {
+                Some(id) => id.clone(),
+                None => continue,
+            };
+            review.insert("superseded_by", ids[0].clone());
+            review.insert("archived_at", Utc::now());
+
+            // Upserting keeps a rerun after an interrupted start from failing on the archived copy
+            archive
+                .replace_one(doc! { "_id": &id }, review, ReplaceOptions::builder().upsert(true).build())
+                .await?;
+            reviews.delete_one(doc! { "_id": &id }, None).await?;
+            archived += 1;
+        }
+
+        if let Ok(key) = duplicate.get_document("_id") {
+            if let (Ok(item_type), Ok(item_id)) = (key.get_str("item_type"), key.get_str("item_id")) {
+                crate::reviews::rebuild(db, item_type, item_id).await?;
+            }
+        }
+    }
+
+    if archived > 0 {
+        log::info!("Archived {} duplicate reviews to reviews_archive", archived);
+    }
+
+    Ok(())
+}
+
+/// Gives purchases completed before ownership keys existed their key, so their
+/// customers can't buy the same product again. Where a customer bought a product
+/// more than once, only the first purchase gets it; the unique index skips any
+/// pair that already has a live purchase.
+async fn backfill_ownership_keys(db: &Database) -> Result<(), mongodb::error::Error> {
+    let repurchasable: Vec<String> = db
+        .collection::<Document>("products")
+        .distinct("_id", doc! { "allow_repurchase": true }, None)
+        .await?
+        .into_iter()
+        .filter_map(|v| v.as_object_id().map(|oid| oid.to_hex()))
+        .collect();
+
+    let purchases = db.collection::<Document>("purchases");
+    let pipeline = vec![
+        doc! { "$match": {
+            "status": "completed",
+            "ownership_key": { "$exists": false },
+            "product_id": { "$nin": repurchasable }
+        } },
+        doc! { "$sort": { "created_at": 1 } },
+        doc! { "$group": {
+            "_id": { "customer_id": "$customer_id", "product_id": "$product_id" },
+            "first": { "$first": "$_id" }
+        } },
+    ];
+    let owners: Vec<Document> = purchases.aggregate(pipeline, None).await?.try_collect().await?;
+
+    let mut backfilled = 0;
+    for owner in owners {
+        let (id, first) = match (owner.get_document("_id"), owner.get("first")) {
+            (Ok(id), Some(first)) => (id, first.clone()),
+            _ => continue,
+        };
+        let key = format!(
+            "{}:{}",
+            id.get_str("customer_id").unwrap_or_default(),
+            id.get_str("product_id").unwrap_or_default()
+        );
+        match purchases
+            .update_one(doc! { "_id": first }, doc! { "$set": { "ownership_key": key } }, None)
+            .await
+        {
+            Ok(_) => backfilled += 1,
+            Err(e) if is_duplicate_key(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if backfilled > 0 {
+        log::info!("Set the ownership key of {} earlier purchases", backfilled);
+    }
+
+    Ok(())
+}
+
+/// Unsets the `booking_id: null` stored on purchase invoices and drops the
+/// non-unique `booking_id` index, so the unique sparse one can replace it.
+async fn migrate_invoice_booking_index(db: &Database) -> Result<(), mongodb::error::Error> {
+    let invoices = db.collection::<Document>("invoices");
+    invoices
+        .update_many(doc! { "booking_id": null }, doc! { "$unset": { "booking_id": "" } }, None)
+        .await?;
+
+    let indexes: Vec<IndexModel> = match invoices.list_indexes(None).await {
+        Ok(cursor) => cursor.try_collect().await?,
+        // Nothing was ever invoiced (NamespaceNotFound)
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == 26) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let outdated = indexes.iter().any(|index| {
+        let options = index.options.as_ref();
+        options.and_then(|o| o.name.as_deref()) == Some("booking_id_1") && options.and_then(|o| o.unique) != Some(true)
+    });
+    if outdated {
+        invoices.drop_index("booking_id_1", None).await?;
+    }
+
+    Ok(())
+}
+
+/// Gives idempotency records stored before per-record expiry an `expires_at`
+/// from the current TTL, and drops the TTL index on `created_at` they expired by.
+async fn migrate_idempotency_expiry(db: &Database) -> Result<(), mongodb::error::Error> {
+    let idempotency_keys = db.collection::<Document>("idempotency_keys");
+    let ttl_ms = crate::idempotency::ttl().num_milliseconds();
+
+    idempotency_keys
+        .update_many(
+            doc! { "expires_at": { "$exists": false } },
+            vec![doc! { "$set": { "expires_at": { "$add": ["$created_at", ttl_ms] } } }],
+            None,
+        )
+        .await?;
+
+    match idempotency_keys.drop_index("created_at_1", None).await {
+        Ok(()) => {}
+        // Already dropped (IndexNotFound), or nothing was ever stored (NamespaceNotFound)
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == 26 || c.code == 27) => {}
+        Err(e) => return Err(e),
+    }
+
+    Ok(())
+}
+
+/// Rewrites amounts stored as plain numbers of major units into `Money`
+/// documents in `DEFAULT_CURRENCY`. Already-converted values are left alone,
+/// so this is safe to run on every start.
+async fn migrate_prices(db: &Database) -> Result<(), mongodb::error::Error> {
+    let number = doc! { "$type": "number" };
+
+    for (collection, field) in [("services", "price"), ("products", "price"), ("purchases", "amount"), ("orders", "total")] {
+        db.collection::<Document>(collection)
+            .update_many(
+                doc! { field: number.clone() },
+                vec![doc! { "$set": { field: to_money(&format!("${}", field)) } }],
+                None,
+            )
+            .await?;
+    }
+
+    // Cart and order items carry a price each
+    for collection in ["carts", "orders"] {
+        db.collection::<Document>(collection)
+            .update_many(
+                doc! { "items.price": number.clone() },
+                vec![doc! { "$set": { "items": {
+                    "$map": {
+                        "input": "$items",
+                        "as": "item",
+                        "in": { "$mergeObjects": ["$$item", { "price": to_money("$$item.price") }] }
+                    }
+                } } }],
+                None,
+            )
+            .await?;
+    }
+
+    // Invoices kept a single `currency` field, which always held the default
+    db.collection::<Document>("invoices")
+        .update_many(
+            doc! { "total": number },
+            vec![
+                doc! { "$set": {
+                    "subtotal": to_money("$subtotal"),
+                    "tax_total": to_money("$tax_total"),
+                    "total": to_money("$total"),
+                    "lines": {
+                        "$map": {
+                            "input": "$lines",
+                            "as": "line",
+                            "in": { "$mergeObjects": ["$$line", {
+                                "unit_price": to_money("$$line.unit_price"),
+                                "tax_amount": to_money("$$line.tax_amount"),
+                                "amount": to_money("$$line.amount")
+                            }] }
+                        }
+                    }
+                } },
+                doc! { "$unset": "currency" },
+            ],
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
+
+// Aggregation expression turning a number of major units into a Money document
+fn to_money(field: &str) -> Document {
+    let currency = crate::money::default_currency();
+    let exponent = crate::money::exponent(&currency);
+    doc! {
+        "$cond": [
+            { "$isNumber": field },
+            {
+                "amount": { "$toLong": { "$round": [{ "$multiply": [field, 10_i64.pow(exponent)] }, 0] } },
+                "currency": currency
+            },
+            field
+        ]
+    }
+}
+
+/// True when a write failed because it violated a unique index.
+pub fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    match error.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == 11000,
+        // insert_many reports per-document failures
+        ErrorKind::BulkWrite(failure) => failure
+            .write_errors
+            .as_ref()
+            .is_some_and(|errors| errors.iter().any(|e| e.code == 11000)),
+        _ => false,
+    }
+}
+
+/// Runs `body` inside a multi-document transaction and commits it.
+///
+/// The whole body is retried when the server labels the failure as a transient
+/// transaction error, and the commit is retried when its outcome is unknown.
+/// Transactions require MongoDB to run as a replica set.
+pub async fn with_transaction<T, F>(db: &Database, mut body: F) -> Result<T, mongodb::error::Error>
+where
+    F: for<'s> FnMut(&'s mut ClientSession) -> BoxFuture<'s, Result<T, mongodb::error::Error>>,
+{
+    // Database doesn't expose its client, but every collection handle does
+    let client = db.collection::<Document>("transactions").client().clone();
+    let mut session = client.start_session(None).await?;
+    let mut attempt = 1;
+
+    loop {
+        session.start_transaction(None).await?;
+
+        let value = match body(&mut session).await {
+            Ok(value) => value,
+            Err(e) => {
+                let _ = session.abort_transaction().await;
+                if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS {
+                    attempt += 1;
+                    continue;
+                }
+                return Err(e);
+            }
+        };
+
+        let mut commit_attempt = 1;
+        let committed = loop {
+            match session.commit_transaction().await {
+                Ok(()) => break Ok(()),
+                Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && commit_attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    commit_attempt += 1;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        match committed {
+            Ok(()) => return Ok(value),
+            Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Booking payments held on the customer's behalf until the service is delivered.
+//!
+//! A paid booking's money stays in the ledger's escrow account. Completing the
+//! booking opens a dispute window (`BOOKING_DISPUTE_WINDOW_HOURS`) during which
+//! the customer can ask for a refund; after it the rest goes to the provider.
+//! Cancelling refunds what the service's cancellation policy allows and passes
+//! the remainder on as a cancellation fee.
+
+use mongodb::{Database, bson::{doc, oid::ObjectId, Document}};
+use futures::stream::TryStreamExt;
+use chrono::{DateTime, Duration, Utc};
+use std::env;
+use crate::db::with_transaction;
+use crate::ledger;
+use crate::models::{Booking, Notification, Refund, Service};
+use crate::money::{Money, MoneyError};
+use crate::payments::PaymentGateway;
+use crate::refunds::{self, RefundError, KIND_BOOKING};
+
+pub const CANCELLATION_POLICIES: [&str; 3] = ["flexible", "moderate", "strict"];
+
+/// Share of the payment refunded when the customer cancels `notice` before the start:
+/// - flexible: everything up to 24 hours before, half after that
+/// - moderate: everything up to 5 days before, half up to 24 hours before
+/// - strict: half up to 7 days before
+pub fn cancellation_refund_rate(policy: &str, notice: Duration) -> f64 {
+    let hours = notice.num_hours();
+    match policy {
+        "flexible" if hours >= 24 => 1.0,
+        "flexible" => 0.5,
+        "strict" if hours >= 7 * 24 => 0.5,
+        "strict" => 0.0,
+        _ if hours >= 5 * 24 => 1.0,
+        _ if hours >= 24 => 0.5,
+        _ => 0.0,
+    }
+}
+
+/// How long customers can dispute a completed booking before the provider is paid.
+pub fn dispute_window() -> Duration {
+    let hours = env::var("BOOKING_DISPUTE_WINDOW_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(72);
+    Duration::hours(hours)
+}
+
+/// The total that will have been refunded once a cancellation is settled.
+///
+/// Providers cancelling, and customers cancelling before the provider confirmed,
+/// get everything back; otherwise the service's policy applies.
+pub fn cancellation_refund(
+    booking: &Booking,
+    service: &Service,
+    by_provider: bool,
+    now: DateTime<Utc>,
+) -> Result<Option<Money>, MoneyError> {
+    let amount = match (&booking.amount, booking.escrow_status.as_deref()) {
+        (Some(amount), Some("held")) => amount,
+        _ => return Ok(None),
+    };
+    let refunded = booking.refunded_amount.clone().unwrap_or_else(|| Money::zero(&amount.currency));
+
+    let rate = if by_provider || booking.status == "pending" {
+        1.0
+    } else {
+        match booking.starts_at_utc() {
+            Some(start) => cancellation_refund_rate(&service.cancellation_policy, start - now),
+            None => 1.0,
+        }
+    };
+
+    let share = amount.checked_sub(&refunded)?.percentage(rate)?;
+    Ok(Some(refunded.checked_add(&share)?))
+}
+
+/// Refunds a cancelled or expired booking up to its `cancellation_refund` and
+/// releases the rest. Safe to call again until it succeeds.
+pub async fn settle_cancellation(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    booking_oid: ObjectId,
+    actor: &str,
+) -> Result<(), RefundError> {
+    let booking = db
+        .collection::<Booking>("bookings")
+        .find_one(doc! { "_id": booking_oid, "escrow_status": "held" }, None)
+        .await?;
+    let booking = match booking {
+        Some(b) => b,
+        None => return Ok(()),
+    };
+
+    if let (Some(target), Some(amount)) = (&booking.cancellation_refund, &booking.amount) {
+        let refunded = booking.refunded_amount.clone().unwrap_or_else(|| Money::zero(&amount.currency));
+        let outstanding = target.checked_sub(&refunded)?;
+
+        if outstanding.amount > 0 {
+            let charge = refunds::load_charge(db, KIND_BOOKING, &booking_oid.to_hex()).await?;
+            let reason = format!("Booking {}", booking.status);
+            refunds::issue(db, gateway, &charge, Some(outstanding.amount), &reason, actor).await?;
+        }
+    }
+
+    release(db, booking_oid).await?;
+    Ok(())
+}
+
+/// Passes whatever the customer hasn't been refunded on to the provider.
+///
+/// Waits while a refund of the booking is requested or being processed, so a
+/// customer's dispute holds the payment until the provider or an admin decides.
+pub async fn release(db: &Database, booking_oid: ObjectId) -> Result<bool, mongodb::error::Error> {
+    let booking = match db
+        .collection::<Booking>("bookings")
+        .find_one(doc! { "_id": booking_oid, "escrow_status": "held" }, None)
+        .await?
+    {
+        Some(b) => b,
+        None => return Ok(false),
+    };
+    let service = match ObjectId::parse_str(&booking.service_id) {
+        Ok(oid) => db.collection::<Service>("services").find_one(doc! { "_id": oid }, None).await?,
+        Err(_) => None,
+    };
+    let service = match service {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+
+    let db_handle = db.clone();
+    let booking_id = booking_oid.to_hex();
+    let provider_id = service.provider_id.clone();
+    let category = service.category.clone();
+
+    let released = with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let booking_id = booking_id.clone();
+        let provider_id = provider_id.clone();
+        let category = category.clone();
+        Box::pin(async move {
+            let open_refund = db
+                .collection::<Refund>("refunds")
+                .find_one_with_session(
+                    doc! { "kind": KIND_BOOKING, "subject_id": &booking_id, "status": { "$in": ["requested", "processing"] } },
+                    None,
+                    &mut *session,
+                )
+                .await?;
+            if open_refund.is_some() {
+                return Ok(None);
+            }
+
+            // Reading the booking in the transaction makes a concurrent refund conflict with the release
+            let booking = match db
+                .collection::<Booking>("bookings")
+                .find_one_with_session(
+                    doc! { "_id": booking_oid, "escrow_status": "held", "payment_status": "paid" },
+                    None,
+                    &mut *session,
+                )
+                .await?
+            {
+                Some(b) => b,
+                None => return Ok(None),
+            };
+            let amount = match &booking.amount {
+                Some(a) => a.clone(),
+                None => return Ok(None),
+            };
+            let refunded = booking.refunded_amount.clone().unwrap_or_else(|| Money::zero(&amount.currency));
+            let remaining = amount.checked_sub(&refunded)?;
+
+            let released_amount = match bson::to_bson(&remaining) {
+                Ok(b) => b,
+                Err(e) => return Err(mongodb::error::Error::custom(e.to_string())),
+            };
+            let escrow_status = if remaining.amount > 0 { "released" } else { "refunded" };
+            db.collection::<Document>("bookings")
+                .update_one_with_session(
+                    doc! { "_id": booking_oid, "escrow_status": "held" },
+                    doc! { "$set": { "escrow_status": escrow_status, "released_amount": released_amount } },
+                    None,
+                    &mut *session,
+                )
+                .await?;
+
+            if remaining.amount > 0 {
+                ledger::record_escrow_release(&db, session, &booking_id, &provider_id, &category, &remaining).await?;
+            }
+
+            Ok(Some(remaining))
+        })
+    })
+    .await?;
+
+    let remaining = match released {
+        Some(r) => r,
+        None => return Ok(false),
+    };
+
+    if remaining.amount > 0 {
+        let notification = Notification {
+            id: None,
+            user_id: service.provider_id,
+            kind: "escrow_released".to_string(),
+            message: format!("{} for {} on {} has been added to your balance", remaining, service.title, booking.booking_date),
+            reference_id: Some(booking_oid.to_hex()),
+            read: false,
+            created_at: Utc::now(),
+        };
+        if let Err(e) = db.collection::<Notification>("notifications").insert_one(notification, None).await {
+            log::warn!("Failed to send escrow_released notification: {}", e);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Releases completed bookings past their dispute window and settles cancelled
+/// and expired ones whose refund didn't go through.
+pub async fn run_due(db: &Database, gateway: &dyn PaymentGateway) -> Result<(), mongodb::error::Error> {
+    let bookings = db.collection::<Booking>("bookings");
+
+    let due: Vec<Booking> = bookings
+        .find(
+            doc! { "escrow_status": "held", "status": "completed", "release_at": { "$lte": Utc::now() } },
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+    for booking in due {
+        if let Some(booking_oid) = booking.id {
+            release(db, booking_oid).await?;
+        }
+    }
+
+    let ended: Vec<Booking> = bookings
+        .find(doc! { "escrow_status": "held", "status": { "$in": ["cancelled", "expired"] } }, None)
+        .await?
+        .try_collect()
+        .await?;
+    for booking in ended {
+        if let Some(booking_oid) = booking.id {
+            if let Err(e) = settle_cancellation(db, gateway, booking_oid, "system").await {
+                log::warn!("Failed to settle booking {}: {}", booking_oid.to_hex(), e);
+            }
+        }
+    }
+
+    Ok(())
+}
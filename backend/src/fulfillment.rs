@@ -0,0 +1,290 @@
+use mongodb::Database;
+use mongodb::bson::{doc, oid::ObjectId};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use chrono::{Duration, Utc};
+use futures::stream::TryStreamExt;
+use crate::db::with_transaction;
+use crate::invoices;
+use crate::ledger;
+use crate::models::{Booking, Bundle, DownloadEvent, Order, Product, ProductVersion, Purchase};
+
+/// Marks a paid purchase as completed and counts the sale in one transaction.
+///
+/// Returns `false` when the purchase is no longer in one of the `from` statuses,
+/// e.g. because a webhook already completed it.
+pub async fn complete_purchase(db: &Database, purchase_oid: ObjectId, from: &[&str]) -> Result<bool, mongodb::error::Error> {
+    let from: Vec<String> = from.iter().map(|s| s.to_string()).collect();
+    let db_handle = db.clone();
+
+    with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let from = from.clone();
+        Box::pin(async move {
+            let purchases = db.collection::<Purchase>("purchases");
+
+            let purchase = match purchases
+                .find_one_with_session(doc! { "_id": purchase_oid, "status": { "$in": &from } }, None, session)
+                .await?
+            {
+                Some(p) => p,
+                None => return Ok(false),
+            };
+
+            purchases
+                .update_one_with_session(
+                    doc! { "_id": purchase_oid, "status": &purchase.status },
+                    doc! { "$set": { "status": "completed" } },
+                    None,
+                    session,
+                )
+                .await?;
+
+            if let Ok(product_oid) = ObjectId::parse_str(&purchase.product_id) {
+                db.collection::<Product>("products")
+                    .update_one_with_session(
+                        doc! { "_id": product_oid },
+                        doc! { "$inc": { "sales": 1 } },
+                        None,
+                        session,
+                    )
+                    .await?;
+            }
+
+            ledger::record_sales(&db, session, std::slice::from_ref(&purchase)).await?;
+
+            // Order purchases are invoiced together by `complete_order`
+            if purchase.order_id.is_none() {
+                invoices::issue_for_purchases(&db, session, &[purchase], None).await?;
+            }
+
+            Ok(true)
+        })
+    })
+    .await
+}
+
+/// Marks a captured booking payment as paid, puts it in escrow until the
+/// booking is completed and invoices it, in one transaction.
+///
+/// Returns `false` when the payment is no longer in one of the `from` statuses.
+pub async fn complete_booking_payment(db: &Database, booking_oid: ObjectId, from: &[&str]) -> Result<bool, mongodb::error::Error> {
+    let from: Vec<String> = from.iter().map(|s| s.to_string()).collect();
+    let db_handle = db.clone();
+
+    with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let from = from.clone();
+        Box::pin(async move {
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let booking = match db
+                .collection::<Booking>("bookings")
+                .find_one_and_update_with_session(
+                    doc! { "_id": booking_oid, "payment_status": { "$in": &from } },
+                    doc! { "$set": { "payment_status": "paid", "escrow_status": "held" } },
+                    options,
+                    &mut *session,
+                )
+                .await?
+            {
+                Some(b) => b,
+                None => return Ok(false),
+            };
+
+            if let Some(amount) = &booking.amount {
+                ledger::record_escrow_hold(&db, session, &booking_oid.to_hex(), amount).await?;
+            }
+            invoices::issue_for_booking(&db, session, &booking).await?;
+
+            Ok(true)
+        })
+    })
+    .await
+}
+
+/// Completes a paid order and all of its purchases in one transaction.
+///
+/// Returns `false` when the order is no longer in one of the `from` statuses.
+pub async fn complete_order(db: &Database, order_oid: ObjectId, from: &[&str]) -> Result<bool, mongodb::error::Error> {
+    let from: Vec<String> = from.iter().map(|s| s.to_string()).collect();
+    let db_handle = db.clone();
+
+    with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let from = from.clone();
+        Box::pin(async move {
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let order = match db
+                .collection::<Order>("orders")
+                .find_one_and_update_with_session(
+                    doc! { "_id": order_oid, "status": { "$in": &from } },
+                    doc! { "$set": { "status": "completed" } },
+                    options,
+                    session,
+                )
+                .await?
+            {
+                Some(o) => o,
+                None => return Ok(false),
+            };
+
+            let purchases = db.collection::<Purchase>("purchases");
+            let mut cursor = purchases
+                .find_with_session(
+                    doc! { "order_id": order_oid.to_hex(), "status": { "$in": &from } },
+                    None,
+                    session,
+                )
+                .await?;
+            let mut completed = Vec::new();
+            while let Some(purchase) = cursor.next(session).await.transpose()? {
+                completed.push(purchase);
+            }
+
+            purchases
+                .update_many_with_session(
+                    doc! { "order_id": order_oid.to_hex(), "status": { "$in": &from } },
+                    doc! { "$set": { "status": "completed" } },
+                    None,
+                    session,
+                )
+                .await?;
+
+            let product_oids: Vec<ObjectId> = completed
+                .iter()
+                .filter_map(|p| ObjectId::parse_str(&p.product_id).ok())
+                .collect();
+            db.collection::<Product>("products")
+                .update_many_with_session(
+                    doc! { "_id": { "$in": product_oids } },
+                    doc! { "$inc": { "sales": 1 } },
+                    None,
+                    session,
+                )
+                .await?;
+
+            if let Some(bundle_oid) = order.bundle_id.as_deref().and_then(|id| ObjectId::parse_str(id).ok()) {
+                db.collection::<Bundle>("bundles")
+                    .update_one_with_session(
+                        doc! { "_id": bundle_oid },
+                        doc! { "$inc": { "sales": 1 } },
+                        None,
+                        session,
+                    )
+                    .await?;
+            }
+
+            ledger::record_sales(&db, session, &completed).await?;
+            invoices::issue_for_purchases(&db, session, &completed, Some(&order)).await?;
+
+            Ok(true)
+        })
+    })
+    .await
+}
+
+/// Counts a download against the purchase's limit, logs it and bumps the
+/// product's download counter. Returns `false` once the limit is used up.
+pub async fn record_download(db: &Database, purchase: &Purchase) -> Result<bool, mongodb::error::Error> {
+    let event = DownloadEvent {
+        id: None,
+        purchase_id: purchase.id.map(|oid| oid.to_hex()).unwrap_or_default(),
+        product_id: purchase.product_id.clone(),
+        customer_id: purchase.customer_id.clone(),
+        created_at: Utc::now(),
+    };
+    let db_handle = db.clone();
+
+    with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let event = event.clone();
+        Box::pin(async move {
+            let purchase_oid = ObjectId::parse_str(&event.purchase_id).ok();
+            let counted = db
+                .collection::<Purchase>("purchases")
+                .update_one_with_session(
+                    doc! {
+                        "_id": purchase_oid,
+                        "status": "completed",
+                        "$or": [
+                            { "download_limit": null },
+                            { "$expr": { "$lt": ["$download_count", "$download_limit"] } },
+                        ]
+                    },
+                    doc! { "$inc": { "download_count": 1 } },
+                    None,
+                    session,
+                )
+                .await?;
+
+            if counted.modified_count == 0 {
+                return Ok(false);
+            }
+
+            db.collection::<DownloadEvent>("download_events")
+                .insert_one_with_session(&event, None, session)
+                .await?;
+
+            if let Ok(product_oid) = ObjectId::parse_str(&event.product_id) {
+                db.collection::<Product>("products")
+                    .update_one_with_session(
+                        doc! { "_id": product_oid },
+                        doc! { "$inc": { "downloads": 1 } },
+                        None,
+                        session,
+                    )
+                    .await?;
+            }
+
+            Ok(true)
+        })
+    })
+    .await
+}
+
+/// Whether `purchase` gives access to `version` under the product's update policy.
+pub fn entitles(product: &Product, purchase: &Purchase, version: &ProductVersion) -> bool {
+    // Purchases from before versioning bought the first version
+    let purchased_version = purchase.version.unwrap_or(1);
+    if version.version <= purchased_version {
+        return true;
+    }
+
+    match product.update_policy.as_str() {
+        "none" => false,
+        "window" => {
+            let days = product.update_window_days.unwrap_or(0) as i64;
+            version.released_at <= purchase.created_at + Duration::days(days)
+        }
+        _ => true,
+    }
+}
+
+/// The newest version of the product that `purchase` is entitled to.
+pub async fn entitled_version(
+    db: &Database,
+    product: &Product,
+    purchase: &Purchase,
+) -> Result<Option<ProductVersion>, mongodb::error::Error> {
+    let product_id = product.id.map(|oid| oid.to_hex()).unwrap_or_default();
+    let options = FindOptions::builder().sort(doc! { "version": -1 }).build();
+    let versions: Vec<ProductVersion> = db
+        .collection::<ProductVersion>("product_versions")
+        .find(doc! { "product_id": product_id }, options)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(versions.into_iter().find(|v| entitles(product, purchase, v)))
+}
+
+/// The product's latest version, which subscribers always get.
+pub async fn current_version(db: &Database, product: &Product) -> Result<Option<ProductVersion>, mongodb::error::Error> {
+    let product_id = product.id.map(|oid| oid.to_hex()).unwrap_or_default();
+    db.collection::<ProductVersion>("product_versions")
+        .find_one(doc! { "product_id": product_id, "version": product.current_version }, None)
+        .await
+}
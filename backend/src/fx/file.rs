@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+use std::path::PathBuf;
+use super::{FxError, RateProvider, RateTable};
+
+/// Reads `{ "base": "USD", "rates": { "EUR": 0.92, ... } }` from a JSON file,
+/// so rates can be updated by a cron job without restarting the server.
+pub struct FileRates {
+    path: PathBuf,
+}
+
+impl FileRates {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileRates { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl RateProvider for FileRates {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn fetch(&self) -> Result<RateTable, FxError> {
+        let data = tokio::fs::read(&self.path)
+            .await
+            .map_err(|e| FxError::Unavailable(format!("{}: {}", self.path.display(), e)))?;
+
+        let mut table: RateTable = serde_json::from_slice(&data)
+            .map_err(|e| FxError::Unavailable(format!("{}: {}", self.path.display(), e)))?;
+
+        table.base = table.base.to_uppercase();
+        table.rates = table.rates.into_iter().map(|(c, r)| (c.to_uppercase(), r)).collect();
+        Ok(table)
+    }
+}
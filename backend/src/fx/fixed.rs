@@ -0,0 +1,44 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::env;
+use crate::money::default_currency;
+use super::{FxError, RateProvider, RateTable};
+
+/// Rates configured in the environment, for development and offline use:
+/// `FX_STATIC_RATES=EUR=0.92,GBP=0.79` per one `DEFAULT_CURRENCY`.
+pub struct StaticRates {
+    table: RateTable,
+}
+
+impl StaticRates {
+    pub fn from_env() -> Self {
+        let rates = env::var("FX_STATIC_RATES").unwrap_or_default();
+        StaticRates::new(&default_currency(), &rates)
+    }
+
+    pub fn new(base: &str, rates: &str) -> Self {
+        let rates: HashMap<String, f64> = rates
+            .split(',')
+            .filter_map(|pair| {
+                let (currency, rate) = pair.split_once('=')?;
+                let rate = rate.trim().parse().ok()?;
+                Some((currency.trim().to_uppercase(), rate))
+            })
+            .collect();
+
+        StaticRates {
+            table: RateTable { base: base.to_uppercase(), rates },
+        }
+    }
+}
+
+#[async_trait]
+impl RateProvider for StaticRates {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    async fn fetch(&self) -> Result<RateTable, FxError> {
+        Ok(self.table.clone())
+    }
+}
@@ -0,0 +1,172 @@
+mod file;
+mod fixed;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use crate::money::{exponent, is_valid_currency, Money};
+
+pub use file::FileRates;
+pub use fixed::StaticRates;
+
+#[derive(Debug)]
+pub enum FxError {
+    InvalidCurrency(String),
+    UnsupportedCurrency(String),
+    Unavailable(String),
+}
+
+impl fmt::Display for FxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FxError::InvalidCurrency(c) => write!(f, "Invalid currency code: {}", c),
+            FxError::UnsupportedCurrency(c) => write!(f, "No exchange rate for {}", c),
+            FxError::Unavailable(msg) => write!(f, "Exchange rates unavailable: {}", msg),
+        }
+    }
+}
+
+/// Units of each currency per one unit of `base`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateTable {
+    pub base: String,
+    pub rates: HashMap<String, f64>,
+}
+
+impl RateTable {
+    fn rate(&self, currency: &str) -> Option<f64> {
+        if currency == self.base {
+            Some(1.0)
+        } else {
+            self.rates.get(currency).copied().filter(|r| *r > 0.0)
+        }
+    }
+
+    /// `money` in `currency`, rounded to the nearest minor unit. Display only:
+    /// charges always use the listing price.
+    pub fn convert(&self, money: &Money, currency: &str) -> Option<Money> {
+        if money.currency == currency {
+            return Some(money.clone());
+        }
+
+        let from_rate = self.rate(&money.currency)?;
+        let to_rate = self.rate(currency)?;
+        let major = money.amount as f64 / 10_f64.powi(exponent(&money.currency) as i32);
+        let minor = (major / from_rate * to_rate * 10_f64.powi(exponent(currency) as i32)).round();
+
+        // `as` saturates, so only reject what cannot be a real amount
+        minor.is_finite().then(|| Money::new(minor as i64, currency))
+    }
+}
+
+/// Source of exchange rates; `ExchangeRates` caches whatever it returns.
+#[async_trait]
+pub trait RateProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn fetch(&self) -> Result<RateTable, FxError>;
+}
+
+struct Cached {
+    table: Arc<RateTable>,
+    fetched_at: Instant,
+}
+
+/// Rates shared by the catalog endpoints, refreshed at most once per `ttl`.
+pub struct ExchangeRates {
+    provider: Box<dyn RateProvider>,
+    ttl: Duration,
+    cached: Mutex<Option<Cached>>,
+}
+
+impl ExchangeRates {
+    pub fn new(provider: Box<dyn RateProvider>, ttl: Duration) -> Self {
+        ExchangeRates { provider, ttl, cached: Mutex::new(None) }
+    }
+
+    pub fn provider_name(&self) -> &'static str {
+        self.provider.name()
+    }
+
+    pub async fn table(&self) -> Result<Arc<RateTable>, FxError> {
+        let mut cached = self.cached.lock().await;
+        if let Some(c) = cached.as_ref() {
+            if c.fetched_at.elapsed() < self.ttl {
+                return Ok(c.table.clone());
+            }
+        }
+
+        match self.provider.fetch().await {
+            Ok(table) => {
+                let table = Arc::new(table);
+                *cached = Some(Cached { table: table.clone(), fetched_at: Instant::now() });
+                Ok(table)
+            }
+            // Slightly old rates beat no display prices at all
+            Err(e) => match cached.as_ref() {
+                Some(c) => {
+                    log::warn!("Serving stale exchange rates: {}", e);
+                    Ok(c.table.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Rates for the `?currency=` a client asked for, or `None` when it asked for none.
+    pub async fn display_in(&self, currency: Option<&String>) -> Result<Option<DisplayCurrency>, FxError> {
+        let currency = match currency {
+            Some(c) => c.to_uppercase(),
+            None => return Ok(None),
+        };
+        if !is_valid_currency(&currency) {
+            return Err(FxError::InvalidCurrency(currency));
+        }
+
+        let table = self.table().await?;
+        if table.rate(&currency).is_none() {
+            return Err(FxError::UnsupportedCurrency(currency));
+        }
+
+        Ok(Some(DisplayCurrency { currency, table }))
+    }
+}
+
+/// A requested display currency together with the rates to convert into it.
+pub struct DisplayCurrency {
+    currency: String,
+    table: Arc<RateTable>,
+}
+
+impl DisplayCurrency {
+    /// `None` when there is no rate for the listing's currency.
+    pub fn convert(&self, money: &Money) -> Option<Money> {
+        self.table.convert(money, &self.currency)
+    }
+}
+
+/// Builds the provider selected by `FX_PROVIDER` (`static` when unset, or `file`).
+///
+/// A typo is rejected rather than quietly showing prices at the static rates.
+pub fn from_env() -> Result<ExchangeRates, String> {
+    let provider: Box<dyn RateProvider> = match env::var("FX_PROVIDER").as_deref() {
+        Ok("file") => {
+            let path = env::var("FX_RATES_FILE").unwrap_or_else(|_| "./fx_rates.json".to_string());
+            Box::new(FileRates::new(path))
+        }
+        Ok("static") | Err(_) => Box::new(StaticRates::from_env()),
+        Ok(other) => return Err(format!("FX_PROVIDER must be static or file, not {:?}", other)),
+    };
+
+    let ttl = env::var("FX_CACHE_SECONDS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(3600);
+
+    Ok(ExchangeRates::new(provider, Duration::from_secs(ttl)))
+}
@@ -3,7 +3,7 @@ use mongodb::Database;
 use mongodb::bson::doc;
 use bcrypt::{hash, verify, DEFAULT_COST};
 use chrono::Utc;
-use crate::models::{LoginRequest, SignupRequest, AuthResponse, UserResponse, User};
+use crate::models::{LoginRequest, SignupRequest, AuthResponse, UserResponse, User, TaxProfile};
 use crate::auth::create_jwt;
 
 #[post("/auth/signup")]
@@ -32,6 +32,8 @@ pub async fn signup(
         email: req.email.clone(),
         password_hash,
         user_type: req.user_type.clone(),
+        calendar_token: None,
+        tax_profile: TaxProfile::default(),
         created_at: Utc::now(),
     };
 
@@ -1,13 +1,21 @@
-use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
-use mongodb::{Database, bson::doc};
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Collection, Database, bson::{doc, oid::ObjectId}};
 use chrono::Utc;
 use futures::stream::TryStreamExt;
-use crate::models::{Booking, CreateBookingRequest};
+use std::collections::HashMap;
+use crate::models::{Booking, CreateBookingRequest, Service, UpdateBookingStatusRequest};
 use crate::auth::verify_jwt;
+use crate::escrow;
+use crate::fulfillment;
+use crate::handlers::purchases::payment_error_response;
+use crate::idempotency;
+use crate::jobs;
+use crate::payments::{PaymentError, PaymentGateway};
 
 #[post("/bookings")]
 pub async fn create_booking(
     db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
     req: HttpRequest,
     booking_req: web::Json<CreateBookingRequest>,
 ) -> impl Responder {
@@ -23,26 +31,177 @@ pub async fn create_booking(
     };
 
     let customer_id = claims.sub;
+
+    let idempotency_key = match idempotency::key_from_request(&req) {
+        Ok(key) => key,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    if let Some(key) = &idempotency_key {
+        if let Err(response) = idempotency::begin(&db, &customer_id, key, &*booking_req).await {
+            return response;
+        }
+    }
+
+    let response = book_service(&db, gateway.get_ref(), customer_id.clone(), booking_req.into_inner()).await;
+
+    match idempotency_key {
+        Some(key) => idempotency::finish(&db, &customer_id, &key, response).await,
+        None => response,
+    }
+}
+
+async fn book_service(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    customer_id: String,
+    booking_req: CreateBookingRequest,
+) -> HttpResponse {
+    let service_oid = match ObjectId::parse_str(&booking_req.service_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid service ID"),
+    };
+
+    let service = match db.collection::<Service>("services").find_one(doc! { "_id": service_oid }, None).await {
+        Ok(Some(s)) => s,
+        Ok(None) => return HttpResponse::NotFound().json("Service not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch service"),
+    };
+
+    // The deposit (or the full price) is held in escrow until the booking is completed
+    let amount = match service.deposit_rate {
+        Some(rate) => match service.price.percentage(rate) {
+            Ok(amount) => amount,
+            Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+        },
+        None => service.price.clone(),
+    };
+    let payment_method = match (&booking_req.payment_method, amount.amount > 0) {
+        (Some(method), true) => Some(method.clone()),
+        (None, true) => return HttpResponse::BadRequest().json("payment_method is required"),
+        (_, false) => None,
+    };
+
     let collection = db.collection::<Booking>("bookings");
 
     let new_booking = Booking {
         id: None,
-        customer_id,
+        customer_id: customer_id.clone(),
         service_id: booking_req.service_id.clone(),
         booking_date: booking_req.booking_date.clone(),
         booking_time: booking_req.booking_time.clone(),
         notes: booking_req.notes.clone(),
         status: "pending".to_string(),
+        sequence: 0,
+        payment_intent_id: None,
+        payment_status: payment_method.as_ref().map(|_| "pending".to_string()),
+        amount: payment_method.as_ref().map(|_| amount.clone()),
+        refunded_amount: None,
+        escrow_status: None,
+        release_at: None,
+        released_amount: None,
+        cancellation_refund: None,
         created_at: Utc::now(),
     };
 
-    match collection.insert_one(new_booking, None).await {
-        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
-            "success": true,
-            "message": "Booking created successfully"
-        })),
-        Err(_) => HttpResponse::InternalServerError().json("Failed to create booking"),
+    let booking_oid = match collection.insert_one(&new_booking, None).await {
+        Ok(result) => match result.inserted_id.as_object_id() {
+            Some(oid) => oid,
+            None => return HttpResponse::InternalServerError().json("Failed to create booking"),
+        },
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to create booking"),
+    };
+
+    if let Some(payment_method) = payment_method {
+        let metadata = HashMap::from([
+            ("booking_id".to_string(), booking_oid.to_hex()),
+            ("service_id".to_string(), booking_req.service_id.clone()),
+            ("customer_id".to_string(), customer_id),
+        ]);
+
+        let intent = match gateway.create_intent(amount.amount, &amount.currency, &metadata).await {
+            Ok(intent) => intent,
+            Err(e) => return fail_booking(&collection, booking_oid, "pending", e).await,
+        };
+
+        // Webhooks and refunds find the payment through its intent, so don't charge without it
+        let recorded = collection
+            .update_one(
+                doc! { "_id": booking_oid },
+                doc! { "$set": { "payment_intent_id": &intent.id } },
+                None,
+            )
+            .await;
+        if recorded.is_err() {
+            let error = PaymentError::Gateway("Failed to record the payment".to_string());
+            return fail_booking(&collection, booking_oid, "pending", error).await;
+        }
+
+        if let Err(e) = gateway.confirm(&intent.id, &payment_method).await {
+            return fail_booking(&collection, booking_oid, "pending", e).await;
+        }
+
+        let authorized = collection
+            .update_one(
+                doc! { "_id": booking_oid, "payment_status": "pending" },
+                doc! { "$set": { "payment_status": "authorized" } },
+                None,
+            )
+            .await;
+        if authorized.is_err() {
+            return HttpResponse::InternalServerError().json("Failed to update booking");
+        }
+
+        if let Err(e) = gateway.capture(&intent.id).await {
+            if let Err(cancel_error) = gateway.cancel(&intent.id).await {
+                log::warn!("Failed to void payment intent {}: {}", intent.id, cancel_error);
+            }
+            return fail_booking(&collection, booking_oid, "authorized", e).await;
+        }
+
+        // A webhook may have recorded the payment first, which is just as good
+        if fulfillment::complete_booking_payment(db, booking_oid, &["authorized"]).await.is_err() {
+            return idempotency::charged(HttpResponse::InternalServerError().json("Failed to update booking"));
+        }
+    }
+
+    if let Err(e) = jobs::schedule_booking_reminders(db, &booking_oid, &new_booking).await {
+        log::warn!("Failed to schedule booking reminders: {}", e);
     }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "message": "Booking created successfully",
+        "booking_id": booking_oid.to_hex(),
+        "amount": new_booking.amount
+    }))
+}
+
+// A booking whose payment failed never goes ahead
+async fn fail_booking(
+    collection: &Collection<Booking>,
+    booking_oid: ObjectId,
+    from: &str,
+    error: PaymentError,
+) -> HttpResponse {
+    let _ = collection
+        .update_one(
+            doc! { "_id": booking_oid, "payment_status": from },
+            doc! {
+                "$set": { "payment_status": "failed", "status": "cancelled" },
+                "$inc": { "sequence": 1 }
+            },
+            None,
+        )
+        .await;
+
+    let body = serde_json::json!({
+        "success": false,
+        "message": error.to_string(),
+        "booking_id": booking_oid.to_hex()
+    });
+
+    payment_error_response(&error, body)
 }
 
 #[get("/bookings")]
@@ -78,3 +237,125 @@ pub async fn get_user_bookings(
         Err(_) => HttpResponse::InternalServerError().json("Failed to fetch bookings"),
     }
 }
+
+#[put("/bookings/{id}/status")]
+pub async fn update_booking_status(
+    db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    status_req: web::Json<UpdateBookingStatusRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let booking_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid booking ID"),
+    };
+
+    let collection = db.collection::<Booking>("bookings");
+    let booking = match collection.find_one(doc! { "_id": booking_oid }, None).await {
+        Ok(Some(b)) => b,
+        Ok(None) => return HttpResponse::NotFound().json("Booking not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch booking"),
+    };
+
+    let service = match ObjectId::parse_str(&booking.service_id) {
+        Ok(service_oid) => match db.collection::<Service>("services").find_one(doc! { "_id": service_oid }, None).await {
+            Ok(service) => service,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch booking"),
+        },
+        Err(_) => None,
+    };
+    let is_provider = matches!(&service, Some(service) if service.provider_id == claims.sub);
+    let is_customer = booking.customer_id == claims.sub;
+
+    if !is_provider && !is_customer {
+        return HttpResponse::NotFound().json("Booking not found");
+    }
+
+    let allowed = match (booking.status.as_str(), status_req.status.as_str()) {
+        ("pending", "confirmed") => is_provider,
+        ("pending", "cancelled") | ("confirmed", "cancelled") => true,
+        ("confirmed", "completed") | ("awaiting_completion", "completed") => is_provider,
+        _ => false,
+    };
+
+    if !allowed {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": format!("Cannot change booking from {} to {}", booking.status, status_req.status)
+        }));
+    }
+
+    // Platform-paid bookings can only go ahead once the payment has been captured
+    let needs_payment = matches!(status_req.status.as_str(), "confirmed" | "completed") && booking.payment_status.is_some();
+    if needs_payment && booking.payment_status.as_deref() != Some("paid") {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "success": false,
+            "message": "Booking has not been paid"
+        }));
+    }
+
+    let mut set = doc! { "status": &status_req.status };
+    let now = Utc::now();
+    match (status_req.status.as_str(), &service) {
+        // The provider is paid once the customer has had time to dispute
+        ("completed", _) if booking.escrow_status.as_deref() == Some("held") => {
+            set.insert("release_at", now + escrow::dispute_window());
+        }
+        ("cancelled", Some(service)) => match escrow::cancellation_refund(&booking, service, is_provider, now) {
+            Ok(Some(refund)) => {
+                set.insert("cancellation_refund", doc! { "amount": refund.amount, "currency": refund.currency });
+            }
+            Ok(None) => {}
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to update booking"),
+        },
+        _ => {}
+    }
+
+    // Matching on the previous status (and payment) guards against concurrent
+    // transitions; bumping the sequence lets calendar clients pick up the change.
+    let mut filter = doc! { "_id": booking_oid, "status": &booking.status };
+    if needs_payment {
+        filter.insert("payment_status", "paid");
+    }
+    match collection
+        .update_one(
+            filter,
+            doc! {
+                "$set": set,
+                "$inc": { "sequence": 1 }
+            },
+            None,
+        )
+        .await
+    {
+        Ok(result) if result.modified_count == 1 => {}
+        Ok(_) => return HttpResponse::Conflict().json("Booking was modified concurrently"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to update booking"),
+    }
+
+    let mut message = "Booking status updated".to_string();
+    if status_req.status == "cancelled" {
+        // The scheduler retries settlements that don't go through now
+        if let Err(e) = escrow::settle_cancellation(&db, gateway.get_ref(), booking_oid, &claims.sub).await {
+            log::warn!("Failed to settle cancelled booking {}: {}", booking_oid.to_hex(), e);
+            message = "Booking cancelled; the refund will be retried shortly".to_string();
+        }
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "message": message
+    }))
+}
@@ -0,0 +1,266 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use chrono::Utc;
+use crate::models::{Bundle, CreateBundleRequest, CurrencyQuery, Order, OrderItem, Product, Purchase, PurchaseBundleRequest};
+use crate::auth::verify_jwt;
+use crate::bundles;
+use crate::fx::ExchangeRates;
+use crate::handlers::orders::{owned_products, place_order};
+use crate::handlers::products::fx_error_response;
+use crate::handlers::purchases::pending_purchase;
+use crate::idempotency;
+use crate::money::Money;
+use crate::payments::PaymentGateway;
+use crate::tax::{self, TaxRules};
+
+/// Creates a bundle of the caller's own products.
+#[post("/bundles")]
+pub async fn create_bundle(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    bundle_req: web::Json<CreateBundleRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    if !bundle_req.price.is_valid_price() {
+        return HttpResponse::BadRequest().json("Invalid price");
+    }
+
+    let mut product_ids: Vec<String> = Vec::with_capacity(bundle_req.product_ids.len());
+    for product_id in &bundle_req.product_ids {
+        if ObjectId::parse_str(product_id).is_err() {
+            return HttpResponse::BadRequest().json("Invalid product ID");
+        }
+        if !product_ids.contains(product_id) {
+            product_ids.push(product_id.clone());
+        }
+    }
+
+    let bundle = Bundle {
+        id: None,
+        seller_id: claims.sub,
+        title: bundle_req.title.clone(),
+        description: bundle_req.description.clone(),
+        category: bundle_req.category.clone(),
+        product_ids,
+        price: bundle_req.price.clone(),
+        icon: bundle_req.icon.clone(),
+        sales: 0,
+        created_at: Utc::now(),
+    };
+
+    let products = match bundles::products_of(&db, &bundle).await {
+        Ok(Some(products)) => products,
+        Ok(None) => return HttpResponse::BadRequest().json("Product not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to create bundle"),
+    };
+    if let Err(message) = bundles::validate(&bundle.seller_id, &bundle.price, &products) {
+        return HttpResponse::BadRequest().json(message);
+    }
+
+    match db.collection::<Bundle>("bundles").insert_one(&bundle, None).await {
+        Ok(result) => {
+            let bundle = Bundle { id: result.inserted_id.as_object_id(), ..bundle };
+            let products: Vec<&Product> = products.iter().collect();
+            HttpResponse::Created().json(bundles::bundle_response(bundle, &products, None))
+        }
+        Err(_) => HttpResponse::InternalServerError().json("Failed to create bundle"),
+    }
+}
+
+#[get("/bundles/{id}")]
+pub async fn get_bundle(
+    db: web::Data<Database>,
+    fx: web::Data<ExchangeRates>,
+    id: web::Path<String>,
+    query: web::Query<CurrencyQuery>,
+) -> impl Responder {
+    let display = match fx.display_in(query.currency.as_ref()).await {
+        Ok(d) => d,
+        Err(e) => return fx_error_response(&e),
+    };
+
+    let bundle_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid bundle ID"),
+    };
+
+    match bundles::catalog(&db, doc! { "_id": bundle_oid }, display.as_ref()).await {
+        Ok(mut found) if !found.is_empty() => HttpResponse::Ok().json(found.remove(0)),
+        Ok(_) => HttpResponse::NotFound().json("Bundle not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch bundle"),
+    }
+}
+
+/// Buys every product in the bundle with a single payment.
+#[post("/bundles/{id}/purchase")]
+pub async fn purchase_bundle(
+    db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    tax_rules: web::Data<TaxRules>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    purchase_req: web::Json<PurchaseBundleRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let customer_id = claims.sub;
+
+    let idempotency_key = match idempotency::key_from_request(&req) {
+        Ok(key) => key,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    if let Some(key) = &idempotency_key {
+        if let Err(response) = idempotency::begin(&db, &customer_id, key, &*purchase_req).await {
+            return response;
+        }
+    }
+
+    let response = buy_bundle(
+        &db,
+        gateway.get_ref(),
+        &tax_rules,
+        customer_id.clone(),
+        &id,
+        purchase_req.into_inner(),
+    )
+    .await;
+
+    match idempotency_key {
+        Some(key) => idempotency::finish(&db, &customer_id, &key, response).await,
+        None => response,
+    }
+}
+
+async fn buy_bundle(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    tax_rules: &TaxRules,
+    customer_id: String,
+    bundle_id: &str,
+    purchase_req: PurchaseBundleRequest,
+) -> HttpResponse {
+    let billing_address = match &purchase_req.billing_address {
+        Some(address) => address,
+        None => return HttpResponse::BadRequest().json("billing_address is required to calculate tax"),
+    };
+
+    let bundle_oid = match ObjectId::parse_str(bundle_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid bundle ID"),
+    };
+
+    let bundle = match db.collection::<Bundle>("bundles").find_one(doc! { "_id": bundle_oid }, None).await {
+        Ok(Some(b)) => b,
+        Ok(None) => return HttpResponse::NotFound().json("Bundle not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch bundle"),
+    };
+
+    let products = match bundles::products_of(db, &bundle).await {
+        Ok(Some(products)) => products,
+        Ok(None) => return HttpResponse::Conflict().json("Some products in this bundle are no longer available"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch products"),
+    };
+
+    // Product prices may have changed since the bundle was listed
+    if let Err(message) = bundles::validate(&bundle.seller_id, &bundle.price, &products) {
+        return HttpResponse::Conflict().json(message);
+    }
+
+    let one_time: Vec<&str> = bundle
+        .product_ids
+        .iter()
+        .zip(&products)
+        .filter(|(_, product)| !product.allow_repurchase)
+        .map(|(product_id, _)| product_id.as_str())
+        .collect();
+    match owned_products(db, &customer_id, &one_time).await {
+        Ok(owned) if !owned.is_empty() => {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "success": false,
+                "message": "You already own some of the products in this bundle",
+                "product_ids": owned
+            }));
+        }
+        Ok(_) => {}
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to check ownership"),
+    }
+
+    let seller = match tax::seller_profiles(db, std::slice::from_ref(&bundle.seller_id)).await {
+        Ok(mut profiles) => profiles.remove(&bundle.seller_id).unwrap_or_default(),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to calculate tax"),
+    };
+
+    let prices: Vec<Money> = products.iter().map(|p| p.price.clone()).collect();
+    let shares = match bundles::split(&bundle.price, &prices) {
+        Ok(shares) => shares,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+
+    let order_oid = ObjectId::new();
+    let order_id = order_oid.to_hex();
+
+    // Each product is taxed on its share of the bundle price, at its own tax category
+    let mut purchases: Vec<Purchase> = Vec::with_capacity(products.len());
+    let mut items: Vec<OrderItem> = Vec::with_capacity(products.len());
+    for (product, share) in products.iter().zip(shares) {
+        let tax = match tax_rules.calculate(&share, &product.tax_category, billing_address, &seller) {
+            Ok(tax) => tax,
+            Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+        };
+        let product_id = product.id.map(|oid| oid.to_hex()).unwrap_or_default();
+        let ownership_key = (!product.allow_repurchase).then(|| format!("{}:{}", customer_id, product_id));
+        let purchase = Purchase {
+            id: Some(ObjectId::new()),
+            ..pending_purchase(&customer_id, product, &purchase_req.payment_method, tax, ownership_key, Some(order_id.clone()))
+        };
+        items.push(OrderItem {
+            product_id,
+            title: product.title.clone(),
+            price: share,
+            tax: purchase.tax().ok(),
+            purchase_id: purchase.id.map(|oid| oid.to_hex()).unwrap_or_default(),
+        });
+        purchases.push(purchase);
+    }
+
+    let total = match Money::sum(&bundle.price.currency, purchases.iter().map(|p| &p.amount)) {
+        Ok(total) => total,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+
+    let order = Order {
+        id: Some(order_oid),
+        customer_id,
+        items,
+        total,
+        payment_method: purchase_req.payment_method.clone(),
+        billing_address: purchase_req.billing_address.clone(),
+        bundle_id: Some(bundle_id.to_string()),
+        status: "pending".to_string(),
+        payment_intent_id: None,
+        failure_reason: None,
+        created_at: Utc::now(),
+    };
+
+    place_order(db, gateway, order, purchases).await
+}
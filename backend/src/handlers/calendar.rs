@@ -0,0 +1,218 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use futures::stream::TryStreamExt;
+use std::collections::HashMap;
+use std::env;
+use uuid::Uuid;
+use crate::models::{Booking, Service, User, BOOKING_DURATION_MINUTES};
+use crate::auth::verify_jwt;
+use crate::ical::{event_status, render_calendar, CalendarEvent};
+
+#[get("/bookings/{id}.ics")]
+pub async fn get_booking_ics(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let booking_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid booking ID"),
+    };
+
+    let booking = match db.collection::<Booking>("bookings").find_one(doc! { "_id": booking_oid }, None).await {
+        Ok(Some(b)) => b,
+        Ok(None) => return HttpResponse::NotFound().json("Booking not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch booking"),
+    };
+
+    let services = match load_services(&db, std::slice::from_ref(&booking)).await {
+        Ok(s) => s,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch booking"),
+    };
+
+    let is_provider = services
+        .get(&booking.service_id)
+        .map(|s| s.provider_id == claims.sub)
+        .unwrap_or(false);
+
+    if booking.customer_id != claims.sub && !is_provider {
+        return HttpResponse::NotFound().json("Booking not found");
+    }
+
+    let event = match booking_event(&booking, &services, &claims.sub) {
+        Some(e) => e,
+        None => return HttpResponse::UnprocessableEntity().json("Booking has an invalid date or time"),
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .body(render_calendar(None, &[event]))
+}
+
+#[post("/calendar/feed")]
+pub async fn create_calendar_feed(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let user_oid = match ObjectId::parse_str(&claims.sub) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    // Issuing a new token revokes any previously shared feed URL
+    let feed_token = Uuid::new_v4().simple().to_string();
+    let users = db.collection::<User>("users");
+
+    match users
+        .update_one(
+            doc! { "_id": user_oid },
+            doc! { "$set": { "calendar_token": &feed_token } },
+            None,
+        )
+        .await
+    {
+        Ok(result) if result.matched_count == 1 => {
+            let base_url = env::var("PUBLIC_BASE_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "feed_url": format!("{}/api/calendar/feed/{}.ics", base_url.trim_end_matches('/'), feed_token)
+            }))
+        }
+        Ok(_) => HttpResponse::NotFound().json("User not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to create calendar feed"),
+    }
+}
+
+#[get("/calendar/feed/{token}.ics")]
+pub async fn get_calendar_feed(
+    db: web::Data<Database>,
+    feed_token: web::Path<String>,
+) -> impl Responder {
+    let user = match db
+        .collection::<User>("users")
+        .find_one(doc! { "calendar_token": feed_token.as_str() }, None)
+        .await
+    {
+        Ok(Some(u)) => u,
+        Ok(None) => return HttpResponse::NotFound().json("Calendar feed not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch calendar feed"),
+    };
+
+    let user_id = match user.id {
+        Some(oid) => oid.to_hex(),
+        None => return HttpResponse::NotFound().json("Calendar feed not found"),
+    };
+
+    let bookings = match feed_bookings(&db, &user_id).await {
+        Ok(b) => b,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch calendar feed"),
+    };
+
+    let services = match load_services(&db, &bookings).await {
+        Ok(s) => s,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch calendar feed"),
+    };
+
+    let events: Vec<CalendarEvent> = bookings
+        .iter()
+        .filter_map(|b| booking_event(b, &services, &user_id))
+        .collect();
+
+    HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .body(render_calendar(Some(&format!("{} - MarketHub bookings", user.name)), &events))
+}
+
+// Confirmed (including past) bookings the user takes part in, either as customer or as provider.
+// Cancelled bookings stay in the feed so subscribed calendars drop the event.
+async fn feed_bookings(db: &Database, user_id: &str) -> Result<Vec<Booking>, mongodb::error::Error> {
+    let service_ids: Vec<String> = db
+        .collection::<Service>("services")
+        .find(doc! { "provider_id": user_id }, None)
+        .await?
+        .try_collect::<Vec<Service>>()
+        .await?
+        .into_iter()
+        .filter_map(|s| s.id.map(|oid| oid.to_hex()))
+        .collect();
+
+    let filter = doc! {
+        "status": { "$in": ["confirmed", "awaiting_completion", "completed", "cancelled"] },
+        "$or": [
+            { "customer_id": user_id },
+            { "service_id": { "$in": service_ids } },
+        ]
+    };
+
+    db.collection::<Booking>("bookings")
+        .find(filter, None)
+        .await?
+        .try_collect()
+        .await
+}
+
+async fn load_services(db: &Database, bookings: &[Booking]) -> Result<HashMap<String, Service>, mongodb::error::Error> {
+    let ids: Vec<ObjectId> = bookings
+        .iter()
+        .filter_map(|b| ObjectId::parse_str(&b.service_id).ok())
+        .collect();
+
+    let services: Vec<Service> = db
+        .collection::<Service>("services")
+        .find(doc! { "_id": { "$in": ids } }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(services
+        .into_iter()
+        .filter_map(|s| s.id.map(|oid| (oid.to_hex(), s)))
+        .collect())
+}
+
+fn booking_event(booking: &Booking, services: &HashMap<String, Service>, viewer_id: &str) -> Option<CalendarEvent> {
+    let booking_id = booking.id?.to_hex();
+    let start = booking.starts_at()?;
+    let service = services.get(&booking.service_id);
+
+    let title = service.map(|s| s.title.as_str()).unwrap_or("Service booking");
+    let summary = match service {
+        Some(s) if s.provider_id == viewer_id && booking.customer_id != viewer_id => {
+            format!("{} (customer booking)", title)
+        }
+        _ => title.to_string(),
+    };
+
+    Some(CalendarEvent {
+        uid: format!("booking-{}@markethub", booking_id),
+        sequence: booking.sequence,
+        start,
+        duration_minutes: BOOKING_DURATION_MINUTES,
+        summary,
+        location: service.map(|s| s.location.clone()),
+        description: booking.notes.clone(),
+        status: event_status(&booking.status),
+    })
+}
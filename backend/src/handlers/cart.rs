@@ -0,0 +1,208 @@
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::UpdateOptions;
+use chrono::Utc;
+use futures::stream::TryStreamExt;
+use std::collections::HashMap;
+use crate::models::{AddCartItemRequest, Cart, CartItem, CartLine, CartResponse, Product};
+use crate::auth::verify_jwt;
+use crate::db::is_duplicate_key;
+use crate::money::{default_currency, Money, MoneyError};
+
+#[get("/cart")]
+pub async fn get_cart(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let cart = match db.collection::<Cart>("carts").find_one(doc! { "user_id": &claims.sub }, None).await {
+        Ok(cart) => cart,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch cart"),
+    };
+    let items = cart.map(|c| c.items).unwrap_or_default();
+
+    let products = match current_products(&db, &items).await {
+        Ok(p) => p,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch cart"),
+    };
+
+    match cart_response(&items, &products) {
+        Ok(cart) => HttpResponse::Ok().json(cart),
+        Err(e) => HttpResponse::Conflict().json(e.to_string()),
+    }
+}
+
+#[post("/cart/items")]
+pub async fn add_cart_item(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    item_req: web::Json<AddCartItemRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let product_oid = match ObjectId::parse_str(&item_req.product_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid product ID"),
+    };
+
+    let product = match db.collection::<Product>("products").find_one(doc! { "_id": product_oid }, None).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return HttpResponse::NotFound().json("Product not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch product"),
+    };
+
+    let carts = db.collection::<Cart>("carts");
+
+    // One checkout means one payment, so a cart holds a single currency
+    match carts.find_one(doc! { "user_id": &claims.sub }, None).await {
+        Ok(Some(cart)) => {
+            if let Some(other) = cart.items.iter().find(|i| i.price.currency != product.price.currency) {
+                return HttpResponse::Conflict().json(format!(
+                    "Your cart contains items priced in {}; check them out before adding {} items",
+                    other.price.currency, product.price.currency
+                ));
+            }
+        }
+        Ok(None) => {}
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch cart"),
+    }
+
+    let item = CartItem {
+        product_id: product_oid.to_hex(),
+        title: product.title,
+        price: product.price.clone(),
+        added_at: Utc::now(),
+    };
+    let item_doc = match bson::to_bson(&item) {
+        Ok(b) => b,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to add item"),
+    };
+
+    // When the product is already in the cart the filter misses, and the upsert
+    // then collides with the unique index on user_id
+    let added = carts
+        .update_one(
+            doc! { "user_id": &claims.sub, "items.product_id": { "$ne": &item.product_id } },
+            doc! {
+                "$push": { "items": item_doc },
+                "$set": { "updated_at": Utc::now() }
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await;
+
+    match added {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Added to cart"
+        })),
+        Err(e) if is_duplicate_key(&e) => HttpResponse::Conflict().json("Product is already in your cart"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to add item"),
+    }
+}
+
+#[delete("/cart/items/{product_id}")]
+pub async fn remove_cart_item(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    product_id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let removed = db
+        .collection::<Cart>("carts")
+        .update_one(
+            doc! { "user_id": &claims.sub, "items.product_id": product_id.as_str() },
+            doc! {
+                "$pull": { "items": { "product_id": product_id.as_str() } },
+                "$set": { "updated_at": Utc::now() }
+            },
+            None,
+        )
+        .await;
+
+    match removed {
+        Ok(result) if result.modified_count > 0 => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Removed from cart"
+        })),
+        Ok(_) => HttpResponse::NotFound().json("Product is not in your cart"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to remove item"),
+    }
+}
+
+/// Catalog entries for the cart's items, keyed by product ID.
+pub async fn current_products(db: &Database, items: &[CartItem]) -> Result<HashMap<String, Product>, mongodb::error::Error> {
+    let product_oids: Vec<ObjectId> = items
+        .iter()
+        .filter_map(|item| ObjectId::parse_str(&item.product_id).ok())
+        .collect();
+
+    let products: Vec<Product> = db
+        .collection::<Product>("products")
+        .find(doc! { "_id": { "$in": product_oids } }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(products
+        .into_iter()
+        .filter_map(|p| p.id.map(|oid| (oid.to_hex(), p)))
+        .collect())
+}
+
+/// Compares each item's snapshot with the catalog.
+pub fn cart_response(items: &[CartItem], products: &HashMap<String, Product>) -> Result<CartResponse, MoneyError> {
+    let lines: Vec<CartLine> = items
+        .iter()
+        .map(|item| {
+            let current_price = products.get(&item.product_id).map(|p| p.price.clone());
+            CartLine {
+                product_id: item.product_id.clone(),
+                title: item.title.clone(),
+                price: item.price.clone(),
+                price_changed: current_price.as_ref() != Some(&item.price),
+                current_price,
+            }
+        })
+        .collect();
+
+    let currency = items
+        .first()
+        .map(|item| item.price.currency.clone())
+        .unwrap_or_else(default_currency);
+
+    Ok(CartResponse {
+        total: Money::sum(&currency, lines.iter().map(|line| &line.price))?,
+        has_changes: lines.iter().any(|line| line.price_changed),
+        items: lines,
+    })
+}
@@ -0,0 +1,224 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::FindOptions;
+use chrono::Utc;
+use futures::stream::TryStreamExt;
+use crate::models::{Coupon, CreateCouponRequest, Product, ValidateCouponRequest};
+use crate::auth::verify_jwt;
+use crate::coupons::{self, CouponError, KIND_FIXED, KIND_PERCENTAGE};
+use crate::db::is_duplicate_key;
+use crate::handlers::niche::niche_categories;
+
+/// Creates a promotional code for the caller's own products.
+#[post("/coupons")]
+pub async fn create_coupon(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    coupon_req: web::Json<CreateCouponRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let code = coupons::normalize_code(&coupon_req.code);
+    if code.len() < 3 || code.len() > 32 || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return HttpResponse::BadRequest().json("code must be 3-32 letters, digits, '-' or '_'");
+    }
+
+    match (coupon_req.kind.as_str(), coupon_req.rate, &coupon_req.amount_off) {
+        (KIND_PERCENTAGE, Some(rate), None) if rate > 0.0 && rate <= 1.0 => {}
+        (KIND_PERCENTAGE, _, _) => {
+            return HttpResponse::BadRequest().json("Percentage coupons need a rate above 0 and at most 1, and no amount_off");
+        }
+        (KIND_FIXED, None, Some(off)) if off.is_valid_price() && off.amount > 0 => {}
+        (KIND_FIXED, _, _) => {
+            return HttpResponse::BadRequest().json("Fixed coupons need a positive amount_off, and no rate");
+        }
+        _ => return HttpResponse::BadRequest().json("kind must be percentage or fixed"),
+    }
+
+    if let Some(niche) = coupon_req.niches.iter().find(|n| niche_categories(n).is_none()) {
+        return HttpResponse::BadRequest().json(format!("Invalid niche type: {}", niche));
+    }
+
+    if let (Some(starts_at), Some(ends_at)) = (coupon_req.starts_at, coupon_req.ends_at) {
+        if ends_at <= starts_at {
+            return HttpResponse::BadRequest().json("ends_at must be after starts_at");
+        }
+    }
+
+    if coupon_req.max_redemptions.is_some_and(|max| max < 1) || coupon_req.max_per_customer.is_some_and(|max| max < 1) {
+        return HttpResponse::BadRequest().json("Usage limits must be at least 1");
+    }
+
+    let mut product_oids = Vec::with_capacity(coupon_req.product_ids.len());
+    for product_id in &coupon_req.product_ids {
+        match ObjectId::parse_str(product_id) {
+            Ok(oid) => product_oids.push(oid),
+            Err(_) => return HttpResponse::BadRequest().json("Invalid product ID"),
+        }
+    }
+    if !product_oids.is_empty() {
+        let owned = match db
+            .collection::<Product>("products")
+            .count_documents(doc! { "_id": { "$in": &product_oids }, "seller_id": &claims.sub }, None)
+            .await
+        {
+            Ok(n) => n,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to create coupon"),
+        };
+        if owned != product_oids.len() as u64 {
+            return HttpResponse::BadRequest().json("Coupons can only apply to your own products");
+        }
+    }
+
+    let coupon = Coupon {
+        id: None,
+        code,
+        seller_id: claims.sub,
+        kind: coupon_req.kind.clone(),
+        rate: coupon_req.rate,
+        amount_off: coupon_req.amount_off.clone(),
+        product_ids: coupon_req.product_ids.clone(),
+        categories: coupon_req.categories.clone(),
+        niches: coupon_req.niches.clone(),
+        starts_at: coupon_req.starts_at,
+        ends_at: coupon_req.ends_at,
+        max_redemptions: coupon_req.max_redemptions,
+        max_per_customer: coupon_req.max_per_customer,
+        redemptions: 0,
+        stackable: coupon_req.stackable,
+        active: true,
+        created_at: Utc::now(),
+    };
+
+    match db.collection::<Coupon>("coupons").insert_one(&coupon, None).await {
+        Ok(result) => HttpResponse::Created().json(Coupon {
+            id: result.inserted_id.as_object_id(),
+            ..coupon
+        }),
+        Err(e) if is_duplicate_key(&e) => HttpResponse::Conflict().json("Coupon code already exists"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to create coupon"),
+    }
+}
+
+#[get("/seller/coupons")]
+pub async fn get_seller_coupons(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    match db.collection::<Coupon>("coupons").find(doc! { "seller_id": &claims.sub }, options).await {
+        Ok(cursor) => match cursor.try_collect::<Vec<Coupon>>().await {
+            Ok(coupons) => HttpResponse::Ok().json(coupons),
+            Err(_) => HttpResponse::InternalServerError().json("Failed to fetch coupons"),
+        },
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch coupons"),
+    }
+}
+
+/// Stops a code from being used; purchases already made keep their discount.
+#[post("/coupons/{id}/deactivate")]
+pub async fn deactivate_coupon(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let coupon_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid coupon ID"),
+    };
+
+    match db
+        .collection::<Coupon>("coupons")
+        .update_one(
+            doc! { "_id": coupon_oid, "seller_id": &claims.sub },
+            doc! { "$set": { "active": false } },
+            None,
+        )
+        .await
+    {
+        Ok(result) if result.matched_count == 1 => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Coupon deactivated"
+        })),
+        Ok(_) => HttpResponse::NotFound().json("Coupon not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to update coupon"),
+    }
+}
+
+/// Previews the price of a product with the given codes, before tax.
+#[post("/coupons/validate")]
+pub async fn validate_coupons(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    validate_req: web::Json<ValidateCouponRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let product_oid = match ObjectId::parse_str(&validate_req.product_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid product ID"),
+    };
+
+    let product = match db.collection::<Product>("products").find_one(doc! { "_id": product_oid }, None).await {
+        Ok(Some(p)) => p,
+        _ => return HttpResponse::NotFound().json("Product not found"),
+    };
+
+    match coupons::apply(&db, &product, &validate_req.codes, &claims.sub, Utc::now()).await {
+        Ok((price, discounts)) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "product_id": validate_req.product_id,
+            "price": product.price,
+            "discounts": discounts,
+            "discounted_price": price
+        })),
+        Err(e) => coupon_error_response(&e),
+    }
+}
+
+pub fn coupon_error_response(error: &CouponError) -> HttpResponse {
+    match error {
+        CouponError::Rejected(msg) => HttpResponse::BadRequest().json(msg),
+        CouponError::Database(_) => HttpResponse::InternalServerError().json("Failed to apply coupons"),
+    }
+}
@@ -0,0 +1,145 @@
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header::ContentDisposition;
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use futures::stream::TryStreamExt;
+use std::collections::HashMap;
+use crate::models::{Invoice, InvoiceQuery, Order};
+use crate::auth::verify_jwt;
+use crate::invoices::render_pdf;
+
+/// The invoices for an order, one per seller, as a PDF (default) or JSON.
+#[get("/orders/{id}/invoice")]
+pub async fn get_order_invoice(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<InvoiceQuery>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let order_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid order ID"),
+    };
+
+    match db
+        .collection::<Order>("orders")
+        .find_one(doc! { "_id": order_oid, "customer_id": &claims.sub }, None)
+        .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().json("Order not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch order"),
+    }
+
+    let invoices = match find_invoices(&db, doc! { "order_id": id.as_str() }).await {
+        Ok(invoices) => invoices,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch invoices"),
+    };
+
+    // Invoices are issued when the order completes
+    if invoices.is_empty() {
+        return HttpResponse::NotFound().json("Order has not been invoiced yet");
+    }
+
+    invoice_response(invoices, &query, &format!("order-{}.pdf", id.as_str()))
+}
+
+/// Invoices addressed to the caller, or issued by them with `?role=seller`.
+#[get("/invoices")]
+pub async fn get_invoices(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let filter = match query.get("role").map(|r| r.as_str()) {
+        Some("seller") => doc! { "seller_id": &claims.sub },
+        _ => doc! { "customer_id": &claims.sub },
+    };
+
+    match find_invoices(&db, filter).await {
+        Ok(invoices) => HttpResponse::Ok().json(invoices),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch invoices"),
+    }
+}
+
+#[get("/invoices/{id}")]
+pub async fn get_invoice(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<InvoiceQuery>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let invoice_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid invoice ID"),
+    };
+
+    // Both the buyer and the seller may see an invoice
+    let filter = doc! {
+        "_id": invoice_oid,
+        "$or": [{ "customer_id": &claims.sub }, { "seller_id": &claims.sub }]
+    };
+
+    match db.collection::<Invoice>("invoices").find_one(filter, None).await {
+        Ok(Some(invoice)) => {
+            let filename = format!("{}.pdf", invoice.invoice_number);
+            invoice_response(vec![invoice], &query, &filename)
+        }
+        Ok(None) => HttpResponse::NotFound().json("Invoice not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch invoice"),
+    }
+}
+
+async fn find_invoices(db: &Database, filter: mongodb::bson::Document) -> Result<Vec<Invoice>, mongodb::error::Error> {
+    let mut options = mongodb::options::FindOptions::default();
+    options.sort = Some(doc! { "issued_at": -1 });
+
+    db.collection::<Invoice>("invoices")
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await
+}
+
+fn invoice_response(invoices: Vec<Invoice>, query: &InvoiceQuery, filename: &str) -> HttpResponse {
+    match query.format.as_deref() {
+        Some("json") => HttpResponse::Ok().json(invoices),
+        Some("pdf") | None => HttpResponse::Ok()
+            .content_type("application/pdf")
+            .insert_header(ContentDisposition::attachment(filename))
+            .body(render_pdf(&invoices)),
+        Some(_) => HttpResponse::BadRequest().json("format must be pdf or json"),
+    }
+}
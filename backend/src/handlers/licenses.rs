@@ -0,0 +1,192 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use futures::stream::TryStreamExt;
+use std::collections::HashMap;
+use crate::models::{LicenseSummary, Product, Purchase, ValidateLicenseRequest, ValidateLicenseResponse};
+use crate::auth::verify_jwt;
+use crate::licenses::{self, Activation};
+
+/// Called by licensed software; no user session is involved.
+#[post("/licenses/validate")]
+pub async fn validate_license(
+    db: web::Data<Database>,
+    validate_req: web::Json<ValidateLicenseRequest>,
+) -> impl Responder {
+    let machine_id = validate_req.machine_id.trim();
+    if machine_id.is_empty() || machine_id.len() > 255 {
+        return HttpResponse::BadRequest().json("Invalid machine_id");
+    }
+
+    let purchase = match db
+        .collection::<Purchase>("purchases")
+        .find_one(
+            doc! {
+                "license_key": validate_req.license_key.trim().to_uppercase(),
+                "product_id": &validate_req.product_id,
+                "status": "completed"
+            },
+            None,
+        )
+        .await
+    {
+        Ok(Some(p)) => p,
+        Ok(None) => return HttpResponse::Ok().json(invalid("invalid_key", 0, None)),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to validate license"),
+    };
+
+    if purchase.license_revoked {
+        return HttpResponse::Ok().json(invalid("revoked", purchase.activation_count, purchase.max_activations));
+    }
+
+    match licenses::activate(&db, &purchase, machine_id).await {
+        Ok(Activation::Existing(count)) | Ok(Activation::New(count)) => HttpResponse::Ok().json(ValidateLicenseResponse {
+            valid: true,
+            reason: None,
+            activations: count,
+            max_activations: purchase.max_activations,
+        }),
+        Ok(Activation::LimitReached(count)) => {
+            HttpResponse::Ok().json(invalid("activation_limit_reached", count, purchase.max_activations))
+        }
+        Err(_) => HttpResponse::InternalServerError().json("Failed to validate license"),
+    }
+}
+
+/// Lists the license keys issued for the seller's products, optionally for one product.
+#[get("/seller/licenses")]
+pub async fn get_seller_licenses(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let mut product_filter = doc! { "seller_id": &claims.sub, "license_keys": true };
+    if let Some(product_id) = query.get("product_id") {
+        match ObjectId::parse_str(product_id) {
+            Ok(oid) => {
+                product_filter.insert("_id", oid);
+            }
+            Err(_) => return HttpResponse::BadRequest().json("Invalid product ID"),
+        }
+    }
+
+    let product_ids: Vec<String> = match db.collection::<Product>("products").find(product_filter, None).await {
+        Ok(cursor) => match cursor.try_collect::<Vec<Product>>().await {
+            Ok(products) => products.into_iter().filter_map(|p| p.id.map(|oid| oid.to_hex())).collect(),
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch licenses"),
+        },
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch licenses"),
+    };
+
+    let mut options = mongodb::options::FindOptions::default();
+    options.sort = Some(doc! { "created_at": -1 });
+
+    let purchases = match db
+        .collection::<Purchase>("purchases")
+        .find(
+            doc! {
+                "product_id": { "$in": product_ids },
+                "status": "completed",
+                "license_key": { "$exists": true }
+            },
+            options,
+        )
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect::<Vec<Purchase>>().await {
+            Ok(p) => p,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch licenses"),
+        },
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch licenses"),
+    };
+
+    let licenses: Vec<LicenseSummary> = purchases
+        .into_iter()
+        .filter_map(|p| {
+            Some(LicenseSummary {
+                license_key: p.license_key?,
+                purchase_id: p.id?.to_hex(),
+                product_id: p.product_id,
+                customer_id: p.customer_id,
+                activations: p.activation_count,
+                max_activations: p.max_activations,
+                revoked: p.license_revoked,
+                purchased_at: p.created_at,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(licenses)
+}
+
+#[post("/licenses/{key}/revoke")]
+pub async fn revoke_license(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    key: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let purchases = db.collection::<Purchase>("purchases");
+    let license_key = key.trim().to_uppercase();
+
+    let purchase = match purchases.find_one(doc! { "license_key": &license_key }, None).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return HttpResponse::NotFound().json("License not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch license"),
+    };
+
+    // Only the seller of the licensed product may revoke its keys
+    let product = match ObjectId::parse_str(&purchase.product_id) {
+        Ok(oid) => db.collection::<Product>("products").find_one(doc! { "_id": oid }, None).await,
+        Err(_) => Ok(None),
+    };
+    match product {
+        Ok(Some(p)) if p.seller_id == claims.sub => {}
+        Ok(_) => return HttpResponse::NotFound().json("License not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch license"),
+    }
+
+    match purchases
+        .update_one(
+            doc! { "license_key": &license_key },
+            doc! { "$set": { "license_revoked": true } },
+            None,
+        )
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "License revoked"
+        })),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to revoke license"),
+    }
+}
+
+fn invalid(reason: &str, activations: i32, max_activations: Option<i32>) -> ValidateLicenseResponse {
+    ValidateLicenseResponse {
+        valid: false,
+        reason: Some(reason.to_string()),
+        activations,
+        max_activations,
+    }
+}
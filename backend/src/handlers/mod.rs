@@ -5,3 +5,17 @@ pub mod niche;
 pub mod bookings;
 pub mod purchases;
 pub mod reviews;
+pub mod calendar;
+pub mod notifications;
+pub mod webhooks;
+pub mod uploads;
+pub mod licenses;
+pub mod cart;
+pub mod orders;
+pub mod invoices;
+pub mod tax;
+pub mod refunds;
+pub mod payouts;
+pub mod coupons;
+pub mod bundles;
+pub mod subscriptions;
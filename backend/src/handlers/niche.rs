@@ -1,27 +1,44 @@
 use actix_web::{get, web, HttpResponse, Responder};
 use mongodb::{Database, bson::doc};
 use futures::stream::TryStreamExt;
-use crate::models::Product;
+use crate::bundles;
+use crate::models::{CatalogItem, Product};
+use crate::fx::ExchangeRates;
+use crate::handlers::products::{fx_error_response, product_response, sort_catalog};
+
+/// Product categories that make up a niche market.
+pub fn niche_categories(niche: &str) -> Option<&'static [&'static str]> {
+    match niche {
+        "resume" => Some(&["career", "resume", "cv"]),
+        "business" => Some(&["business", "invoice", "contract"]),
+        "student" => Some(&["education", "student", "notes"]),
+        "creator" => Some(&["creative", "design", "graphics"]),
+        "developer" => Some(&["development", "code", "api"]),
+        _ => None,
+    }
+}
 
 #[get("/niche/{niche_type}")]
 pub async fn get_niche_products(
     db: web::Data<Database>,
+    fx: web::Data<ExchangeRates>,
     niche_type: web::Path<String>,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> impl Responder {
-    let niche_categories = match niche_type.as_str() {
-        "resume" => vec!["career", "resume", "cv"],
-        "business" => vec!["business", "invoice", "contract"],
-        "student" => vec!["education", "student", "notes"],
-        "creator" => vec!["creative", "design", "graphics"],
-        "developer" => vec!["development", "code", "api"],
-        _ => return HttpResponse::BadRequest().json("Invalid niche type"),
+    let niche_categories = match niche_categories(niche_type.as_str()) {
+        Some(categories) => categories,
+        None => return HttpResponse::BadRequest().json("Invalid niche type"),
+    };
+
+    let display = match fx.display_in(query.get("currency")).await {
+        Ok(d) => d,
+        Err(e) => return fx_error_response(&e),
     };
 
     let collection = db.collection::<Product>("products");
     
     let mut filter = doc! {
-        "category": { "$in": niche_categories }
+        "category": { "$in": niche_categories.to_vec() }
     };
     
     if let Some(search) = query.get("search") {
@@ -31,28 +48,27 @@ pub async fn get_niche_products(
         ]);
     }
 
-    let mut options = mongodb::options::FindOptions::default();
-    
-    if let Some(sort) = query.get("sort") {
-        let sort_doc = match sort.as_str() {
-            "popular" => doc! { "downloads": -1 },
-            "recent" => doc! { "created_at": -1 },
-            "price-low" => doc! { "price": 1 },
-            "price-high" => doc! { "price": -1 },
-            _ => doc! { "created_at": -1 },
-        };
-        options.sort = Some(sort_doc);
-    } else {
-        options.sort = Some(doc! { "created_at": -1 });
-    }
+    let products = match collection.find(filter.clone(), None).await {
+        Ok(cursor) => match cursor.try_collect::<Vec<Product>>().await {
+            Ok(products) => products,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch niche products"),
+        },
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch niche products"),
+    };
 
-    match collection.find(filter, options).await {
-        Ok(cursor) => {
-            match cursor.try_collect::<Vec<Product>>().await {
-                Ok(products) => HttpResponse::Ok().json(products),
-                Err(_) => HttpResponse::InternalServerError().json("Failed to fetch niche products"),
-            }
-        }
-        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch niche products"),
-    }
+    let bundles = match bundles::catalog(&db, filter, display.as_ref()).await {
+        Ok(b) => b,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch niche products"),
+    };
+
+    let mut items: Vec<CatalogItem> = products
+        .into_iter()
+        .map(|p| CatalogItem::Product(product_response(p, display.as_ref())))
+        .chain(bundles.into_iter().map(CatalogItem::Bundle))
+        .collect();
+
+    // Sort bundles in among the products
+    sort_catalog(&mut items, query.get("sort").map(String::as_str).unwrap_or("recent"));
+
+    HttpResponse::Ok().json(items)
 }
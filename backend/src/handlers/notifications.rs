@@ -0,0 +1,79 @@
+use actix_web::{get, put, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use futures::stream::TryStreamExt;
+use crate::models::Notification;
+use crate::auth::verify_jwt;
+
+#[get("/notifications")]
+pub async fn get_notifications(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let collection = db.collection::<Notification>("notifications");
+
+    let filter = doc! { "user_id": claims.sub };
+    let mut options = mongodb::options::FindOptions::default();
+    options.sort = Some(doc! { "created_at": -1 });
+    options.limit = Some(100);
+
+    match collection.find(filter, options).await {
+        Ok(cursor) => {
+            match cursor.try_collect::<Vec<Notification>>().await {
+                Ok(notifications) => HttpResponse::Ok().json(notifications),
+                Err(_) => HttpResponse::InternalServerError().json("Failed to fetch notifications"),
+            }
+        }
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch notifications"),
+    }
+}
+
+#[put("/notifications/{id}/read")]
+pub async fn mark_notification_read(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let notification_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid notification ID"),
+    };
+
+    let collection = db.collection::<Notification>("notifications");
+
+    match collection
+        .update_one(
+            doc! { "_id": notification_oid, "user_id": claims.sub },
+            doc! { "$set": { "read": true } },
+            None,
+        )
+        .await
+    {
+        Ok(result) if result.matched_count == 1 => HttpResponse::Ok().json(serde_json::json!({
+            "success": true
+        })),
+        Ok(_) => HttpResponse::NotFound().json("Notification not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to update notification"),
+    }
+}
@@ -0,0 +1,477 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use chrono::Utc;
+use futures::stream::TryStreamExt;
+use std::collections::HashMap;
+use crate::models::{Cart, CartItem, CheckoutRequest, Order, OrderItem, Purchase};
+use crate::auth::verify_jwt;
+use crate::db::{is_duplicate_key, with_transaction};
+use crate::download_links::signed_download_url;
+use crate::fulfillment;
+use crate::handlers::cart::{cart_response, current_products};
+use crate::handlers::purchases::{payment_error_response, pending_purchase};
+use crate::idempotency;
+use crate::money::Money;
+use crate::payments::{PaymentError, PaymentGateway};
+use crate::tax::{self, TaxRules};
+
+#[post("/checkout")]
+pub async fn checkout(
+    db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    tax_rules: web::Data<TaxRules>,
+    req: HttpRequest,
+    checkout_req: web::Json<CheckoutRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let customer_id = claims.sub;
+
+    let idempotency_key = match idempotency::key_from_request(&req) {
+        Ok(key) => key,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    if let Some(key) = &idempotency_key {
+        if let Err(response) = idempotency::begin(&db, &customer_id, key, &*checkout_req).await {
+            return response;
+        }
+    }
+
+    let response = checkout_cart(
+        &db,
+        gateway.get_ref(),
+        &tax_rules,
+        customer_id.clone(),
+        checkout_req.into_inner(),
+    )
+    .await;
+
+    match idempotency_key {
+        Some(key) => idempotency::finish(&db, &customer_id, &key, response).await,
+        None => response,
+    }
+}
+
+async fn checkout_cart(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    tax_rules: &TaxRules,
+    customer_id: String,
+    checkout_req: CheckoutRequest,
+) -> HttpResponse {
+    let billing_address = match &checkout_req.billing_address {
+        Some(address) => address,
+        None => return HttpResponse::BadRequest().json("billing_address is required to calculate tax"),
+    };
+
+    let carts = db.collection::<Cart>("carts");
+
+    let items = match carts.find_one(doc! { "user_id": &customer_id }, None).await {
+        Ok(cart) => cart.map(|c| c.items).unwrap_or_default(),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch cart"),
+    };
+
+    if items.is_empty() {
+        return HttpResponse::BadRequest().json("Cart is empty");
+    }
+
+    let products = match current_products(db, &items).await {
+        Ok(p) => p,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch products"),
+    };
+
+    // Never charge a price the customer hasn't seen: refresh the snapshots and let them re-confirm
+    let cart = match cart_response(&items, &products) {
+        Ok(cart) => cart,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+    if cart.has_changes {
+        let refreshed: Vec<CartItem> = items
+            .iter()
+            .filter_map(|item| {
+                let product = products.get(&item.product_id)?;
+                Some(CartItem { price: product.price.clone(), title: product.title.clone(), ..item.clone() })
+            })
+            .collect();
+        if let Ok(refreshed) = bson::to_bson(&refreshed) {
+            let _ = carts
+                .update_one(
+                    doc! { "user_id": &customer_id },
+                    doc! { "$set": { "items": refreshed, "updated_at": Utc::now() } },
+                    None,
+                )
+                .await;
+        }
+
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "success": false,
+            "message": "Some items changed price or are no longer available; review your cart and check out again",
+            "cart": cart
+        }));
+    }
+
+    let one_time: Vec<&str> = items
+        .iter()
+        .filter(|item| !products[&item.product_id].allow_repurchase)
+        .map(|item| item.product_id.as_str())
+        .collect();
+    let already_owned = match owned_products(db, &customer_id, &one_time).await {
+        Ok(owned) => owned,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to check ownership"),
+    };
+    if !already_owned.is_empty() {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "success": false,
+            "message": "You already own some of these products; remove them from your cart",
+            "product_ids": already_owned
+        }));
+    }
+
+    let seller_ids: Vec<String> = products.values().map(|p| p.seller_id.clone()).collect();
+    let sellers = match tax::seller_profiles(db, &seller_ids).await {
+        Ok(s) => s,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to calculate tax"),
+    };
+
+    let order_oid = ObjectId::new();
+    let order_id = order_oid.to_hex();
+
+    // Each seller taxes their own items, so every purchase carries its own tax lines
+    let mut purchases: Vec<Purchase> = Vec::with_capacity(items.len());
+    for item in &items {
+        let product = &products[&item.product_id];
+        let seller = sellers.get(&product.seller_id).cloned().unwrap_or_default();
+        let tax = match tax_rules.calculate(&product.price, &product.tax_category, billing_address, &seller) {
+            Ok(tax) => tax,
+            Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+        };
+        let ownership_key = (!product.allow_repurchase).then(|| format!("{}:{}", customer_id, item.product_id));
+        purchases.push(Purchase {
+            id: Some(ObjectId::new()),
+            ..pending_purchase(&customer_id, product, &checkout_req.payment_method, tax, ownership_key, Some(order_id.clone()))
+        });
+    }
+
+    let total = match Money::sum(&cart.total.currency, purchases.iter().map(|p| &p.amount)) {
+        Ok(total) => total,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+
+    let order = Order {
+        id: Some(order_oid),
+        customer_id: customer_id.clone(),
+        items: purchases
+            .iter()
+            .map(|p| OrderItem {
+                product_id: p.product_id.clone(),
+                title: products[&p.product_id].title.clone(),
+                price: products[&p.product_id].price.clone(),
+                tax: p.tax().ok(),
+                purchase_id: p.id.map(|oid| oid.to_hex()).unwrap_or_default(),
+            })
+            .collect(),
+        total,
+        payment_method: checkout_req.payment_method.clone(),
+        billing_address: checkout_req.billing_address.clone(),
+        bundle_id: None,
+        status: "pending".to_string(),
+        payment_intent_id: None,
+        failure_reason: None,
+        created_at: Utc::now(),
+    };
+
+    let bought: Vec<String> = order.items.iter().map(|item| item.product_id.clone()).collect();
+    let response = place_order(db, gateway, order, purchases).await;
+
+    if response.status().is_success() {
+        let _ = carts
+            .update_one(
+                doc! { "user_id": &customer_id },
+                doc! {
+                    "$pull": { "items": { "product_id": { "$in": bought } } },
+                    "$set": { "updated_at": Utc::now() }
+                },
+                None,
+            )
+            .await;
+    }
+
+    response
+}
+
+/// Products among `product_ids` the customer already owns and can't buy again.
+pub async fn owned_products(
+    db: &Database,
+    customer_id: &str,
+    product_ids: &[&str],
+) -> Result<Vec<String>, mongodb::error::Error> {
+    let owned_keys: Vec<String> = product_ids
+        .iter()
+        .map(|product_id| format!("{}:{}", customer_id, product_id))
+        .collect();
+
+    let purchases: Vec<Purchase> = db
+        .collection::<Purchase>("purchases")
+        .find(doc! { "ownership_key": { "$in": &owned_keys } }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(purchases.into_iter().map(|p| p.product_id).collect())
+}
+
+/// Stores a pending order with its purchases and charges it in one payment.
+pub async fn place_order(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    order: Order,
+    purchases: Vec<Purchase>,
+) -> HttpResponse {
+    let order_oid = match order.id {
+        Some(oid) => oid,
+        None => return HttpResponse::InternalServerError().json("Failed to create order"),
+    };
+    let order_id = order_oid.to_hex();
+    let customer_id = order.customer_id.clone();
+    let payment_method = order.payment_method.clone();
+
+    let db_handle = db.clone();
+    let order_doc = order.clone();
+    let purchase_docs = purchases.clone();
+    let created = with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let order = order_doc.clone();
+        let purchases = purchase_docs.clone();
+        Box::pin(async move {
+            db.collection::<Order>("orders")
+                .insert_one_with_session(order, None, session)
+                .await?;
+            db.collection::<Purchase>("purchases")
+                .insert_many_with_session(purchases, None, session)
+                .await?;
+            Ok(())
+        })
+    })
+    .await;
+
+    match created {
+        Ok(()) => {}
+        // Another checkout or purchase got there first
+        Err(e) if is_duplicate_key(&e) => {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "success": false,
+                "message": "You already own some of these products"
+            }));
+        }
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to create order"),
+    }
+
+    let metadata = HashMap::from([
+        ("order_id".to_string(), order_id.clone()),
+        ("customer_id".to_string(), customer_id),
+    ]);
+
+    let intent = match gateway.create_intent(order.total.amount, &order.total.currency, &metadata).await {
+        Ok(intent) => intent,
+        Err(e) => return fail_order(db, order_oid, "pending", e).await,
+    };
+
+    // Webhooks and refunds find the payment through its intent, so don't charge without it
+    let order_recorded = db
+        .collection::<Order>("orders")
+        .update_one(doc! { "_id": order_oid }, doc! { "$set": { "payment_intent_id": &intent.id } }, None)
+        .await;
+    let purchases_recorded = db
+        .collection::<Purchase>("purchases")
+        .update_many(doc! { "order_id": &order_id }, doc! { "$set": { "payment_intent_id": &intent.id } }, None)
+        .await;
+    if order_recorded.is_err() || purchases_recorded.is_err() {
+        let error = PaymentError::Gateway("Failed to record the payment".to_string());
+        return fail_order(db, order_oid, "pending", error).await;
+    }
+
+    if let Err(e) = gateway.confirm(&intent.id, &payment_method).await {
+        return fail_order(db, order_oid, "pending", e).await;
+    }
+
+    let authorized = match transition_order(db, order_oid, "pending", "authorized").await {
+        Ok(true) => true,
+        // The authorization webhook may have got there first
+        Ok(false) => matches!(
+            db.collection::<Order>("orders")
+                .find_one(doc! { "_id": order_oid, "status": "authorized" }, None)
+                .await,
+            Ok(Some(_))
+        ),
+        Err(_) => false,
+    };
+
+    // Don't leave funds held against an order that can't move on
+    if !authorized {
+        if let Err(e) = gateway.cancel(&intent.id).await {
+            log::warn!("Failed to void payment intent {}: {}", intent.id, e);
+        }
+        let error = PaymentError::Gateway("Failed to record the payment authorization".to_string());
+        return fail_order(db, order_oid, "pending", error).await;
+    }
+
+    if let Err(e) = gateway.capture(&intent.id).await {
+        if let Err(cancel_error) = gateway.cancel(&intent.id).await {
+            log::warn!("Failed to void payment intent {}: {}", intent.id, cancel_error);
+        }
+        return fail_order(db, order_oid, "authorized", e).await;
+    }
+
+    // A webhook may have completed the order first, which is just as good
+    if fulfillment::complete_order(db, order_oid, &["authorized"]).await.is_err() {
+        return idempotency::charged(HttpResponse::InternalServerError().json("Failed to update order"));
+    }
+
+    let items: Vec<serde_json::Value> = purchases
+        .iter()
+        .map(|p| {
+            let purchase_id = p.id.map(|oid| oid.to_hex()).unwrap_or_default();
+            serde_json::json!({
+                "product_id": p.product_id,
+                "purchase_id": purchase_id,
+                "download_url": signed_download_url(&purchase_id),
+                "license_key": p.license_key
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "message": "Order completed",
+        "order_id": order_id,
+        "total": order.total,
+        "items": items
+    }))
+}
+
+#[get("/orders")]
+pub async fn get_orders(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let mut options = mongodb::options::FindOptions::default();
+    options.sort = Some(doc! { "created_at": -1 });
+
+    match db.collection::<Order>("orders").find(doc! { "customer_id": &claims.sub }, options).await {
+        Ok(cursor) => match cursor.try_collect::<Vec<Order>>().await {
+            Ok(orders) => HttpResponse::Ok().json(orders),
+            Err(_) => HttpResponse::InternalServerError().json("Failed to fetch orders"),
+        },
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch orders"),
+    }
+}
+
+#[get("/orders/{id}")]
+pub async fn get_order(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let order_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid order ID"),
+    };
+
+    match db
+        .collection::<Order>("orders")
+        .find_one(doc! { "_id": order_oid, "customer_id": &claims.sub }, None)
+        .await
+    {
+        Ok(Some(order)) => HttpResponse::Ok().json(order),
+        Ok(None) => HttpResponse::NotFound().json("Order not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch order"),
+    }
+}
+
+/// Moves the order and its purchases from `from` to `to`.
+async fn transition_order(db: &Database, order_oid: ObjectId, from: &str, to: &str) -> Result<bool, mongodb::error::Error> {
+    let moved = db
+        .collection::<Order>("orders")
+        .update_one(
+            doc! { "_id": order_oid, "status": from },
+            doc! { "$set": { "status": to } },
+            None,
+        )
+        .await?;
+
+    if moved.modified_count == 0 {
+        return Ok(false);
+    }
+
+    db.collection::<Purchase>("purchases")
+        .update_many(
+            doc! { "order_id": order_oid.to_hex(), "status": from },
+            doc! { "$set": { "status": to } },
+            None,
+        )
+        .await?;
+
+    Ok(true)
+}
+
+async fn fail_order(db: &Database, order_oid: ObjectId, from: &str, error: PaymentError) -> HttpResponse {
+    let _ = db
+        .collection::<Order>("orders")
+        .update_one(
+            doc! { "_id": order_oid, "status": from },
+            doc! { "$set": { "status": "failed", "failure_reason": error.to_string() } },
+            None,
+        )
+        .await;
+    let _ = db
+        .collection::<Purchase>("purchases")
+        .update_many(
+            doc! { "order_id": order_oid.to_hex(), "status": from },
+            doc! {
+                "$set": { "status": "failed", "failure_reason": error.to_string() },
+                "$unset": { "ownership_key": "" }
+            },
+            None,
+        )
+        .await;
+
+    let body = serde_json::json!({
+        "success": false,
+        "message": error.to_string(),
+        "order_id": order_oid.to_hex()
+    });
+
+    payment_error_response(&error, body)
+}
@@ -0,0 +1,197 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::FindOptions;
+use futures::stream::TryStreamExt;
+use std::collections::HashMap;
+use crate::models::{FailPayoutRequest, Payout};
+use crate::auth::{is_admin, verify_jwt};
+use crate::ledger;
+use crate::payouts;
+
+/// What the platform owes the caller, per currency.
+#[get("/seller/balance")]
+pub async fn get_balance(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    match ledger::seller_balances(&db, &claims.sub).await {
+        Ok(balances) => HttpResponse::Ok().json(balances),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch balance"),
+    }
+}
+
+#[get("/seller/payouts")]
+pub async fn get_seller_payouts(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    match find_payouts(&db, doc! { "seller_id": &claims.sub }).await {
+        Ok(payouts) => HttpResponse::Ok().json(payouts),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch payouts"),
+    }
+}
+
+/// Payouts across sellers, filtered by `status` and `batch_id`.
+#[get("/admin/payouts")]
+pub async fn get_payouts(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    if !is_admin(&db, &claims.sub).await {
+        return HttpResponse::Forbidden().json("Admin access required");
+    }
+
+    let mut filter = doc! {};
+    for field in ["status", "batch_id"] {
+        if let Some(value) = query.get(field) {
+            filter.insert(field, value);
+        }
+    }
+
+    match find_payouts(&db, filter).await {
+        Ok(payouts) => HttpResponse::Ok().json(payouts),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch payouts"),
+    }
+}
+
+#[post("/admin/payouts/{id}/paid")]
+pub async fn mark_payout_paid(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    if !is_admin(&db, &claims.sub).await {
+        return HttpResponse::Forbidden().json("Admin access required");
+    }
+
+    let payout_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid payout ID"),
+    };
+
+    match payouts::mark_paid(&db, payout_oid).await {
+        Ok(Some(payout)) => HttpResponse::Ok().json(payout),
+        Ok(None) => HttpResponse::Conflict().json("Payout not found or already settled"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to update payout"),
+    }
+}
+
+#[post("/admin/payouts/{id}/failed")]
+pub async fn mark_payout_failed(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    fail_req: web::Json<FailPayoutRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    if !is_admin(&db, &claims.sub).await {
+        return HttpResponse::Forbidden().json("Admin access required");
+    }
+
+    let payout_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid payout ID"),
+    };
+
+    let reason = fail_req.reason.trim();
+    if reason.is_empty() {
+        return HttpResponse::BadRequest().json("reason is required");
+    }
+
+    match payouts::mark_failed(&db, payout_oid, reason).await {
+        Ok(Some(payout)) => HttpResponse::Ok().json(payout),
+        Ok(None) => HttpResponse::Conflict().json("Payout not found or already settled"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to update payout"),
+    }
+}
+
+/// Checks that the ledger balances and every completed sale is recorded in it.
+#[get("/admin/ledger/reconcile")]
+pub async fn reconcile_ledger(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    if !is_admin(&db, &claims.sub).await {
+        return HttpResponse::Forbidden().json("Admin access required");
+    }
+
+    match ledger::reconcile(&db).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to reconcile ledger"),
+    }
+}
+
+async fn find_payouts(db: &Database, filter: mongodb::bson::Document) -> Result<Vec<Payout>, mongodb::error::Error> {
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    db.collection::<Payout>("payouts")
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await
+}
@@ -1,15 +1,33 @@
 use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
 use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
 use chrono::Utc;
 use futures::stream::TryStreamExt;
-use crate::models::{Product, CreateProductRequest};
+use crate::models::{
+    default_tax_category, default_update_policy, CatalogItem, CreateProductRequest, CreateProductVersionRequest, CurrencyQuery,
+    Product, ProductAccessResponse, ProductResponse, ProductVersion, ProductVersionResponse, Purchase, RatingSummary, StoredFile,
+};
 use crate::auth::verify_jwt;
+use crate::bundles;
+use crate::db::with_transaction;
+use crate::fx::{DisplayCurrency, ExchangeRates, FxError};
+use crate::jobs;
+use crate::subscriptions;
+use crate::tax::TaxRules;
+use crate::money::Money;
+use crate::handlers::uploads::{PURPOSE_PRODUCT_FILE, PURPOSE_PRODUCT_IMAGE};
 
 #[get("/products")]
 pub async fn get_products(
     db: web::Data<Database>,
+    fx: web::Data<ExchangeRates>,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> impl Responder {
+    let display = match fx.display_in(query.get("currency")).await {
+        Ok(d) => d,
+        Err(e) => return fx_error_response(&e),
+    };
+
     let collection = db.collection::<Product>("products");
     
     let mut filter = doc! {};
@@ -25,22 +43,46 @@ pub async fn get_products(
         ]);
     }
 
-    match collection.find(filter, None).await {
-        Ok(cursor) => {
-            match cursor.try_collect::<Vec<Product>>().await {
-                Ok(products) => HttpResponse::Ok().json(products),
-                Err(_) => HttpResponse::InternalServerError().json("Failed to fetch products"),
-            }
-        }
-        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch products"),
+    let products = match collection.find(filter.clone(), None).await {
+        Ok(cursor) => match cursor.try_collect::<Vec<Product>>().await {
+            Ok(products) => products,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch products"),
+        },
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch products"),
+    };
+
+    // Bundles are listed after the products, matching the same filters
+    let bundles = match bundles::catalog(&db, filter, display.as_ref()).await {
+        Ok(b) => b,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch products"),
+    };
+
+    let mut items: Vec<CatalogItem> = products
+        .into_iter()
+        .map(|p| CatalogItem::Product(product_response(p, display.as_ref())))
+        .chain(bundles.into_iter().map(CatalogItem::Bundle))
+        .collect();
+
+    // Sort bundles in among the products
+    if let Some(sort) = query.get("sort") {
+        sort_catalog(&mut items, sort);
     }
+
+    HttpResponse::Ok().json(items)
 }
 
 #[get("/products/{id}")]
 pub async fn get_product_by_id(
     db: web::Data<Database>,
+    fx: web::Data<ExchangeRates>,
     id: web::Path<String>,
+    query: web::Query<CurrencyQuery>,
 ) -> impl Responder {
+    let display = match fx.display_in(query.currency.as_ref()).await {
+        Ok(d) => d,
+        Err(e) => return fx_error_response(&e),
+    };
+
     let collection = db.collection::<Product>("products");
     
     let object_id = match ObjectId::parse_str(id.as_str()) {
@@ -49,15 +91,114 @@ pub async fn get_product_by_id(
     };
 
     match collection.find_one(doc! { "_id": object_id }, None).await {
-        Ok(Some(product)) => HttpResponse::Ok().json(product),
+        Ok(Some(product)) => HttpResponse::Ok().json(product_response(product, display.as_ref())),
         Ok(None) => HttpResponse::NotFound().json("Product not found"),
         Err(_) => HttpResponse::InternalServerError().json("Failed to fetch product"),
     }
 }
 
+/// Adds the display price when the client asked for a currency.
+pub fn product_response(product: Product, display: Option<&DisplayCurrency>) -> ProductResponse {
+    let display_price = display.and_then(|d| d.convert(&product.price));
+    ProductResponse { display_price, ..ProductResponse::from(product) }
+}
+
+/// Orders a listing of products and bundles. The sort is stable, so items that
+/// compare equal keep the order the database returned them in.
+///
+/// Prices compare by the converted display price when one was asked for; items
+/// left in their own currency are grouped by currency rather than compared by
+/// amount across currencies.
+pub fn sort_catalog(items: &mut [CatalogItem], sort: &str) {
+    let price_key = |item: &CatalogItem| {
+        let price = item.listed_price();
+        (price.currency.clone(), price.amount)
+    };
+
+    match sort {
+        "popular" => items.sort_by_key(|item| std::cmp::Reverse(item.sales())),
+        "top-rated" => items.sort_by(|a, b| {
+            b.weighted_rating().unwrap_or(f64::MIN).total_cmp(&a.weighted_rating().unwrap_or(f64::MIN))
+        }),
+        "price-low" => items.sort_by_key(price_key),
+        "price-high" => items.sort_by(|a, b| {
+            let ((a_currency, a_amount), (b_currency, b_amount)) = (price_key(a), price_key(b));
+            a_currency.cmp(&b_currency).then(b_amount.cmp(&a_amount))
+        }),
+        _ => items.sort_by_key(|item| std::cmp::Reverse(item.created_at())),
+    }
+}
+
+pub fn fx_error_response(error: &FxError) -> HttpResponse {
+    match error {
+        FxError::Unavailable(_) => HttpResponse::ServiceUnavailable().json(error.to_string()),
+        _ => HttpResponse::BadRequest().json(error.to_string()),
+    }
+}
+
+#[get("/products/{id}/access")]
+pub async fn get_product_access(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let product_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid product ID"),
+    };
+
+    let mut options = mongodb::options::FindOneOptions::default();
+    options.sort = Some(doc! { "created_at": -1 });
+
+    let purchase = db
+        .collection::<Purchase>("purchases")
+        .find_one(
+            doc! { "customer_id": &claims.sub, "product_id": id.as_str(), "status": "completed" },
+            options,
+        )
+        .await;
+
+    let purchase = match purchase {
+        Ok(p) => p,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to check product access"),
+    };
+
+    // A live subscription to the seller counts as owning the products it covers
+    let subscription = match &purchase {
+        Some(_) => None,
+        None => match db.collection::<Product>("products").find_one(doc! { "_id": product_oid }, None).await {
+            Ok(Some(product)) => match subscriptions::covering(&db, &claims.sub, &product).await {
+                Ok(s) => s,
+                Err(_) => return HttpResponse::InternalServerError().json("Failed to check product access"),
+            },
+            Ok(None) => None,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to check product access"),
+        },
+    };
+
+    HttpResponse::Ok().json(ProductAccessResponse {
+        product_id: id.into_inner(),
+        owned: purchase.is_some() || subscription.is_some(),
+        purchase_id: purchase.and_then(|p| p.id).map(|oid| oid.to_hex()),
+        subscription_id: subscription.and_then(|s| s.id).map(|oid| oid.to_hex()),
+    })
+}
+
 #[post("/products")]
 pub async fn create_product(
     db: web::Data<Database>,
+    tax_rules: web::Data<TaxRules>,
     req: HttpRequest,
     product_req: web::Json<CreateProductRequest>,
 ) -> impl Responder {
@@ -73,28 +214,257 @@ pub async fn create_product(
     };
 
     let seller_id = claims.sub;
-    let collection = db.collection::<Product>("products");
+
+    // Products may only reference files their seller uploaded for that purpose
+    match owns_file(&db, &seller_id, &product_req.file_id, PURPOSE_PRODUCT_FILE).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest().json("Unknown product file"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to check product file"),
+    }
+    if let Some(image_file_id) = &product_req.image_file_id {
+        match owns_file(&db, &seller_id, image_file_id, PURPOSE_PRODUCT_IMAGE).await {
+            Ok(true) => {}
+            Ok(false) => return HttpResponse::BadRequest().json("Unknown product image"),
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to check product image"),
+        }
+    }
+
+    let update_policy = product_req.update_policy.clone().unwrap_or_else(default_update_policy);
+    if let Err(message) = validate_update_policy(&update_policy, product_req.update_window_days) {
+        return HttpResponse::BadRequest().json(message);
+    }
+
+    let price = Money::new(product_req.price.amount, &product_req.price.currency);
+    if !price.is_valid_price() {
+        return HttpResponse::BadRequest().json("price must be a non-negative amount in minor units with an ISO 4217 currency code");
+    }
+
+    if matches!(product_req.max_activations, Some(n) if n < 1) {
+        return HttpResponse::BadRequest().json("max_activations must be at least 1");
+    }
+
+    let tax_category = product_req.tax_category.clone().unwrap_or_else(default_tax_category);
+    if !tax_rules.is_category(&tax_category) {
+        return HttpResponse::BadRequest().json(format!(
+            "tax_category must be one of: {}",
+            tax_rules.categories.join(", ")
+        ));
+    }
+
+    let product_oid = ObjectId::new();
+    let now = Utc::now();
 
     let new_product = Product {
-        id: None,
+        id: Some(product_oid),
         seller_id,
         title: product_req.title.clone(),
         description: product_req.description.clone(),
         category: product_req.category.clone(),
-        price: product_req.price,
+        price,
         file_type: product_req.file_type.clone(),
-        file_url: product_req.file_url.clone(),
+        file_id: Some(product_req.file_id.clone()),
+        file_url: None,
         icon: product_req.icon.clone(),
+        image_file_id: product_req.image_file_id.clone(),
+        current_version: 1,
+        update_policy,
+        update_window_days: product_req.update_window_days,
+        license_keys: product_req.license_keys,
+        max_activations: product_req.max_activations,
+        allow_repurchase: product_req.allow_repurchase,
+        download_limit: product_req.download_limit,
+        tax_category,
         rating: None,
+        ratings: RatingSummary::default(),
+        weighted_rating: None,
+        sales: 0,
         downloads: 0,
-        created_at: Utc::now(),
+        created_at: now,
+    };
+
+    let first_version = ProductVersion {
+        id: None,
+        product_id: product_oid.to_hex(),
+        version: 1,
+        changelog: "Initial release".to_string(),
+        file_id: product_req.file_id.clone(),
+        released_at: now,
     };
 
-    match collection.insert_one(new_product, None).await {
-        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+    let db_handle = db.get_ref().clone();
+    let created = with_transaction(&db, move |session| {
+        let db = db_handle.clone();
+        let new_product = new_product.clone();
+        let first_version = first_version.clone();
+        Box::pin(async move {
+            db.collection::<Product>("products")
+                .insert_one_with_session(new_product, None, session)
+                .await?;
+            db.collection::<ProductVersion>("product_versions")
+                .insert_one_with_session(first_version, None, session)
+                .await?;
+            Ok(())
+        })
+    })
+    .await;
+
+    match created {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
             "success": true,
-            "message": "Product created successfully"
+            "message": "Product created successfully",
+            "product_id": product_oid.to_hex()
         })),
         Err(_) => HttpResponse::InternalServerError().json("Failed to create product"),
     }
 }
+
+#[get("/products/{id}/versions")]
+pub async fn get_product_versions(
+    db: web::Data<Database>,
+    id: web::Path<String>,
+) -> impl Responder {
+    let options = FindOptions::builder().sort(doc! { "version": -1 }).build();
+
+    match db
+        .collection::<ProductVersion>("product_versions")
+        .find(doc! { "product_id": id.as_str() }, options)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect::<Vec<ProductVersion>>().await {
+            Ok(versions) => {
+                let versions: Vec<ProductVersionResponse> =
+                    versions.into_iter().map(ProductVersionResponse::from).collect();
+                HttpResponse::Ok().json(versions)
+            }
+            Err(_) => HttpResponse::InternalServerError().json("Failed to fetch product versions"),
+        },
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch product versions"),
+    }
+}
+
+#[post("/products/{id}/versions")]
+pub async fn create_product_version(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    version_req: web::Json<CreateProductVersionRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let product_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid product ID"),
+    };
+
+    if version_req.changelog.trim().is_empty() {
+        return HttpResponse::BadRequest().json("Changelog is required");
+    }
+
+    match owns_file(&db, &claims.sub, &version_req.file_id, PURPOSE_PRODUCT_FILE).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest().json("Unknown product file"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to check product file"),
+    }
+
+    let seller_id = claims.sub.clone();
+    let changelog = version_req.changelog.trim().to_string();
+    let file_id = version_req.file_id.clone();
+    let db_handle = db.get_ref().clone();
+
+    // Bumping the counter and recording the version together keeps version numbers gap-free
+    let released = with_transaction(&db, move |session| {
+        let db = db_handle.clone();
+        let seller_id = seller_id.clone();
+        let changelog = changelog.clone();
+        let file_id = file_id.clone();
+        Box::pin(async move {
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let product = match db
+                .collection::<Product>("products")
+                .find_one_and_update_with_session(
+                    doc! { "_id": product_oid, "seller_id": &seller_id },
+                    doc! { "$inc": { "current_version": 1 }, "$set": { "file_id": &file_id } },
+                    options,
+                    session,
+                )
+                .await?
+            {
+                Some(p) => p,
+                None => return Ok(None),
+            };
+
+            let version = ProductVersion {
+                id: None,
+                product_id: product_oid.to_hex(),
+                version: product.current_version,
+                changelog,
+                file_id,
+                released_at: Utc::now(),
+            };
+            db.collection::<ProductVersion>("product_versions")
+                .insert_one_with_session(&version, None, session)
+                .await?;
+
+            Ok(Some(version))
+        })
+    })
+    .await;
+
+    let version = match released {
+        Ok(Some(v)) => v,
+        Ok(None) => return HttpResponse::NotFound().json("Product not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to release version"),
+    };
+
+    let scheduled = jobs::schedule(
+        &db,
+        "product_update",
+        Utc::now(),
+        doc! { "product_id": &version.product_id, "version": version.version },
+        Some(format!("product_update:{}:{}", version.product_id, version.version)),
+    )
+    .await;
+    if let Err(e) = scheduled {
+        log::error!("Failed to schedule update notifications for {}: {}", version.product_id, e);
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "message": "Version released successfully",
+        "version": ProductVersionResponse::from(version)
+    }))
+}
+
+fn validate_update_policy(policy: &str, window_days: Option<i32>) -> Result<(), &'static str> {
+    match (policy, window_days) {
+        ("lifetime", _) | ("none", _) => Ok(()),
+        ("window", Some(days)) if days > 0 => Ok(()),
+        ("window", _) => Err("update_window_days must be positive for the window update policy"),
+        _ => Err("update_policy must be one of: lifetime, none, window"),
+    }
+}
+
+async fn owns_file(db: &Database, owner_id: &str, file_id: &str, purpose: &str) -> mongodb::error::Result<bool> {
+    let oid = match ObjectId::parse_str(file_id) {
+        Ok(oid) => oid,
+        Err(_) => return Ok(false),
+    };
+
+    let file = db
+        .collection::<StoredFile>("files")
+        .find_one(doc! { "_id": oid, "owner_id": owner_id, "purpose": purpose }, None)
+        .await?;
+
+    Ok(file.is_some())
+}
@@ -1,13 +1,28 @@
 use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
-use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use actix_web::http::header::ContentDisposition;
+use mongodb::{Collection, Database, bson::{doc, oid::ObjectId}};
 use chrono::Utc;
 use futures::stream::TryStreamExt;
-use crate::models::{Purchase, CreatePurchaseRequest, Product};
+use std::collections::HashMap;
+use crate::models::{Purchase, CreatePurchaseRequest, DownloadLinkQuery, LibraryItem, Product, StoredFile};
 use crate::auth::verify_jwt;
+use crate::coupons;
+use crate::db::is_duplicate_key;
+use crate::download_links::{signed_download_url, verify_download_link};
+use crate::fulfillment;
+use crate::handlers::coupons::coupon_error_response;
+use crate::idempotency;
+use crate::licenses;
+use crate::payments::{PaymentError, PaymentGateway};
+use crate::storage::Storage;
+use crate::subscriptions;
+use crate::tax::{self, TaxCalculation, TaxRules};
 
 #[post("/purchases")]
 pub async fn create_purchase(
     db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    tax_rules: web::Data<TaxRules>,
     req: HttpRequest,
     purchase_req: web::Json<CreatePurchaseRequest>,
 ) -> impl Responder {
@@ -23,6 +38,45 @@ pub async fn create_purchase(
     };
 
     let customer_id = claims.sub;
+
+    let idempotency_key = match idempotency::key_from_request(&req) {
+        Ok(key) => key,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    if let Some(key) = &idempotency_key {
+        if let Err(response) = idempotency::begin(&db, &customer_id, key, &*purchase_req).await {
+            return response;
+        }
+    }
+
+    let response = purchase_product(
+        &db,
+        gateway.get_ref(),
+        &tax_rules,
+        customer_id.clone(),
+        purchase_req.into_inner(),
+    )
+    .await;
+
+    match idempotency_key {
+        Some(key) => idempotency::finish(&db, &customer_id, &key, response).await,
+        None => response,
+    }
+}
+
+async fn purchase_product(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    tax_rules: &TaxRules,
+    customer_id: String,
+    purchase_req: CreatePurchaseRequest,
+) -> HttpResponse {
+    let billing_address = match &purchase_req.billing_address {
+        Some(address) => address,
+        None => return HttpResponse::BadRequest().json("billing_address is required to calculate tax"),
+    };
+
     let products_collection = db.collection::<Product>("products");
 
     let product_oid = match ObjectId::parse_str(&purchase_req.product_id) {
@@ -35,37 +89,270 @@ pub async fn create_purchase(
         _ => return HttpResponse::NotFound().json("Product not found"),
     };
 
+    let (price, discounts) = match coupons::apply(db, &product, &purchase_req.coupon_codes, &customer_id, Utc::now()).await {
+        Ok(priced) => priced,
+        Err(e) => return coupon_error_response(&e),
+    };
+
+    let seller = match tax::seller_profiles(db, std::slice::from_ref(&product.seller_id)).await {
+        Ok(mut profiles) => profiles.remove(&product.seller_id).unwrap_or_default(),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to calculate tax"),
+    };
+    let tax = match tax_rules.calculate(&price, &product.tax_category, billing_address, &seller) {
+        Ok(tax) => tax,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+
     let purchases_collection = db.collection::<Purchase>("purchases");
 
+    let ownership_key = if product.allow_repurchase {
+        None
+    } else {
+        Some(format!("{}:{}", customer_id, purchase_req.product_id))
+    };
+
     let new_purchase = Purchase {
-        id: None,
-        customer_id,
-        product_id: purchase_req.product_id.clone(),
-        payment_method: purchase_req.payment_method.clone(),
-        amount: product.price,
-        status: "completed".to_string(),
-        download_url: product.file_url.clone(),
-        created_at: Utc::now(),
+        billing_address: purchase_req.billing_address.clone(),
+        discounts: discounts.clone(),
+        ..pending_purchase(&customer_id, &product, &purchase_req.payment_method, tax, ownership_key, None)
     };
+    let license_key = new_purchase.license_key.clone();
+    let amount = new_purchase.amount.clone();
+    let tax_lines = new_purchase.tax_lines.clone();
 
-    match purchases_collection.insert_one(new_purchase, None).await {
-        Ok(_) => {
-            // Update download count
-            let _ = products_collection
-                .update_one(
-                    doc! { "_id": product_oid },
-                    doc! { "$inc": { "downloads": 1 } },
-                    None,
-                )
-                .await;
+    if let Err(e) = coupons::reserve(db, &customer_id, &discounts).await {
+        return coupon_error_response(&e);
+    }
+
+    let purchase_oid = match purchases_collection.insert_one(new_purchase, None).await {
+        Ok(result) => match result.inserted_id.as_object_id() {
+            Some(oid) => oid,
+            None => return HttpResponse::InternalServerError().json("Failed to create purchase"),
+        },
+        Err(e) => {
+            coupons::release(db, &customer_id, &discounts).await;
+            if is_duplicate_key(&e) {
+                return HttpResponse::Conflict().json(serde_json::json!({
+                    "success": false,
+                    "message": "You already own this product"
+                }));
+            }
+            return HttpResponse::InternalServerError().json("Failed to create purchase");
+        }
+    };
+
+    let metadata = HashMap::from([
+        ("purchase_id".to_string(), purchase_oid.to_hex()),
+        ("product_id".to_string(), purchase_req.product_id.clone()),
+        ("customer_id".to_string(), customer_id),
+    ]);
+
+    let intent = match gateway.create_intent(amount.amount, &amount.currency, &metadata).await {
+        Ok(intent) => intent,
+        Err(e) => return fail_purchase(db, purchase_oid, "pending", e).await,
+    };
+
+    // Webhooks and refunds find the payment through its intent, so don't charge without it
+    let recorded = purchases_collection
+        .update_one(
+            doc! { "_id": purchase_oid },
+            doc! { "$set": { "payment_intent_id": &intent.id } },
+            None,
+        )
+        .await;
+    if recorded.is_err() {
+        let error = PaymentError::Gateway("Failed to record the payment".to_string());
+        return fail_purchase(db, purchase_oid, "pending", error).await;
+    }
+
+    if let Err(e) = gateway.confirm(&intent.id, &purchase_req.payment_method).await {
+        return fail_purchase(db, purchase_oid, "pending", e).await;
+    }
+
+    let authorized = match transition_purchase(&purchases_collection, purchase_oid, "pending", "authorized").await {
+        Ok(true) => true,
+        // The authorization webhook may have got there first
+        Ok(false) => matches!(
+            purchases_collection.find_one(doc! { "_id": purchase_oid, "status": "authorized" }, None).await,
+            Ok(Some(_))
+        ),
+        Err(_) => false,
+    };
 
+    // Don't leave funds held against a purchase that can't move on
+    if !authorized {
+        if let Err(e) = gateway.cancel(&intent.id).await {
+            log::warn!("Failed to void payment intent {}: {}", intent.id, e);
+        }
+        let error = PaymentError::Gateway("Failed to record the payment authorization".to_string());
+        return fail_purchase(db, purchase_oid, "pending", error).await;
+    }
+
+    if let Err(e) = gateway.capture(&intent.id).await {
+        if let Err(cancel_error) = gateway.cancel(&intent.id).await {
+            log::warn!("Failed to void payment intent {}: {}", intent.id, cancel_error);
+        }
+        return fail_purchase(db, purchase_oid, "authorized", e).await;
+    }
+
+    // A webhook may have completed the purchase first, which is just as good
+    match fulfillment::complete_purchase(db, purchase_oid, &["authorized"]).await {
+        Ok(_) => {
             HttpResponse::Ok().json(serde_json::json!({
                 "success": true,
                 "message": "Purchase successful",
-                "download_url": product.file_url
+                "purchase_id": purchase_oid.to_hex(),
+                "amount": amount,
+                "discounts": discounts,
+                "tax_lines": tax_lines,
+                "download_url": signed_download_url(&purchase_oid.to_hex()),
+                "license_key": license_key
             }))
         }
-        Err(_) => HttpResponse::InternalServerError().json("Failed to create purchase"),
+        _ => idempotency::charged(HttpResponse::InternalServerError().json("Failed to update purchase")),
+    }
+}
+
+#[get("/purchases/{id}/download")]
+pub async fn download_purchase(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let purchase_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid purchase ID"),
+    };
+
+    let purchase = match db
+        .collection::<Purchase>("purchases")
+        .find_one(doc! { "_id": purchase_oid, "customer_id": &claims.sub }, None)
+        .await
+    {
+        Ok(Some(p)) => p,
+        Ok(None) => return HttpResponse::NotFound().json("Purchase not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch purchase"),
+    };
+
+    if purchase.status != "completed" {
+        return HttpResponse::Forbidden().json("Purchase is not completed");
+    }
+
+    if let Some(limit) = purchase.download_limit {
+        if purchase.download_count >= limit {
+            return HttpResponse::Forbidden().json("Download limit reached");
+        }
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "download_url": signed_download_url(id.as_str()),
+        "downloads_remaining": purchase.download_limit.map(|limit| limit - purchase.download_count)
+    }))
+}
+
+#[get("/downloads/{purchase_id}")]
+pub async fn serve_download(
+    db: web::Data<Database>,
+    storage: web::Data<dyn Storage>,
+    purchase_id: web::Path<String>,
+    query: web::Query<DownloadLinkQuery>,
+) -> impl Responder {
+    if !verify_download_link(purchase_id.as_str(), query.expires, &query.signature) {
+        return HttpResponse::Forbidden().json("Download link is invalid or has expired");
+    }
+
+    let purchase_oid = match ObjectId::parse_str(purchase_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid purchase ID"),
+    };
+
+    let purchase = match db
+        .collection::<Purchase>("purchases")
+        .find_one(doc! { "_id": purchase_oid }, None)
+        .await
+    {
+        Ok(Some(p)) => p,
+        Ok(None) => return HttpResponse::NotFound().json("Purchase not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch purchase"),
+    };
+
+    // Links outlive refunds by a few minutes at most, but still re-check
+    if purchase.status != "completed" {
+        return HttpResponse::Forbidden().json("Purchase is not completed");
+    }
+
+    let product = match ObjectId::parse_str(&purchase.product_id) {
+        Ok(oid) => match db.collection::<Product>("products").find_one(doc! { "_id": oid }, None).await {
+            Ok(Some(p)) => p,
+            Ok(None) => return HttpResponse::NotFound().json("Product not found"),
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch product"),
+        },
+        Err(_) => return HttpResponse::NotFound().json("Product not found"),
+    };
+
+    let file_id = if product.current_version > 0 {
+        match fulfillment::entitled_version(&db, &product, &purchase).await {
+            Ok(Some(version)) => Some(version.file_id),
+            Ok(None) => return HttpResponse::NotFound().json("Product file not found"),
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch product version"),
+        }
+    } else {
+        product.file_id.clone()
+    };
+
+    let file_oid = match file_id.as_deref().map(ObjectId::parse_str) {
+        Some(Ok(oid)) => oid,
+        Some(Err(_)) => return HttpResponse::NotFound().json("Product file not found"),
+        // Products created before uploads still point at an external URL
+        None => {
+            let file_url = match product.file_url {
+                Some(url) => url,
+                None => return HttpResponse::NotFound().json("Product file not found"),
+            };
+            return match fulfillment::record_download(&db, &purchase).await {
+                Ok(true) => HttpResponse::Found()
+                    .insert_header(("Location", file_url))
+                    .finish(),
+                Ok(false) => HttpResponse::Forbidden().json("Download limit reached"),
+                Err(_) => HttpResponse::InternalServerError().json("Failed to record download"),
+            };
+        }
+    };
+
+    let file = match db.collection::<StoredFile>("files").find_one(doc! { "_id": file_oid }, None).await {
+        Ok(Some(f)) => f,
+        Ok(None) => return HttpResponse::NotFound().json("Product file not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch product file"),
+    };
+
+    // Fetch before counting so a storage outage doesn't use up a download
+    let data = match storage.get(&file.storage_key).await {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Failed to read {}: {}", file.storage_key, e);
+            return HttpResponse::InternalServerError().json("Failed to read product file");
+        }
+    };
+
+    match fulfillment::record_download(&db, &purchase).await {
+        Ok(true) => HttpResponse::Ok()
+            .content_type(file.content_type)
+            .insert_header(ContentDisposition::attachment(file.original_name))
+            .body(data),
+        Ok(false) => HttpResponse::Forbidden().json("Download limit reached"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to record download"),
     }
 }
 
@@ -102,3 +389,184 @@ pub async fn get_user_purchases(
         Err(_) => HttpResponse::InternalServerError().json("Failed to fetch purchases"),
     }
 }
+
+#[get("/library")]
+pub async fn get_library(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let mut options = mongodb::options::FindOptions::default();
+    options.sort = Some(doc! { "created_at": -1 });
+
+    let purchases = match db
+        .collection::<Purchase>("purchases")
+        .find(doc! { "customer_id": &claims.sub, "status": "completed" }, options)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect::<Vec<Purchase>>().await {
+            Ok(p) => p,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch library"),
+        },
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch library"),
+    };
+
+    let product_oids: Vec<ObjectId> = purchases
+        .iter()
+        .filter_map(|p| ObjectId::parse_str(&p.product_id).ok())
+        .collect();
+
+    let products: HashMap<String, Product> = match db
+        .collection::<Product>("products")
+        .find(doc! { "_id": { "$in": product_oids } }, None)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect::<Vec<Product>>().await {
+            Ok(products) => products
+                .into_iter()
+                .filter_map(|p| p.id.map(|oid| (oid.to_hex(), p)))
+                .collect(),
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch library"),
+        },
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch library"),
+    };
+
+    // Newest purchase first, so repeat purchases of a consumable collapse onto the latest
+    let mut seen = std::collections::HashSet::new();
+    let mut library: Vec<LibraryItem> = purchases
+        .into_iter()
+        .filter(|p| seen.insert(p.product_id.clone()))
+        .filter_map(|p| {
+            let product = products.get(&p.product_id)?.clone();
+            Some(LibraryItem {
+                purchase_id: Some(p.id?.to_hex()),
+                subscription_id: None,
+                purchased_at: p.created_at,
+                license_key: p.license_key,
+                product: product.into(),
+            })
+        })
+        .collect();
+
+    let covered = match subscriptions::covered_products(&db, &claims.sub).await {
+        Ok(covered) => covered,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch library"),
+    };
+
+    // Products bought outright are listed once, as purchases
+    for (subscription, product) in covered {
+        let product_id = product.id.map(|oid| oid.to_hex()).unwrap_or_default();
+        if seen.insert(product_id) {
+            library.push(LibraryItem {
+                purchase_id: None,
+                subscription_id: subscription.id.map(|oid| oid.to_hex()),
+                purchased_at: subscription.created_at,
+                license_key: None,
+                product: product.into(),
+            });
+        }
+    }
+
+    HttpResponse::Ok().json(library)
+}
+
+// Moves a purchase between statuses, refusing if it is no longer in `from`.
+async fn transition_purchase(
+    collection: &Collection<Purchase>,
+    purchase_oid: ObjectId,
+    from: &str,
+    to: &str,
+) -> Result<bool, mongodb::error::Error> {
+    collection
+        .update_one(
+            doc! { "_id": purchase_oid, "status": from },
+            doc! { "$set": { "status": to } },
+            None,
+        )
+        .await
+        .map(|result| result.modified_count == 1)
+}
+
+/// A new pending purchase of `product`, snapshotting the product's terms.
+pub fn pending_purchase(
+    customer_id: &str,
+    product: &Product,
+    payment_method: &str,
+    tax: TaxCalculation,
+    ownership_key: Option<String>,
+    order_id: Option<String>,
+) -> Purchase {
+    Purchase {
+        id: None,
+        customer_id: customer_id.to_string(),
+        product_id: product.id.map(|oid| oid.to_hex()).unwrap_or_default(),
+        payment_method: payment_method.to_string(),
+        amount: tax.total,
+        discounts: Vec::new(),
+        tax_lines: tax.lines,
+        tax_rules_version: Some(tax.rules_version),
+        refunded_amount: None,
+        status: "pending".to_string(),
+        payment_intent_id: None,
+        failure_reason: None,
+        ownership_key,
+        version: (product.current_version > 0).then_some(product.current_version),
+        download_limit: product.download_limit,
+        download_count: 0,
+        license_key: product.license_keys.then(licenses::generate_key),
+        max_activations: product.max_activations,
+        activation_count: 0,
+        license_revoked: false,
+        order_id,
+        billing_address: None,
+        created_at: Utc::now(),
+    }
+}
+
+async fn fail_purchase(
+    db: &Database,
+    purchase_oid: ObjectId,
+    from: &str,
+    error: PaymentError,
+) -> HttpResponse {
+    let failed = db
+        .collection::<Purchase>("purchases")
+        .find_one_and_update(
+            doc! { "_id": purchase_oid, "status": from },
+            doc! {
+                "$set": { "status": "failed", "failure_reason": error.to_string() },
+                "$unset": { "ownership_key": "" }
+            },
+            None,
+        )
+        .await;
+    if let Ok(Some(purchase)) = failed {
+        coupons::release_purchase(db, &purchase).await;
+    }
+
+    let body = serde_json::json!({
+        "success": false,
+        "message": error.to_string(),
+        "purchase_id": purchase_oid.to_hex()
+    });
+
+    payment_error_response(&error, body)
+}
+
+pub fn payment_error_response(error: &PaymentError, body: serde_json::Value) -> HttpResponse {
+    match error {
+        PaymentError::Declined(_) => HttpResponse::PaymentRequired().json(body),
+        PaymentError::InvalidRequest(_) => HttpResponse::BadRequest().json(body),
+        _ => HttpResponse::BadGateway().json(body),
+    }
+}
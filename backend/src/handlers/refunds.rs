@@ -0,0 +1,279 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::FindOptions;
+use futures::stream::TryStreamExt;
+use std::collections::HashMap;
+use crate::models::{CreateRefundRequest, Refund, RejectRefundRequest};
+use crate::auth::{is_admin, verify_jwt};
+use crate::handlers::purchases::payment_error_response;
+use crate::payments::PaymentGateway;
+use crate::refunds::{self, RefundError, KIND_BOOKING, KIND_PURCHASE};
+
+/// Refunds a purchase in full or in part; for its seller or an admin.
+#[post("/purchases/{id}/refund")]
+pub async fn refund_purchase(
+    db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    refund_req: web::Json<CreateRefundRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    issue_refund(&db, gateway.get_ref(), KIND_PURCHASE, &id, &refund_req, &claims.sub).await
+}
+
+/// Refunds a booking paid through the platform; for its provider or an admin.
+#[post("/bookings/{id}/refund")]
+pub async fn refund_booking(
+    db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    refund_req: web::Json<CreateRefundRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    issue_refund(&db, gateway.get_ref(), KIND_BOOKING, &id, &refund_req, &claims.sub).await
+}
+
+/// Asks the seller to refund one of the caller's purchases.
+#[post("/purchases/{id}/refund-request")]
+pub async fn request_purchase_refund(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    refund_req: web::Json<CreateRefundRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    request_refund(&db, KIND_PURCHASE, &id, &refund_req, &claims.sub).await
+}
+
+/// Asks the provider to refund one of the caller's bookings.
+#[post("/bookings/{id}/refund-request")]
+pub async fn request_booking_refund(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    refund_req: web::Json<CreateRefundRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    request_refund(&db, KIND_BOOKING, &id, &refund_req, &claims.sub).await
+}
+
+/// Refunds the caller asked for, or those waiting on them with `?role=seller`.
+#[get("/refunds")]
+pub async fn get_refunds(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let mut filter = match query.get("role").map(|r| r.as_str()) {
+        Some("seller") => doc! { "seller_id": &claims.sub },
+        _ => doc! { "customer_id": &claims.sub },
+    };
+    if let Some(status) = query.get("status") {
+        filter.insert("status", status);
+    }
+
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    let refunds = match db.collection::<Refund>("refunds").find(filter, options).await {
+        Ok(cursor) => cursor.try_collect::<Vec<Refund>>().await,
+        Err(e) => Err(e),
+    };
+
+    match refunds {
+        Ok(refunds) => HttpResponse::Ok().json(refunds),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch refunds"),
+    }
+}
+
+#[post("/refunds/{id}/approve")]
+pub async fn approve_refund(
+    db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let refund_oid = match decidable_refund(&db, &id, &claims.sub).await {
+        Ok(oid) => oid,
+        Err(e) => return refund_error_response(&e),
+    };
+
+    match refunds::approve(&db, gateway.get_ref(), refund_oid, &claims.sub).await {
+        Ok(refund) => HttpResponse::Ok().json(refund),
+        Err(e) => refund_error_response(&e),
+    }
+}
+
+#[post("/refunds/{id}/reject")]
+pub async fn reject_refund(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    reject_req: web::Json<RejectRefundRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let reason = reject_req.reason.trim();
+    if reason.is_empty() {
+        return HttpResponse::BadRequest().json("reason is required");
+    }
+
+    let refund_oid = match decidable_refund(&db, &id, &claims.sub).await {
+        Ok(oid) => oid,
+        Err(e) => return refund_error_response(&e),
+    };
+
+    match refunds::reject(&db, refund_oid, &claims.sub, reason).await {
+        Ok(refund) => HttpResponse::Ok().json(refund),
+        Err(e) => refund_error_response(&e),
+    }
+}
+
+async fn issue_refund(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    kind: &str,
+    subject_id: &str,
+    refund_req: &CreateRefundRequest,
+    user_id: &str,
+) -> HttpResponse {
+    let reason = refund_req.reason.trim();
+    if reason.is_empty() {
+        return HttpResponse::BadRequest().json("reason is required");
+    }
+
+    let charge = match refunds::load_charge(db, kind, subject_id).await {
+        Ok(c) => c,
+        Err(e) => return refund_error_response(&e),
+    };
+
+    if charge.seller_id != user_id && !is_admin(db, user_id).await {
+        return HttpResponse::Forbidden().json("Only the seller or an admin can issue refunds");
+    }
+
+    match refunds::issue(db, gateway, &charge, refund_req.amount, reason, user_id).await {
+        Ok(refund) => HttpResponse::Ok().json(refund),
+        Err(e) => refund_error_response(&e),
+    }
+}
+
+async fn request_refund(
+    db: &Database,
+    kind: &str,
+    subject_id: &str,
+    refund_req: &CreateRefundRequest,
+    user_id: &str,
+) -> HttpResponse {
+    let reason = refund_req.reason.trim();
+    if reason.is_empty() {
+        return HttpResponse::BadRequest().json("reason is required");
+    }
+
+    let charge = match refunds::load_charge(db, kind, subject_id).await {
+        Ok(c) if c.customer_id == user_id => c,
+        Ok(_) => return refund_error_response(&RefundError::NotFound),
+        Err(e) => return refund_error_response(&e),
+    };
+
+    match refunds::request(db, &charge, refund_req.amount, reason).await {
+        Ok(refund) => HttpResponse::Created().json(refund),
+        Err(e) => refund_error_response(&e),
+    }
+}
+
+// Requests are decided by the seller they were sent to, or by an admin
+async fn decidable_refund(db: &Database, id: &str, user_id: &str) -> Result<ObjectId, RefundError> {
+    let refund_oid = ObjectId::parse_str(id).map_err(|_| RefundError::NotFound)?;
+    let refund = db
+        .collection::<Refund>("refunds")
+        .find_one(doc! { "_id": refund_oid }, None)
+        .await?
+        .ok_or(RefundError::NotFound)?;
+
+    if refund.seller_id != user_id && !is_admin(db, user_id).await {
+        return Err(RefundError::NotFound);
+    }
+
+    Ok(refund_oid)
+}
+
+fn refund_error_response(error: &RefundError) -> HttpResponse {
+    match error {
+        RefundError::NotFound => HttpResponse::NotFound().json("Not found"),
+        RefundError::InvalidAmount(msg) => HttpResponse::BadRequest().json(msg),
+        RefundError::NotRefundable(msg) => HttpResponse::Conflict().json(msg),
+        RefundError::Payment(e) => payment_error_response(e, serde_json::json!(e.to_string())),
+        RefundError::Database(_) => HttpResponse::InternalServerError().json("Failed to process refund"),
+    }
+}
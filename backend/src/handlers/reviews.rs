@@ -1,9 +1,12 @@
-use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
-use mongodb::{Database, bson::doc};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use chrono::Utc;
 use futures::stream::TryStreamExt;
-use crate::models::{Review, CreateReviewRequest, Service, Product};
+use crate::models::{Booking, Review, CreateReviewRequest, Service, Product, Purchase, ReviewEdit, UpdateReviewRequest};
 use crate::auth::verify_jwt;
+use crate::db::is_duplicate_key;
+use crate::reviews;
 
 #[post("/reviews")]
 pub async fn create_review(
@@ -25,6 +28,53 @@ pub async fn create_review(
     let user_id = claims.sub;
     let collection = db.collection::<Review>("reviews");
 
+    if !(1..=5).contains(&review_req.rating) {
+        return HttpResponse::BadRequest().json("rating must be between 1 and 5");
+    }
+
+    let item_oid = match ObjectId::parse_str(&review_req.item_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid item ID"),
+    };
+
+    // Only customers who bought the product or completed a booking of the service may review it
+    let (exists, verified, missing, reason) = match review_req.item_type.as_str() {
+        "product" => (
+            db.collection::<Product>("products").count_documents(doc! { "_id": item_oid }, None).await,
+            db.collection::<Purchase>("purchases")
+                .count_documents(
+                    doc! { "customer_id": &user_id, "product_id": &review_req.item_id, "status": "completed" },
+                    None,
+                )
+                .await,
+            "Product not found",
+            "You can only review products you have purchased",
+        ),
+        "service" => (
+            db.collection::<Service>("services").count_documents(doc! { "_id": item_oid }, None).await,
+            db.collection::<Booking>("bookings")
+                .count_documents(
+                    doc! { "customer_id": &user_id, "service_id": &review_req.item_id, "status": "completed" },
+                    None,
+                )
+                .await,
+            "Service not found",
+            "You can only review services after a completed booking",
+        ),
+        _ => return HttpResponse::BadRequest().json("item_type must be product or service"),
+    };
+
+    match exists {
+        Ok(0) => return HttpResponse::NotFound().json(missing),
+        Ok(_) => {}
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to create review"),
+    }
+    match verified {
+        Ok(0) => return HttpResponse::Forbidden().json(reason),
+        Ok(_) => {}
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to create review"),
+    }
+
     let new_review = Review {
         id: None,
         user_id,
@@ -32,49 +82,138 @@ pub async fn create_review(
         item_type: review_req.item_type.clone(),
         rating: review_req.rating,
         comment: review_req.comment.clone(),
+        verified: true,
+        edits: Vec::new(),
         created_at: Utc::now(),
+        updated_at: None,
     };
 
     match collection.insert_one(new_review, None).await {
         Ok(_) => {
-            // Update average rating
-            let filter = doc! { "item_id": &review_req.item_id, "item_type": &review_req.item_type };
-            
-            if let Ok(cursor) = collection.find(filter, None).await {
-                if let Ok(reviews) = cursor.try_collect::<Vec<Review>>().await {
-                    let avg_rating: f64 = reviews.iter().map(|r| r.rating as f64).sum::<f64>() / reviews.len() as f64;
-                    
-                    if review_req.item_type == "service" {
-                        if let Ok(oid) = mongodb::bson::oid::ObjectId::parse_str(&review_req.item_id) {
-                            let services = db.collection::<Service>("services");
-                            let _ = services.update_one(
-                                doc! { "_id": oid },
-                                doc! { "$set": { "rating": avg_rating } },
-                                None,
-                            ).await;
-                        }
-                    } else if review_req.item_type == "product" {
-                        if let Ok(oid) = mongodb::bson::oid::ObjectId::parse_str(&review_req.item_id) {
-                            let products = db.collection::<Product>("products");
-                            let _ = products.update_one(
-                                doc! { "_id": oid },
-                                doc! { "$set": { "rating": avg_rating } },
-                                None,
-                            ).await;
-                        }
-                    }
-                }
-            }
+            record_rating(&db, &review_req.item_type, &review_req.item_id, None, Some(review_req.rating)).await;
 
             HttpResponse::Ok().json(serde_json::json!({
                 "success": true,
                 "message": "Review created successfully"
             }))
         }
+        Err(e) if is_duplicate_key(&e) => {
+            HttpResponse::Conflict().json("You have already reviewed this item; edit your review instead")
+        }
         Err(_) => HttpResponse::InternalServerError().json("Failed to create review"),
     }
 }
 
+/// Lets the author change their review; the previous version is kept in `edits`.
+#[put("/reviews/{id}")]
+pub async fn update_review(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    review_req: web::Json<UpdateReviewRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let review_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid review ID"),
+    };
+
+    if !(1..=5).contains(&review_req.rating) {
+        return HttpResponse::BadRequest().json("rating must be between 1 and 5");
+    }
+
+    // A pipeline update, so the version moved into `edits` is the one being replaced
+    let now = Utc::now();
+    let update = vec![doc! { "$set": {
+        "edits": { "$concatArrays": [
+            { "$ifNull": ["$edits", []] },
+            [{ "rating": "$rating", "comment": "$comment", "edited_at": now }]
+        ] },
+        "rating": review_req.rating,
+        "comment": &review_req.comment,
+        "updated_at": now,
+    } }];
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::Before)
+        .build();
+
+    match db
+        .collection::<Review>("reviews")
+        .find_one_and_update(doc! { "_id": review_oid, "user_id": &claims.sub }, update, options)
+        .await
+    {
+        Ok(Some(mut review)) => {
+            // The document as it was before the update, so the old rating can be taken off
+            record_rating(&db, &review.item_type, &review.item_id, Some(review.rating), Some(review_req.rating)).await;
+
+            review.edits.push(ReviewEdit {
+                rating: review.rating,
+                comment: std::mem::replace(&mut review.comment, review_req.comment.clone()),
+                edited_at: now,
+            });
+            review.rating = review_req.rating;
+            review.updated_at = Some(now);
+
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "message": "Review updated successfully",
+                "review": review
+            }))
+        }
+        Ok(None) => HttpResponse::NotFound().json("Review not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to update review"),
+    }
+}
+
+#[delete("/reviews/{id}")]
+pub async fn delete_review(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let review_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid review ID"),
+    };
+
+    match db
+        .collection::<Review>("reviews")
+        .find_one_and_delete(doc! { "_id": review_oid, "user_id": &claims.sub }, None)
+        .await
+    {
+        Ok(Some(review)) => {
+            record_rating(&db, &review.item_type, &review.item_id, Some(review.rating), None).await;
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "message": "Review deleted successfully"
+            }))
+        }
+        Ok(None) => HttpResponse::NotFound().json("Review not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to delete review"),
+    }
+}
+
 #[get("/reviews/{item_type}/{item_id}")]
 pub async fn get_reviews(
     db: web::Data<Database>,
@@ -97,3 +236,11 @@ pub async fn get_reviews(
         Err(_) => HttpResponse::InternalServerError().json("Failed to fetch reviews"),
     }
 }
+
+// The review itself is already saved, so a failed update is only logged; the
+// repair job corrects the counters later
+async fn record_rating(db: &Database, item_type: &str, item_id: &str, removed: Option<i32>, added: Option<i32>) {
+    if let Err(e) = reviews::record(db, item_type, item_id, removed, added).await {
+        log::warn!("Failed to update rating of {} {}: {}", item_type, item_id, e);
+    }
+}
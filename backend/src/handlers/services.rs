@@ -2,14 +2,24 @@ use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
 use mongodb::{Database, bson::{doc, oid::ObjectId}};
 use chrono::Utc;
 use futures::stream::TryStreamExt;
-use crate::models::{Service, CreateServiceRequest};
+use crate::models::{default_cancellation_policy, Service, CreateServiceRequest, CurrencyQuery, RatingSummary, ServiceResponse};
+use crate::fx::{DisplayCurrency, ExchangeRates};
+use crate::handlers::products::fx_error_response;
 use crate::auth::verify_jwt;
+use crate::escrow;
+use crate::money::Money;
 
 #[get("/services")]
 pub async fn get_services(
     db: web::Data<Database>,
+    fx: web::Data<ExchangeRates>,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> impl Responder {
+    let display = match fx.display_in(query.get("currency")).await {
+        Ok(d) => d,
+        Err(e) => return fx_error_response(&e),
+    };
+
     let collection = db.collection::<Service>("services");
     
     let mut filter = doc! {};
@@ -29,10 +39,20 @@ pub async fn get_services(
         ]);
     }
 
-    match collection.find(filter, None).await {
+    let mut options = mongodb::options::FindOptions::default();
+    if query.get("sort").map(String::as_str) == Some("top-rated") {
+        options.sort = Some(doc! { "weighted_rating": -1 });
+    }
+
+    match collection.find(filter, options).await {
         Ok(cursor) => {
             match cursor.try_collect::<Vec<Service>>().await {
-                Ok(services) => HttpResponse::Ok().json(services),
+                Ok(services) => HttpResponse::Ok().json(
+                    services
+                        .into_iter()
+                        .map(|s| service_response(s, display.as_ref()))
+                        .collect::<Vec<_>>()
+                ),
                 Err(_) => HttpResponse::InternalServerError().json("Failed to fetch services"),
             }
         }
@@ -43,8 +63,15 @@ pub async fn get_services(
 #[get("/services/{id}")]
 pub async fn get_service_by_id(
     db: web::Data<Database>,
+    fx: web::Data<ExchangeRates>,
     id: web::Path<String>,
+    query: web::Query<CurrencyQuery>,
 ) -> impl Responder {
+    let display = match fx.display_in(query.currency.as_ref()).await {
+        Ok(d) => d,
+        Err(e) => return fx_error_response(&e),
+    };
+
     let collection = db.collection::<Service>("services");
     
     let object_id = match ObjectId::parse_str(id.as_str()) {
@@ -53,12 +80,17 @@ pub async fn get_service_by_id(
     };
 
     match collection.find_one(doc! { "_id": object_id }, None).await {
-        Ok(Some(service)) => HttpResponse::Ok().json(service),
+        Ok(Some(service)) => HttpResponse::Ok().json(service_response(service, display.as_ref())),
         Ok(None) => HttpResponse::NotFound().json("Service not found"),
         Err(_) => HttpResponse::InternalServerError().json("Failed to fetch service"),
     }
 }
 
+fn service_response(service: Service, display: Option<&DisplayCurrency>) -> ServiceResponse {
+    let display_price = display.and_then(|d| d.convert(&service.price));
+    ServiceResponse { service, display_price }
+}
+
 #[post("/services")]
 pub async fn create_service(
     db: web::Data<Database>,
@@ -76,6 +108,23 @@ pub async fn create_service(
         Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
     };
 
+    let price = Money::new(service_req.price.amount, &service_req.price.currency);
+    if !price.is_valid_price() {
+        return HttpResponse::BadRequest().json("price must be a non-negative amount in minor units with an ISO 4217 currency code");
+    }
+
+    if matches!(service_req.deposit_rate, Some(rate) if !(rate > 0.0 && rate <= 1.0)) {
+        return HttpResponse::BadRequest().json("deposit_rate must be greater than 0 and at most 1");
+    }
+
+    let cancellation_policy = service_req
+        .cancellation_policy
+        .clone()
+        .unwrap_or_else(default_cancellation_policy);
+    if !escrow::CANCELLATION_POLICIES.contains(&cancellation_policy.as_str()) {
+        return HttpResponse::BadRequest().json("cancellation_policy must be flexible, moderate or strict");
+    }
+
     let provider_id = claims.sub;
     let collection = db.collection::<Service>("services");
 
@@ -85,10 +134,14 @@ pub async fn create_service(
         title: service_req.title.clone(),
         description: service_req.description.clone(),
         category: service_req.category.clone(),
-        price: service_req.price,
+        price,
         location: service_req.location.clone(),
         icon: service_req.icon.clone(),
         rating: None,
+        ratings: RatingSummary::default(),
+        weighted_rating: None,
+        deposit_rate: service_req.deposit_rate,
+        cancellation_policy,
         created_at: Utc::now(),
     };
 
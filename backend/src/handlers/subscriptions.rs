@@ -0,0 +1,503 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header::ContentDisposition;
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::FindOptions;
+use chrono::Utc;
+use futures::stream::TryStreamExt;
+use crate::models::{
+    default_tax_category, CreateSubscriptionPlanRequest, DownloadLinkQuery, Product, StoredFile, SubscribeRequest, Subscription,
+    SubscriptionPlan, SubscriptionPlanQuery, UpdatePaymentMethodRequest,
+};
+use crate::auth::verify_jwt;
+use crate::download_links::{signed_subscription_download_url, verify_subscription_download_link};
+use crate::fulfillment;
+use crate::handlers::purchases::payment_error_response;
+use crate::idempotency;
+use crate::payments::PaymentGateway;
+use crate::storage::Storage;
+use crate::subscriptions::{self, SubscriptionError, INTERVALS};
+use crate::tax::{self, TaxRules};
+
+/// Creates a recurring plan covering some or all of the caller's products.
+#[post("/subscription-plans")]
+pub async fn create_subscription_plan(
+    db: web::Data<Database>,
+    tax_rules: web::Data<TaxRules>,
+    req: HttpRequest,
+    plan_req: web::Json<CreateSubscriptionPlanRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    if !plan_req.price.is_valid_price() || plan_req.price.amount == 0 {
+        return HttpResponse::BadRequest().json("price must be a positive amount in minor units with an ISO 4217 currency code");
+    }
+
+    if !INTERVALS.contains(&plan_req.interval.as_str()) {
+        return HttpResponse::BadRequest().json("interval must be month or year");
+    }
+
+    let tax_category = plan_req.tax_category.clone().unwrap_or_else(default_tax_category);
+    if !tax_rules.is_category(&tax_category) {
+        return HttpResponse::BadRequest().json(format!(
+            "tax_category must be one of: {}",
+            tax_rules.categories.join(", ")
+        ));
+    }
+
+    let mut product_ids: Vec<String> = Vec::with_capacity(plan_req.product_ids.len());
+    let mut product_oids = Vec::with_capacity(plan_req.product_ids.len());
+    for product_id in &plan_req.product_ids {
+        let oid = match ObjectId::parse_str(product_id) {
+            Ok(oid) => oid,
+            Err(_) => return HttpResponse::BadRequest().json("Invalid product ID"),
+        };
+        if !product_ids.contains(product_id) {
+            product_ids.push(product_id.clone());
+            product_oids.push(oid);
+        }
+    }
+    if !product_oids.is_empty() {
+        let owned = match db
+            .collection::<Product>("products")
+            .count_documents(doc! { "_id": { "$in": &product_oids }, "seller_id": &claims.sub }, None)
+            .await
+        {
+            Ok(n) => n,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to create subscription plan"),
+        };
+        if owned != product_oids.len() as u64 {
+            return HttpResponse::BadRequest().json("Subscription plans can only include your own products");
+        }
+    }
+
+    let plan = SubscriptionPlan {
+        id: None,
+        seller_id: claims.sub,
+        title: plan_req.title.clone(),
+        description: plan_req.description.clone(),
+        price: plan_req.price.clone(),
+        interval: plan_req.interval.clone(),
+        product_ids,
+        tax_category,
+        active: true,
+        created_at: Utc::now(),
+    };
+
+    match db.collection::<SubscriptionPlan>("subscription_plans").insert_one(&plan, None).await {
+        Ok(result) => HttpResponse::Created().json(SubscriptionPlan {
+            id: result.inserted_id.as_object_id(),
+            ..plan
+        }),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to create subscription plan"),
+    }
+}
+
+#[get("/subscription-plans")]
+pub async fn get_subscription_plans(
+    db: web::Data<Database>,
+    query: web::Query<SubscriptionPlanQuery>,
+) -> impl Responder {
+    let mut filter = doc! { "active": true };
+    if let Some(seller_id) = &query.seller_id {
+        filter.insert("seller_id", seller_id);
+    }
+
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    match db.collection::<SubscriptionPlan>("subscription_plans").find(filter, options).await {
+        Ok(cursor) => match cursor.try_collect::<Vec<SubscriptionPlan>>().await {
+            Ok(plans) => HttpResponse::Ok().json(plans),
+            Err(_) => HttpResponse::InternalServerError().json("Failed to fetch subscription plans"),
+        },
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch subscription plans"),
+    }
+}
+
+/// Subscribes the caller and charges the first period straight away.
+#[post("/subscription-plans/{id}/subscribe")]
+pub async fn subscribe(
+    db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    tax_rules: web::Data<TaxRules>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    subscribe_req: web::Json<SubscribeRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let customer_id = claims.sub;
+
+    let idempotency_key = match idempotency::key_from_request(&req) {
+        Ok(key) => key,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    if let Some(key) = &idempotency_key {
+        if let Err(response) = idempotency::begin(&db, &customer_id, key, &*subscribe_req).await {
+            return response;
+        }
+    }
+
+    let response = start_subscription(
+        &db,
+        gateway.get_ref(),
+        &tax_rules,
+        customer_id.clone(),
+        &id,
+        subscribe_req.into_inner(),
+    )
+    .await;
+
+    match idempotency_key {
+        Some(key) => idempotency::finish(&db, &customer_id, &key, response).await,
+        None => response,
+    }
+}
+
+async fn start_subscription(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    tax_rules: &TaxRules,
+    customer_id: String,
+    plan_id: &str,
+    subscribe_req: SubscribeRequest,
+) -> HttpResponse {
+    let billing_address = match &subscribe_req.billing_address {
+        Some(address) => address,
+        None => return HttpResponse::BadRequest().json("billing_address is required to calculate tax"),
+    };
+
+    let plan_oid = match ObjectId::parse_str(plan_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid plan ID"),
+    };
+
+    let plan = match db
+        .collection::<SubscriptionPlan>("subscription_plans")
+        .find_one(doc! { "_id": plan_oid, "active": true }, None)
+        .await
+    {
+        Ok(Some(p)) => p,
+        Ok(None) => return HttpResponse::NotFound().json("Subscription plan not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch subscription plan"),
+    };
+
+    if plan.seller_id == customer_id {
+        return HttpResponse::BadRequest().json("You can't subscribe to your own plan");
+    }
+
+    let seller = match tax::seller_profiles(db, std::slice::from_ref(&plan.seller_id)).await {
+        Ok(mut profiles) => profiles.remove(&plan.seller_id).unwrap_or_default(),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to calculate tax"),
+    };
+
+    // Every renewal charges the tax worked out now, like the plan's price
+    let tax = match tax_rules.calculate(&plan.price, &plan.tax_category, billing_address, &seller) {
+        Ok(tax) => tax,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+
+    let now = Utc::now();
+    let period_end = match subscriptions::period_end(now, &plan.interval) {
+        Some(end) => end,
+        None => return HttpResponse::BadRequest().json("Invalid plan interval"),
+    };
+
+    let subscription = Subscription {
+        id: None,
+        plan_id: plan_id.to_string(),
+        seller_id: plan.seller_id.clone(),
+        customer_id: customer_id.clone(),
+        payment_method: subscribe_req.payment_method.clone(),
+        amount: tax.total,
+        tax_lines: tax.lines,
+        interval: plan.interval.clone(),
+        status: "pending".to_string(),
+        current_period_start: now,
+        current_period_end: period_end,
+        access_until: now,
+        cancel_at_period_end: false,
+        failed_attempts: 0,
+        failure_reason: None,
+        live_key: Some(format!("{}:{}", customer_id, plan_id)),
+        created_at: now,
+    };
+
+    match subscriptions::start(db, gateway, subscription).await {
+        Ok(subscription) => HttpResponse::Created().json(serde_json::json!({
+            "success": true,
+            "message": "Subscription started",
+            "subscription": subscription
+        })),
+        Err(e) => subscription_error_response(&e),
+    }
+}
+
+#[get("/subscriptions")]
+pub async fn get_subscriptions(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    match db
+        .collection::<Subscription>("subscriptions")
+        .find(doc! { "customer_id": &claims.sub }, options)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect::<Vec<Subscription>>().await {
+            Ok(subscriptions) => HttpResponse::Ok().json(subscriptions),
+            Err(_) => HttpResponse::InternalServerError().json("Failed to fetch subscriptions"),
+        },
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch subscriptions"),
+    }
+}
+
+/// Stops renewals; access lasts until the end of the period already paid for.
+#[post("/subscriptions/{id}/cancel")]
+pub async fn cancel_subscription(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let subscription_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid subscription ID"),
+    };
+
+    match subscriptions::cancel(&db, subscription_oid, &claims.sub).await {
+        Ok(subscription) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("Subscription cancelled; access ends {}", subscription.access_until.format("%Y-%m-%d")),
+            "subscription": subscription
+        })),
+        Err(e) => subscription_error_response(&e),
+    }
+}
+
+/// Replaces the payment method used for renewals; a past-due renewal is retried with it.
+#[post("/subscriptions/{id}/payment-method")]
+pub async fn update_payment_method(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    method_req: web::Json<UpdatePaymentMethodRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let subscription_oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid subscription ID"),
+    };
+
+    if method_req.payment_method.trim().is_empty() {
+        return HttpResponse::BadRequest().json("payment_method is required");
+    }
+
+    match subscriptions::update_payment_method(&db, subscription_oid, &claims.sub, &method_req.payment_method).await {
+        Ok(subscription) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Payment method updated",
+            "subscription": subscription
+        })),
+        Err(e) => subscription_error_response(&e),
+    }
+}
+
+#[get("/subscriptions/{id}/products/{product_id}/download")]
+pub async fn download_subscription_product(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let (subscription_id, product_id) = path.into_inner();
+    let (subscription, _) = match covered_product(&db, &subscription_id, &product_id, Some(&claims.sub)).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "download_url": signed_subscription_download_url(&subscription_id, &product_id),
+        "access_until": subscription.access_until
+    }))
+}
+
+/// Serves the latest version of a covered product through a signed link.
+#[get("/downloads/subscriptions/{subscription_id}/{product_id}")]
+pub async fn serve_subscription_download(
+    db: web::Data<Database>,
+    storage: web::Data<dyn Storage>,
+    path: web::Path<(String, String)>,
+    query: web::Query<DownloadLinkQuery>,
+) -> impl Responder {
+    let (subscription_id, product_id) = path.into_inner();
+    if !verify_subscription_download_link(&subscription_id, &product_id, query.expires, &query.signature) {
+        return HttpResponse::Forbidden().json("Download link is invalid or has expired");
+    }
+
+    // Re-checked in case the subscription lapsed after the link was issued
+    let (_, product) = match covered_product(&db, &subscription_id, &product_id, None).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let file_id = if product.current_version > 0 {
+        match fulfillment::current_version(&db, &product).await {
+            Ok(Some(version)) => Some(version.file_id),
+            Ok(None) => return HttpResponse::NotFound().json("Product file not found"),
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch product version"),
+        }
+    } else {
+        product.file_id.clone()
+    };
+
+    let file_oid = match file_id.as_deref().map(ObjectId::parse_str) {
+        Some(Ok(oid)) => oid,
+        Some(Err(_)) => return HttpResponse::NotFound().json("Product file not found"),
+        None => {
+            return match product.file_url {
+                Some(url) => HttpResponse::Found().insert_header(("Location", url)).finish(),
+                None => HttpResponse::NotFound().json("Product file not found"),
+            };
+        }
+    };
+
+    let file = match db.collection::<StoredFile>("files").find_one(doc! { "_id": file_oid }, None).await {
+        Ok(Some(f)) => f,
+        Ok(None) => return HttpResponse::NotFound().json("Product file not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch product file"),
+    };
+
+    match storage.get(&file.storage_key).await {
+        Ok(data) => HttpResponse::Ok()
+            .content_type(file.content_type)
+            .insert_header(ContentDisposition::attachment(file.original_name))
+            .body(data),
+        Err(e) => {
+            log::error!("Failed to read {}: {}", file.storage_key, e);
+            HttpResponse::InternalServerError().json("Failed to read product file")
+        }
+    }
+}
+
+// The subscription and product, if the subscription currently grants access to it.
+async fn covered_product(
+    db: &Database,
+    subscription_id: &str,
+    product_id: &str,
+    customer_id: Option<&str>,
+) -> Result<(Subscription, Product), HttpResponse> {
+    let subscription_oid = ObjectId::parse_str(subscription_id)
+        .map_err(|_| HttpResponse::BadRequest().json("Invalid subscription ID"))?;
+    let product_oid = ObjectId::parse_str(product_id)
+        .map_err(|_| HttpResponse::BadRequest().json("Invalid product ID"))?;
+
+    let mut filter = doc! { "_id": subscription_oid };
+    if let Some(customer_id) = customer_id {
+        filter.insert("customer_id", customer_id);
+    }
+
+    let subscription = match db.collection::<Subscription>("subscriptions").find_one(filter, None).await {
+        Ok(Some(s)) => s,
+        Ok(None) => return Err(HttpResponse::NotFound().json("Subscription not found")),
+        Err(_) => return Err(HttpResponse::InternalServerError().json("Failed to fetch subscription")),
+    };
+
+    if !subscription.grants_access(Utc::now()) {
+        return Err(HttpResponse::Forbidden().json("Subscription is not active"));
+    }
+
+    let plan = match ObjectId::parse_str(&subscription.plan_id) {
+        Ok(oid) => db.collection::<SubscriptionPlan>("subscription_plans").find_one(doc! { "_id": oid }, None).await,
+        Err(_) => Ok(None),
+    };
+    let plan = match plan {
+        Ok(Some(p)) => p,
+        Ok(None) => return Err(HttpResponse::NotFound().json("Subscription plan not found")),
+        Err(_) => return Err(HttpResponse::InternalServerError().json("Failed to fetch subscription plan")),
+    };
+
+    let product = match db.collection::<Product>("products").find_one(doc! { "_id": product_oid }, None).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return Err(HttpResponse::NotFound().json("Product not found")),
+        Err(_) => return Err(HttpResponse::InternalServerError().json("Failed to fetch product")),
+    };
+
+    if !subscriptions::covers(&plan, &product) {
+        return Err(HttpResponse::Forbidden().json("This product is not included in your subscription"));
+    }
+
+    Ok((subscription, product))
+}
+
+pub fn subscription_error_response(error: &SubscriptionError) -> HttpResponse {
+    match error {
+        SubscriptionError::NotFound => HttpResponse::NotFound().json("Subscription not found"),
+        SubscriptionError::Invalid(msg) => HttpResponse::BadRequest().json(msg),
+        SubscriptionError::Conflict(msg) => HttpResponse::Conflict().json(msg),
+        SubscriptionError::Payment(e) => payment_error_response(e, serde_json::json!({
+            "success": false,
+            "message": e.to_string()
+        })),
+        SubscriptionError::Database(_) => HttpResponse::InternalServerError().json("Failed to update subscription"),
+    }
+}
@@ -0,0 +1,97 @@
+use actix_web::{get, put, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use crate::models::{TaxProfile, TaxRegistration, UpdateTaxProfileRequest, User};
+use crate::auth::verify_jwt;
+use crate::tax::TaxRules;
+
+#[get("/seller/tax-profile")]
+pub async fn get_tax_profile(
+    db: web::Data<Database>,
+    req: HttpRequest,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let user_oid = match ObjectId::parse_str(&claims.sub) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    match db.collection::<User>("users").find_one(doc! { "_id": user_oid }, None).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(user.tax_profile),
+        Ok(None) => HttpResponse::NotFound().json("User not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to fetch tax profile"),
+    }
+}
+
+/// Replaces where the seller is established and registered to collect tax.
+#[put("/seller/tax-profile")]
+pub async fn update_tax_profile(
+    db: web::Data<Database>,
+    rules: web::Data<TaxRules>,
+    req: HttpRequest,
+    profile_req: web::Json<UpdateTaxProfileRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let user_oid = match ObjectId::parse_str(&claims.sub) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let country = profile_req.country.as_ref().map(|c| c.trim().to_uppercase());
+    if matches!(&country, Some(c) if c.len() != 2 || !c.chars().all(|ch| ch.is_ascii_uppercase())) {
+        return HttpResponse::BadRequest().json("country must be an ISO 3166-1 alpha-2 code");
+    }
+
+    let mut registrations: Vec<TaxRegistration> = Vec::new();
+    for registration in &profile_req.registrations {
+        let jurisdiction = registration.jurisdiction.trim().to_uppercase();
+        if !rules.jurisdictions.iter().any(|j| j.code() == jurisdiction) {
+            return HttpResponse::BadRequest().json(format!("Unknown tax jurisdiction {}", jurisdiction));
+        }
+        if registration.tax_id.trim().is_empty() {
+            return HttpResponse::BadRequest().json(format!("Missing tax_id for {}", jurisdiction));
+        }
+        if registrations.iter().any(|r| r.jurisdiction == jurisdiction) {
+            return HttpResponse::BadRequest().json(format!("Duplicate registration for {}", jurisdiction));
+        }
+        registrations.push(TaxRegistration {
+            jurisdiction,
+            tax_id: registration.tax_id.trim().to_string(),
+        });
+    }
+
+    let profile = TaxProfile { country, registrations };
+    let profile_doc = match bson::to_bson(&profile) {
+        Ok(b) => b,
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to update tax profile"),
+    };
+
+    match db
+        .collection::<User>("users")
+        .update_one(doc! { "_id": user_oid }, doc! { "$set": { "tax_profile": profile_doc } }, None)
+        .await
+    {
+        Ok(result) if result.matched_count == 1 => HttpResponse::Ok().json(profile),
+        Ok(_) => HttpResponse::NotFound().json("User not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to update tax profile"),
+    }
+}
@@ -0,0 +1,255 @@
+use actix_multipart::Multipart;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web::web::{Bytes, BytesMut};
+use futures::StreamExt;
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::env;
+use crate::auth::verify_jwt;
+use crate::models::StoredFile;
+use crate::storage::Storage;
+
+pub const PURPOSE_PRODUCT_FILE: &str = "product_file";
+pub const PURPOSE_PRODUCT_IMAGE: &str = "product_image";
+
+const IMAGE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+// Office documents and e-books are zip containers, so they are trusted by their declared type
+const ZIP_TYPES: &[&str] = &[
+    "application/zip",
+    "application/epub+zip",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+];
+
+#[post("/uploads/product-file")]
+pub async fn upload_product_file(
+    db: web::Data<Database>,
+    storage: web::Data<dyn Storage>,
+    req: HttpRequest,
+    payload: Multipart,
+) -> impl Responder {
+    upload(&db, &storage, &req, payload, PURPOSE_PRODUCT_FILE).await
+}
+
+#[post("/uploads/product-image")]
+pub async fn upload_product_image(
+    db: web::Data<Database>,
+    storage: web::Data<dyn Storage>,
+    req: HttpRequest,
+    payload: Multipart,
+) -> impl Responder {
+    upload(&db, &storage, &req, payload, PURPOSE_PRODUCT_IMAGE).await
+}
+
+/// Serves product images. Product files are only available through signed download links.
+#[get("/files/{id}")]
+pub async fn get_file(
+    db: web::Data<Database>,
+    storage: web::Data<dyn Storage>,
+    id: web::Path<String>,
+) -> impl Responder {
+    let oid = match ObjectId::parse_str(id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json("Invalid file ID"),
+    };
+
+    let file = match db
+        .collection::<StoredFile>("files")
+        .find_one(doc! { "_id": oid, "purpose": PURPOSE_PRODUCT_IMAGE }, None)
+        .await
+    {
+        Ok(Some(f)) => f,
+        Ok(None) => return HttpResponse::NotFound().json("File not found"),
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch file"),
+    };
+
+    match storage.get(&file.storage_key).await {
+        // Keys are content hashes, so the bytes behind an ID never change
+        Ok(data) => HttpResponse::Ok()
+            .content_type(file.content_type)
+            .insert_header(("Cache-Control", "public, max-age=31536000, immutable"))
+            .insert_header(("ETag", format!("\"{}\"", file.sha256)))
+            .body(data),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to read file"),
+    }
+}
+
+async fn upload(
+    db: &Database,
+    storage: &web::Data<dyn Storage>,
+    req: &HttpRequest,
+    mut payload: Multipart,
+    purpose: &str,
+) -> HttpResponse {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    let max_bytes = max_upload_bytes(purpose);
+
+    let mut upload: Option<(String, String, Bytes)> = None;
+    while let Some(field) = payload.next().await {
+        let mut field = match field {
+            Ok(f) => f,
+            Err(_) => return HttpResponse::BadRequest().json("Malformed multipart body"),
+        };
+
+        if field.name() != Some("file") {
+            continue;
+        }
+
+        let original_name = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            .unwrap_or("upload")
+            .to_string();
+        let declared_type = field
+            .content_type()
+            .map(|m| m.essence_str().to_string())
+            .unwrap_or_default();
+
+        let mut data = BytesMut::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(_) => return HttpResponse::BadRequest().json("Malformed multipart body"),
+            };
+            if data.len() + chunk.len() > max_bytes {
+                return HttpResponse::PayloadTooLarge().json(format!(
+                    "File exceeds the {} MB limit",
+                    max_bytes / (1024 * 1024)
+                ));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        upload = Some((original_name, declared_type, data.freeze()));
+        break;
+    }
+
+    let (original_name, declared_type, data) = match upload {
+        Some(u) => u,
+        None => return HttpResponse::BadRequest().json("Missing 'file' field"),
+    };
+
+    if data.is_empty() {
+        return HttpResponse::BadRequest().json("File is empty");
+    }
+
+    let content_type = match detect_content_type(&data, &declared_type, purpose) {
+        Some(t) => t,
+        None => return HttpResponse::UnsupportedMediaType().json("File type is not allowed"),
+    };
+
+    let sha256 = hex::encode(Sha256::digest(&data));
+    let size = data.len() as i64;
+    // Content-addressed keys: identical uploads share one stored object
+    let storage_key = format!("{}/{}", purpose, sha256);
+
+    if let Err(e) = storage.put(&storage_key, &content_type, data).await {
+        log::error!("Failed to store upload {}: {}", storage_key, e);
+        return HttpResponse::InternalServerError().json("Failed to store file");
+    }
+
+    let file = StoredFile {
+        id: None,
+        owner_id: claims.sub,
+        purpose: purpose.to_string(),
+        original_name: sanitize_filename(&original_name),
+        content_type: content_type.clone(),
+        size,
+        sha256: sha256.clone(),
+        storage_key,
+        created_at: Utc::now(),
+    };
+
+    let files = db.collection::<StoredFile>("files");
+    match files.insert_one(file, None).await {
+        Ok(result) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "file_id": result.inserted_id.as_object_id().map(|oid| oid.to_hex()),
+            "content_type": content_type,
+            "size": size,
+            "sha256": sha256
+        })),
+        Err(_) => {
+            // Don't leave an orphaned object behind, unless an earlier upload shares it
+            let storage_key = format!("{}/{}", purpose, sha256);
+            if let Ok(0) = files.count_documents(doc! { "storage_key": &storage_key }, None).await {
+                let _ = storage.delete(&storage_key).await;
+            }
+            HttpResponse::InternalServerError().json("Failed to save file")
+        }
+    }
+}
+
+fn max_upload_bytes(purpose: &str) -> usize {
+    let (var, default_mb) = if purpose == PURPOSE_PRODUCT_IMAGE {
+        ("UPLOAD_MAX_IMAGE_MB", 5)
+    } else {
+        ("UPLOAD_MAX_FILE_MB", 50)
+    };
+
+    env::var(var)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(default_mb)
+        * 1024
+        * 1024
+}
+
+/// Works out the stored content type from the file's magic bytes rather than
+/// trusting the client, returning `None` if it isn't allowed for `purpose`.
+fn detect_content_type(data: &[u8], declared_type: &str, purpose: &str) -> Option<String> {
+    let sniffed = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        "image/jpeg"
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        "image/gif"
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        "image/webp"
+    } else if data.starts_with(b"%PDF-") {
+        "application/pdf"
+    } else if data.starts_with(b"PK\x03\x04") {
+        if ZIP_TYPES.contains(&declared_type) {
+            return (purpose == PURPOSE_PRODUCT_FILE).then(|| declared_type.to_string());
+        }
+        "application/zip"
+    } else {
+        return None;
+    };
+
+    // Images are fine for either purpose; documents and archives only as product files
+    let allowed = IMAGE_TYPES.contains(&sniffed) || purpose == PURPOSE_PRODUCT_FILE;
+
+    allowed.then(|| sniffed.to_string())
+}
+
+fn sanitize_filename(name: &str) -> String {
+    // Browsers may send a full client-side path
+    let base = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        // Header values must stay ASCII when the name is echoed back on download
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .take(255)
+        .collect();
+
+    if cleaned.trim().is_empty() {
+        "upload".to_string()
+    } else {
+        cleaned
+    }
+}
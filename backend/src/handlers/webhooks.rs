@@ -0,0 +1,349 @@
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{Database, bson::doc};
+use chrono::Utc;
+use futures::stream::TryStreamExt;
+use crate::models::{Booking, Order, PaymentEvent, Purchase, ReplayPaymentEventsRequest};
+use crate::auth::{is_admin, verify_jwt};
+use crate::coupons;
+use crate::db::is_duplicate_key;
+use crate::fulfillment;
+use crate::payments::{PaymentError, PaymentGateway};
+use crate::refunds::{self, RefundError, KIND_BOOKING, KIND_PURCHASE};
+
+/// How an event moves purchases and bookings paid with the event's payment intent.
+struct Transition {
+    purchase_from: &'static [&'static str],
+    purchase_to: &'static str,
+    booking_from: &'static [&'static str],
+    booking_to: &'static str,
+}
+
+#[post("/webhooks/payments")]
+pub async fn payment_webhook(
+    db: web::Data<Database>,
+    gateway: web::Data<dyn PaymentGateway>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    let signature = match req.headers().get("Stripe-Signature").and_then(|h| h.to_str().ok()) {
+        Some(s) => s,
+        None => return HttpResponse::BadRequest().json("Missing signature"),
+    };
+
+    let event = match gateway.verify_webhook(&body, signature) {
+        Ok(e) => e,
+        Err(PaymentError::InvalidSignature) => return HttpResponse::BadRequest().json("Invalid signature"),
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+
+    let payload = match mongodb::bson::to_document(&event.payload) {
+        Ok(d) => d,
+        Err(_) => return HttpResponse::BadRequest().json("Malformed event payload"),
+    };
+
+    let collection = db.collection::<PaymentEvent>("payment_events");
+    let record = PaymentEvent {
+        id: event.id.clone(),
+        gateway: gateway.name().to_string(),
+        event_type: event.event_type,
+        payment_intent_id: event.payment_intent_id,
+        payload,
+        status: "received".to_string(),
+        error: None,
+        received_at: Utc::now(),
+        processed_at: None,
+    };
+
+    let record = match collection.insert_one(&record, None).await {
+        Ok(_) => record,
+        Err(e) if is_duplicate_key(&e) => {
+            // Redelivery: only reprocess if the first attempt didn't go through
+            match collection.find_one(doc! { "_id": &event.id }, None).await {
+                Ok(Some(existing)) if existing.status == "processed" => {
+                    return HttpResponse::Ok().json(serde_json::json!({
+                        "received": true,
+                        "duplicate": true
+                    }));
+                }
+                Ok(Some(existing)) => existing,
+                _ => return HttpResponse::InternalServerError().json("Failed to store event"),
+            }
+        }
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to store event"),
+    };
+
+    match process_event(&db, &record).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "received": true })),
+        // A non-2xx response makes the gateway retry delivery later
+        Err(_) => HttpResponse::InternalServerError().json("Failed to process event"),
+    }
+}
+
+#[post("/admin/payment-events/replay")]
+pub async fn replay_payment_events(
+    db: web::Data<Database>,
+    req: HttpRequest,
+    replay_req: web::Json<ReplayPaymentEventsRequest>,
+) -> impl Responder {
+    let auth_header = match req.headers().get("Authorization") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => return HttpResponse::Unauthorized().json("Missing authorization"),
+    };
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
+    let claims = match verify_jwt(token) {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
+    };
+
+    if !is_admin(&db, &claims.sub).await {
+        return HttpResponse::Forbidden().json("Admin access required");
+    }
+
+    let filter = match &replay_req.event_ids {
+        Some(ids) => doc! { "_id": { "$in": ids } },
+        None => doc! { "status": { "$ne": "processed" } },
+    };
+
+    let mut options = mongodb::options::FindOptions::default();
+    options.sort = Some(doc! { "received_at": 1 });
+
+    let events = match db.collection::<PaymentEvent>("payment_events").find(filter, options).await {
+        Ok(cursor) => match cursor.try_collect::<Vec<PaymentEvent>>().await {
+            Ok(events) => events,
+            Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch payment events"),
+        },
+        Err(_) => return HttpResponse::InternalServerError().json("Failed to fetch payment events"),
+    };
+
+    let mut processed = 0;
+    let mut failed = Vec::new();
+    for event in &events {
+        match process_event(&db, event).await {
+            Ok(()) => processed += 1,
+            Err(_) => failed.push(event.id.clone()),
+        }
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": failed.is_empty(),
+        "replayed": processed,
+        "failed": failed
+    }))
+}
+
+/// Applies a stored event and records the outcome on it. Transitions only fire
+/// from the expected previous status, so replaying an event is harmless.
+async fn process_event(db: &Database, event: &PaymentEvent) -> Result<(), mongodb::error::Error> {
+    let result = apply_event(db, event).await;
+
+    let update = match &result {
+        Ok(()) => doc! { "$set": { "status": "processed", "error": null, "processed_at": Utc::now() } },
+        Err(e) => doc! { "$set": { "status": "failed", "error": e.to_string() } },
+    };
+
+    db.collection::<PaymentEvent>("payment_events")
+        .update_one(doc! { "_id": &event.id }, update, None)
+        .await?;
+
+    result
+}
+
+async fn apply_event(db: &Database, event: &PaymentEvent) -> Result<(), mongodb::error::Error> {
+    let intent_id = match &event.payment_intent_id {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+
+    if event.event_type == "charge.refunded" {
+        // Partial refunds made at the gateway aren't reported per refund, so only full ones are applied
+        if charge_fully_refunded(event) {
+            return apply_refund(db, event, intent_id).await;
+        }
+        return Ok(());
+    }
+
+    let transition = match transition_for(event) {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    // Cart checkouts: an order moves with its purchases
+    let orders = db.collection::<Order>("orders");
+    let order_filter = doc! { "payment_intent_id": intent_id, "status": { "$in": transition.purchase_from } };
+    if transition.purchase_to == "completed" {
+        let matching: Vec<Order> = orders.find(order_filter, None).await?.try_collect().await?;
+        for order in matching {
+            if let Some(order_oid) = order.id {
+                fulfillment::complete_order(db, order_oid, &[order.status.as_str()]).await?;
+            }
+        }
+    } else {
+        let mut set = doc! { "status": transition.purchase_to };
+        if transition.purchase_to == "failed" {
+            set.insert("failure_reason", failure_reason(event));
+        }
+        orders.update_many(order_filter, doc! { "$set": set }, None).await?;
+    }
+
+    let purchases = db.collection::<Purchase>("purchases");
+    let matching: Vec<Purchase> = purchases
+        .find(
+            doc! { "payment_intent_id": intent_id, "status": { "$in": transition.purchase_from } },
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+
+    for purchase in matching {
+        let purchase_oid = match purchase.id {
+            Some(oid) => oid,
+            None => continue,
+        };
+
+        if transition.purchase_to == "completed" {
+            fulfillment::complete_purchase(db, purchase_oid, &[purchase.status.as_str()]).await?;
+            continue;
+        }
+
+        let mut set = doc! { "status": transition.purchase_to };
+        if transition.purchase_to == "failed" {
+            set.insert("failure_reason", failure_reason(event));
+        }
+
+        let mut update = doc! { "$set": set };
+        // Failed purchases no longer count as owning the product
+        if transition.purchase_to == "failed" {
+            update.insert("$unset", doc! { "ownership_key": "" });
+        }
+
+        let updated = purchases
+            .update_one(doc! { "_id": purchase_oid, "status": &purchase.status }, update, None)
+            .await?;
+        if updated.modified_count == 1 && transition.purchase_to == "failed" {
+            coupons::release_purchase(db, &purchase).await;
+        }
+    }
+
+    let bookings = db.collection::<Booking>("bookings");
+    let booking_filter = doc! { "payment_intent_id": intent_id, "payment_status": { "$in": transition.booking_from } };
+    if transition.booking_to == "paid" {
+        let paid: Vec<Booking> = bookings.find(booking_filter, None).await?.try_collect().await?;
+        for booking in paid {
+            if let (Some(booking_oid), Some(status)) = (booking.id, booking.payment_status.as_deref()) {
+                fulfillment::complete_booking_payment(db, booking_oid, &[status]).await?;
+            }
+        }
+    } else {
+        bookings
+            .update_many(booking_filter, doc! { "$set": { "payment_status": transition.booking_to } }, None)
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn transition_for(event: &PaymentEvent) -> Option<Transition> {
+    match event.event_type.as_str() {
+        "payment_intent.amount_capturable_updated" => Some(Transition {
+            purchase_from: &["pending"],
+            purchase_to: "authorized",
+            booking_from: &["pending"],
+            booking_to: "authorized",
+        }),
+        "payment_intent.succeeded" => Some(Transition {
+            purchase_from: &["pending", "authorized"],
+            purchase_to: "completed",
+            booking_from: &["pending", "authorized"],
+            booking_to: "paid",
+        }),
+        "payment_intent.payment_failed" | "payment_intent.canceled" => Some(Transition {
+            purchase_from: &["pending", "authorized"],
+            purchase_to: "failed",
+            booking_from: &["pending", "authorized"],
+            booking_to: "failed",
+        }),
+        _ => None,
+    }
+}
+
+/// Books a refund made at the gateway like one made through the API: each paid
+/// purchase and booking on the intent gets a `Refund` for what was still unrefunded,
+/// with its ledger entries and escrow reversal.
+async fn apply_refund(db: &Database, event: &PaymentEvent, intent_id: &str) -> Result<(), mongodb::error::Error> {
+    let purchases: Vec<Purchase> = db
+        .collection::<Purchase>("purchases")
+        .find(doc! { "payment_intent_id": intent_id, "status": "completed" }, None)
+        .await?
+        .try_collect()
+        .await?;
+    let bookings: Vec<Booking> = db
+        .collection::<Booking>("bookings")
+        .find(doc! { "payment_intent_id": intent_id, "payment_status": "paid" }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    let subjects = purchases
+        .iter()
+        .filter_map(|p| p.id.map(|oid| (KIND_PURCHASE, oid)))
+        .chain(bookings.iter().filter_map(|b| b.id.map(|oid| (KIND_BOOKING, oid))));
+    let gateway_refund_id = refund_id(event);
+
+    for (kind, oid) in subjects {
+        let result = match refunds::load_charge(db, kind, &oid.to_hex()).await {
+            Ok(charge) => refunds::record_gateway_refund(db, &charge, gateway_refund_id.clone()).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {}
+            Err(RefundError::Database(e)) => return Err(e),
+            Err(e) => return Err(mongodb::error::Error::custom(format!("{} {}: {}", kind, oid, e))),
+        }
+    }
+
+    db.collection::<Order>("orders")
+        .update_many(
+            doc! { "payment_intent_id": intent_id, "status": "completed" },
+            doc! { "$set": { "status": "refunded" } },
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+// Most recent refund on the charge; the gateway lists them newest first
+fn refund_id(event: &PaymentEvent) -> Option<String> {
+    event
+        .payload
+        .get_document("data")
+        .and_then(|data| data.get_document("object"))
+        .and_then(|object| object.get_document("refunds"))
+        .and_then(|refunds| refunds.get_array("data"))
+        .ok()
+        .and_then(|list| list.first())
+        .and_then(|refund| refund.as_document())
+        .and_then(|refund| refund.get_str("id").ok())
+        .map(|id| id.to_string())
+}
+
+fn charge_fully_refunded(event: &PaymentEvent) -> bool {
+    event
+        .payload
+        .get_document("data")
+        .and_then(|data| data.get_document("object"))
+        .and_then(|object| object.get_bool("refunded"))
+        .unwrap_or(false)
+}
+
+fn failure_reason(event: &PaymentEvent) -> String {
+    event
+        .payload
+        .get_document("data")
+        .and_then(|data| data.get_document("object"))
+        .and_then(|object| object.get_document("last_payment_error"))
+        .and_then(|error| error.get_str("message"))
+        .map(|message| message.to_string())
+        .unwrap_or_else(|_| event.event_type.clone())
+}
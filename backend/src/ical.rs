@@ -0,0 +1,91 @@
+use chrono::{NaiveDateTime, Utc};
+
+const PRODID: &str = "-//MarketHub//Bookings//EN";
+
+pub struct CalendarEvent {
+    pub uid: String,
+    pub sequence: i32,
+    pub start: NaiveDateTime,
+    pub duration_minutes: i64,
+    pub summary: String,
+    pub location: Option<String>,
+    pub description: Option<String>,
+    pub status: &'static str,
+}
+
+/// Maps a booking status onto the RFC 5545 VEVENT `STATUS` values.
+pub fn event_status(booking_status: &str) -> &'static str {
+    match booking_status {
+        "cancelled" | "expired" => "CANCELLED",
+        "pending" => "TENTATIVE",
+        _ => "CONFIRMED",
+    }
+}
+
+/// Renders a VCALENDAR object containing the given events.
+///
+/// Booking times carry no timezone, so DTSTART is emitted as floating local time.
+pub fn render_calendar(name: Option<&str>, events: &[CalendarEvent]) -> String {
+    let dtstamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:{}", PRODID),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+    ];
+
+    if let Some(name) = name {
+        lines.push(format!("X-WR-CALNAME:{}", escape_text(name)));
+    }
+
+    for event in events {
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}", event.uid));
+        lines.push(format!("SEQUENCE:{}", event.sequence));
+        lines.push(format!("DTSTAMP:{}", dtstamp));
+        lines.push(format!("DTSTART:{}", event.start.format("%Y%m%dT%H%M%S")));
+        lines.push(format!("DURATION:PT{}M", event.duration_minutes));
+        lines.push(format!("SUMMARY:{}", escape_text(&event.summary)));
+        if let Some(location) = &event.location {
+            lines.push(format!("LOCATION:{}", escape_text(location)));
+        }
+        if let Some(description) = &event.description {
+            lines.push(format!("DESCRIPTION:{}", escape_text(description)));
+        }
+        lines.push(format!("STATUS:{}", event.status));
+        lines.push("END:VEVENT".to_string());
+    }
+
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|l| fold_line(l)).collect::<Vec<_>>().concat()
+}
+
+fn escape_text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+// Content lines longer than 75 octets are folded with CRLF + a single space.
+fn fold_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut width = 0;
+
+    for ch in line.chars() {
+        let len = ch.len_utf8();
+        if width + len > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(ch);
+        width += len;
+    }
+
+    folded.push_str("\r\n");
+    folded
+}
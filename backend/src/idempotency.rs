@@ -0,0 +1,137 @@
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse};
+use actix_web::body::to_bytes;
+use mongodb::{Database, bson::doc};
+use chrono::{Duration, Utc};
+use std::env;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::db::is_duplicate_key;
+use crate::models::IdempotencyRecord;
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// How long a stored response is kept for retries, from `IDEMPOTENCY_KEY_TTL_HOURS`.
+pub fn ttl() -> Duration {
+    let hours = env::var("IDEMPOTENCY_KEY_TTL_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(24);
+    Duration::hours(hours)
+}
+
+/// Reads the optional `Idempotency-Key` header, scoped to the request's method
+/// and path so the same key sent to two endpoints never replays the wrong response.
+pub fn key_from_request(req: &HttpRequest) -> Result<Option<String>, &'static str> {
+    let value = match req.headers().get("Idempotency-Key") {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => {
+            Ok(Some(format!("{} {} {}", req.method(), req.path(), key)))
+        }
+        _ => Err("Invalid Idempotency-Key header"),
+    }
+}
+
+/// Reserves `key` for this request. `Err` carries the response to send instead:
+/// the stored response for a retry, 422 if the key was used with another body,
+/// or 409 while the first request is still in flight.
+pub async fn begin<T: Serialize>(db: &Database, user_id: &str, key: &str, request: &T) -> Result<(), HttpResponse> {
+    let collection = db.collection::<IdempotencyRecord>("idempotency_keys");
+    let request_hash = hash_request(request);
+    let now = Utc::now();
+
+    let record = IdempotencyRecord {
+        id: None,
+        user_id: user_id.to_string(),
+        key: key.to_string(),
+        request_hash: request_hash.clone(),
+        status: "processing".to_string(),
+        response_status: None,
+        response_body: None,
+        created_at: now,
+        expires_at: now + ttl(),
+    };
+
+    match collection.insert_one(record, None).await {
+        Ok(_) => return Ok(()),
+        Err(e) if is_duplicate_key(&e) => {}
+        Err(_) => return Err(HttpResponse::InternalServerError().json("Failed to check idempotency key")),
+    }
+
+    let existing = match collection.find_one(doc! { "user_id": user_id, "key": key }, None).await {
+        Ok(Some(r)) => r,
+        // The record expired between the insert and the lookup
+        Ok(None) => return Err(HttpResponse::Conflict().json("Idempotency key is being reset, retry the request")),
+        Err(_) => return Err(HttpResponse::InternalServerError().json("Failed to check idempotency key")),
+    };
+
+    if existing.request_hash != request_hash {
+        return Err(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+            "success": false,
+            "message": "Idempotency-Key was already used with a different request body"
+        })));
+    }
+
+    match (existing.response_status, existing.response_body) {
+        (Some(status), Some(body)) => {
+            let status = StatusCode::from_u16(status as u16).unwrap_or(StatusCode::OK);
+            Err(HttpResponse::build(status)
+                .content_type("application/json")
+                .insert_header(("Idempotent-Replayed", "true"))
+                .body(body))
+        }
+        _ => Err(HttpResponse::Conflict().json("A request with this Idempotency-Key is still being processed")),
+    }
+}
+
+// Marks a response sent after the customer was charged
+struct Charged;
+
+/// Marks a server error sent after the payment was taken, so `finish` stores it
+/// and a retry replays it instead of charging again.
+pub fn charged(mut response: HttpResponse) -> HttpResponse {
+    response.extensions_mut().insert(Charged);
+    response
+}
+
+/// Stores the response for `key` and hands it back to be sent.
+///
+/// Server errors release the key instead, so the client can retry the request,
+/// unless they were marked as `charged`.
+pub async fn finish(db: &Database, user_id: &str, key: &str, response: HttpResponse) -> HttpResponse {
+    let collection = db.collection::<IdempotencyRecord>("idempotency_keys");
+    let filter = doc! { "user_id": user_id, "key": key };
+
+    if response.status().is_server_error() && !response.extensions().contains::<Charged>() {
+        let _ = collection.delete_one(filter, None).await;
+        return response;
+    }
+
+    let (response, body) = response.into_parts();
+    let body = match to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            let _ = collection.delete_one(filter, None).await;
+            return HttpResponse::InternalServerError().json("Failed to read response");
+        }
+    };
+
+    let update = doc! {
+        "$set": {
+            "status": "completed",
+            "response_status": response.status().as_u16() as i32,
+            "response_body": String::from_utf8_lossy(&body).to_string(),
+        }
+    };
+    let _ = collection.update_one(filter, update, None).await;
+
+    response.set_body(body).map_into_boxed_body()
+}
+
+fn hash_request<T: Serialize>(request: &T) -> String {
+    let bytes = serde_json::to_vec(request).unwrap_or_default();
+    hex::encode(Sha256::digest(bytes))
+}
@@ -0,0 +1,348 @@
+use mongodb::{ClientSession, Database};
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use chrono::Utc;
+use futures::stream::TryStreamExt;
+use std::collections::{BTreeMap, HashMap};
+use crate::models::{Booking, Invoice, InvoiceLine, Order, Product, Purchase, Service, User};
+use crate::money::{Money, MoneyError};
+use crate::pdf::{self, Page, PAGE_HEIGHT, PAGE_WIDTH};
+
+/// Issues one invoice per seller for purchases that completed together.
+///
+/// Runs inside the caller's transaction, so an invoice number is only used up
+/// when the sale commits; that is what keeps each seller's numbering gap-free.
+pub async fn issue_for_purchases(
+    db: &Database,
+    session: &mut ClientSession,
+    purchases: &[Purchase],
+    order: Option<&Order>,
+) -> Result<(), mongodb::error::Error> {
+    let customer_id = match purchases.first() {
+        Some(p) => p.customer_id.clone(),
+        None => return Ok(()),
+    };
+
+    let product_oids: Vec<ObjectId> = purchases
+        .iter()
+        .filter_map(|p| ObjectId::parse_str(&p.product_id).ok())
+        .collect();
+    let products: HashMap<String, Product> = db
+        .collection::<Product>("products")
+        .find(doc! { "_id": { "$in": product_oids } }, None)
+        .await?
+        .try_collect::<Vec<Product>>()
+        .await?
+        .into_iter()
+        .filter_map(|p| p.id.map(|oid| (oid.to_hex(), p)))
+        .collect();
+
+    let mut by_seller: BTreeMap<String, Vec<(&Purchase, &Product)>> = BTreeMap::new();
+    for purchase in purchases {
+        if let Some(product) = products.get(&purchase.product_id) {
+            by_seller.entry(product.seller_id.clone()).or_default().push((purchase, product));
+        }
+    }
+
+    let customer_name = user_name(db, &customer_id).await?;
+    let billing_address = match order {
+        Some(order) => order.billing_address.clone(),
+        None => purchases[0].billing_address.clone(),
+    };
+
+    for (seller_id, items) in by_seller {
+        let lines = items
+            .iter()
+            .map(|(purchase, product)| purchase_line(&product.title, purchase))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let invoice = build_invoice(
+            db,
+            session,
+            &seller_id,
+            &customer_id,
+            &customer_name,
+            lines,
+        )
+        .await?;
+
+        let invoice = Invoice {
+            billing_address: billing_address.clone(),
+            reverse_charge: items
+                .iter()
+                .any(|(purchase, _)| purchase.tax_lines.iter().any(|l| l.reverse_charge)),
+            order_id: order.and_then(|o| o.id).map(|oid| oid.to_hex()),
+            purchase_ids: items
+                .iter()
+                .filter_map(|(purchase, _)| purchase.id.map(|oid| oid.to_hex()))
+                .collect(),
+            ..invoice
+        };
+
+        db.collection::<Invoice>("invoices")
+            .insert_one_with_session(invoice, None, session)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Issues the provider's invoice for a booking paid through the platform,
+/// inside the caller's transaction like `issue_for_purchases`.
+pub async fn issue_for_booking(
+    db: &Database,
+    session: &mut ClientSession,
+    booking: &Booking,
+) -> Result<(), mongodb::error::Error> {
+    let booking_id = match booking.id {
+        Some(oid) => oid.to_hex(),
+        None => return Ok(()),
+    };
+
+    let service = match ObjectId::parse_str(&booking.service_id) {
+        Ok(oid) => db.collection::<Service>("services").find_one(doc! { "_id": oid }, None).await?,
+        Err(_) => None,
+    };
+    let service = match service {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    let invoices = db.collection::<Invoice>("invoices");
+
+    // Webhook redeliveries must not bill the booking twice
+    if invoices
+        .find_one_with_session(doc! { "booking_id": &booking_id }, None, &mut *session)
+        .await?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    let customer_name = user_name(db, &booking.customer_id).await?;
+    let charged = booking.amount.clone().unwrap_or_else(|| service.price.clone());
+    let description = format!(
+        "{}{} on {} at {}",
+        if charged.amount < service.price.amount { "Deposit for " } else { "" },
+        service.title,
+        booking.booking_date,
+        booking.booking_time
+    );
+    let invoice = build_invoice(
+        db,
+        session,
+        &service.provider_id,
+        &booking.customer_id,
+        &customer_name,
+        vec![line(&description, &charged)],
+    )
+    .await?;
+
+    invoices
+        .insert_one_with_session(
+            Invoice { booking_id: Some(booking_id), ..invoice },
+            None,
+            session,
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Renders invoices as a PDF, one page each.
+pub fn render_pdf(invoices: &[Invoice]) -> Vec<u8> {
+    let pages: Vec<Page> = invoices.iter().map(render_page).collect();
+    pdf::render(&pages)
+}
+
+fn render_page(invoice: &Invoice) -> Page {
+    let mut page = Page::new();
+    let left = 50.0;
+    let right = PAGE_WIDTH - 50.0;
+    let mut y = PAGE_HEIGHT - 60.0;
+
+    page.text(left, y, 22.0, true, "INVOICE");
+    page.text_right(right, y, 11.0, true, &invoice.invoice_number);
+    y -= 18.0;
+    page.text_right(right, y, 10.0, false, &format!("Issued {}", invoice.issued_at.format("%Y-%m-%d")));
+
+    y -= 40.0;
+    page.text(left, y, 10.0, true, "From");
+    page.text(300.0, y, 10.0, true, "Bill to");
+    y -= 15.0;
+    page.text(left, y, 10.0, false, &invoice.seller_name);
+
+    let mut bill_to = vec![invoice.customer_name.clone()];
+    if let Some(address) = &invoice.billing_address {
+        bill_to = vec![address.name.clone(), address.line1.clone()];
+        if let Some(line2) = &address.line2 {
+            bill_to.push(line2.clone());
+        }
+        bill_to.push(format!("{} {}", address.postal_code, address.city));
+        bill_to.push(address.country.clone());
+        if let Some(tax_id) = &address.tax_id {
+            bill_to.push(format!("Tax ID: {}", tax_id));
+        }
+    }
+    for text in &bill_to {
+        page.text(300.0, y, 10.0, false, text);
+        y -= 14.0;
+    }
+
+    y -= 26.0;
+    page.text(left, y, 10.0, true, "Description");
+    page.text_right(330.0, y, 10.0, true, "Qty");
+    page.text_right(410.0, y, 10.0, true, "Unit price");
+    page.text_right(470.0, y, 10.0, true, "Tax");
+    page.text_right(right, y, 10.0, true, "Amount");
+    y -= 6.0;
+    page.line(left, y, right, y);
+
+    for line in &invoice.lines {
+        y -= 16.0;
+        page.text(left, y, 10.0, false, &truncate(&line.description, 45));
+        page.text_right(330.0, y, 10.0, false, &line.quantity.to_string());
+        page.text_right(410.0, y, 10.0, false, &line.unit_price.to_decimal_string());
+        page.text_right(470.0, y, 10.0, false, &percent(line.tax_rate));
+        page.text_right(right, y, 10.0, false, &line.amount.to_decimal_string());
+    }
+
+    y -= 8.0;
+    page.line(left, y, right, y);
+
+    for (label, amount, bold) in [
+        ("Subtotal", &invoice.subtotal, false),
+        ("Tax", &invoice.tax_total, false),
+        ("Total", &invoice.total, true),
+    ] {
+        y -= 16.0;
+        page.text_right(470.0, y, 10.0, bold, label);
+        page.text_right(right, y, 10.0, bold, &amount.to_string());
+    }
+
+    if invoice.reverse_charge {
+        y -= 30.0;
+        page.text(left, y, 9.0, false, "Reverse charge: the customer is liable to account for the tax.");
+    }
+
+    page
+}
+
+async fn build_invoice(
+    db: &Database,
+    session: &mut ClientSession,
+    seller_id: &str,
+    customer_id: &str,
+    customer_name: &str,
+    lines: Vec<InvoiceLine>,
+) -> Result<Invoice, mongodb::error::Error> {
+    let sequence = next_sequence(db, session, seller_id).await?;
+    let seller_name = user_name(db, seller_id).await?;
+
+    // Everything on one invoice was paid in one currency
+    let currency = lines[0].amount.currency.clone();
+    let subtotal = Money::sum(&currency, lines.iter().map(|l| &l.amount))?;
+    let tax_total = Money::sum(&currency, lines.iter().map(|l| &l.tax_amount))?;
+    let total = subtotal.checked_add(&tax_total)?;
+
+    Ok(Invoice {
+        id: None,
+        invoice_number: invoice_number(seller_id, sequence),
+        sequence,
+        seller_id: seller_id.to_string(),
+        seller_name,
+        customer_id: customer_id.to_string(),
+        customer_name: customer_name.to_string(),
+        billing_address: None,
+        order_id: None,
+        purchase_ids: Vec::new(),
+        booking_id: None,
+        lines,
+        subtotal,
+        tax_total,
+        total,
+        reverse_charge: false,
+        issued_at: Utc::now(),
+    })
+}
+
+fn line(description: &str, price: &Money) -> InvoiceLine {
+    InvoiceLine {
+        description: description.to_string(),
+        quantity: 1,
+        unit_price: price.clone(),
+        tax_rate: 0.0,
+        tax_amount: Money::zero(&price.currency),
+        amount: price.clone(),
+    }
+}
+
+// The purchase amount includes its tax; invoices show the net price and the tax separately
+fn purchase_line(description: &str, purchase: &Purchase) -> Result<InvoiceLine, MoneyError> {
+    let tax = purchase.tax()?;
+    let net = purchase.amount.checked_sub(&tax)?;
+
+    let codes: Vec<&str> = purchase.discounts.iter().map(|d| d.code.as_str()).collect();
+    let description = if codes.is_empty() {
+        description.to_string()
+    } else {
+        format!("{} (coupon {})", description, codes.join(", "))
+    };
+
+    Ok(InvoiceLine {
+        description,
+        quantity: 1,
+        unit_price: net.clone(),
+        tax_rate: purchase.tax_lines.iter().filter(|l| !l.reverse_charge).map(|l| l.rate).sum(),
+        tax_amount: tax,
+        amount: net,
+    })
+}
+
+async fn next_sequence(db: &Database, session: &mut ClientSession, seller_id: &str) -> Result<i64, mongodb::error::Error> {
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+
+    let counter = db
+        .collection::<Document>("invoice_counters")
+        .find_one_and_update_with_session(
+            doc! { "_id": seller_id },
+            doc! { "$inc": { "seq": 1_i64 } },
+            options,
+            session,
+        )
+        .await?;
+
+    Ok(counter.and_then(|c| c.get_i64("seq").ok()).unwrap_or(1))
+}
+
+/// e.g. `INV-9C0E1F-000042`: the seller's ID suffix keeps numbers unique across sellers.
+fn invoice_number(seller_id: &str, sequence: i64) -> String {
+    let suffix = &seller_id[seller_id.len().saturating_sub(6)..];
+    format!("INV-{}-{:06}", suffix.to_uppercase(), sequence)
+}
+
+async fn user_name(db: &Database, user_id: &str) -> Result<String, mongodb::error::Error> {
+    let user = match ObjectId::parse_str(user_id) {
+        Ok(oid) => db.collection::<User>("users").find_one(doc! { "_id": oid }, None).await?,
+        Err(_) => None,
+    };
+
+    Ok(user.map(|u| u.name).unwrap_or_default())
+}
+
+// e.g. 20% or 9.975%
+fn percent(rate: f64) -> String {
+    let formatted = format!("{:.3}", rate * 100.0);
+    format!("{}%", formatted.trim_end_matches('0').trim_end_matches('.'))
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else {
+        format!("{}...", text.chars().take(max_chars - 3).collect::<String>())
+    }
+}
@@ -0,0 +1,467 @@
+use mongodb::{Collection, Database};
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions};
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::TryStreamExt;
+use std::env;
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::db::is_duplicate_key;
+use crate::escrow;
+use crate::fulfillment;
+use crate::payments::PaymentGateway;
+use crate::payouts;
+use crate::reviews;
+use crate::subscriptions;
+use crate::models::{Booking, Job, Notification, Product, ProductVersion, Purchase, Service};
+
+const LEADER_LEASE: &str = "scheduler-leader";
+const MAX_ATTEMPTS: i32 = 5;
+const JOBS_PER_TICK: usize = 50;
+const REMINDER_LEAD_HOURS: [i64; 2] = [24, 1];
+
+/// Queues a job to run at `run_at`. Jobs with a `dedupe_key` are only queued once.
+pub async fn schedule(
+    db: &Database,
+    kind: &str,
+    run_at: DateTime<Utc>,
+    payload: Document,
+    dedupe_key: Option<String>,
+) -> Result<(), mongodb::error::Error> {
+    let jobs = db.collection::<Job>("jobs");
+    let job = Job {
+        id: None,
+        kind: kind.to_string(),
+        payload,
+        dedupe_key: dedupe_key.clone(),
+        status: "pending".to_string(),
+        attempts: 0,
+        locked_by: None,
+        locked_until: None,
+        last_error: None,
+        run_at,
+        created_at: Utc::now(),
+    };
+
+    match jobs.insert_one(job, None).await {
+        Ok(_) => Ok(()),
+        Err(e) if dedupe_key.is_some() && is_duplicate_key(&e) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Queues the 24h and 1h reminders for a freshly created booking.
+pub async fn schedule_booking_reminders(db: &Database, booking_id: &ObjectId, booking: &Booking) -> Result<(), mongodb::error::Error> {
+    let start = match booking.starts_at_utc() {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    for lead in REMINDER_LEAD_HOURS {
+        let run_at = start - Duration::hours(lead);
+        if run_at <= Utc::now() {
+            continue;
+        }
+
+        schedule(
+            db,
+            "booking_reminder",
+            run_at,
+            doc! { "booking_id": booking_id.to_hex(), "lead_hours": lead },
+            Some(format!("booking_reminder:{}:{}", booking_id.to_hex(), lead)),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Spawns the scheduler loop on the actix runtime.
+///
+/// Every instance claims due jobs atomically, so each job runs once. Periodic
+/// maintenance (booking expiry, completion tracking) only runs on the instance
+/// holding the leader lease.
+pub fn start(db: Database, gateway: Arc<dyn PaymentGateway>) {
+    let instance_id = Uuid::new_v4().to_string();
+    let poll_seconds = env_u64("JOB_POLL_SECONDS", 30);
+
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(poll_seconds));
+
+        loop {
+            interval.tick().await;
+
+            if acquire_leadership(&db, &instance_id, poll_seconds).await {
+                run_maintenance(&db, gateway.as_ref()).await;
+            }
+
+            run_due_jobs(&db, gateway.as_ref(), &instance_id).await;
+        }
+    });
+}
+
+async fn acquire_leadership(db: &Database, instance_id: &str, poll_seconds: u64) -> bool {
+    let leases = db.collection::<Document>("scheduler_leases");
+    let now = Utc::now();
+    // The lease outlives a few missed ticks before another instance may take over
+    let expires_at = now + Duration::seconds((poll_seconds * 3) as i64);
+
+    let filter = doc! {
+        "_id": LEADER_LEASE,
+        "$or": [
+            { "holder": instance_id },
+            { "expires_at": { "$lt": now } },
+        ]
+    };
+    let update = doc! { "$set": { "holder": instance_id, "expires_at": expires_at } };
+    let options = UpdateOptions::builder().upsert(true).build();
+
+    match leases.update_one(filter, update, options).await {
+        Ok(_) => true,
+        // The lease exists and is held by someone else, so the upsert collided
+        Err(e) if is_duplicate_key(&e) => false,
+        Err(e) => {
+            log::warn!("Failed to acquire scheduler lease: {}", e);
+            false
+        }
+    }
+}
+
+async fn run_due_jobs(db: &Database, gateway: &dyn PaymentGateway, instance_id: &str) {
+    let jobs = db.collection::<Job>("jobs");
+
+    for _ in 0..JOBS_PER_TICK {
+        let job = match claim_next(&jobs, instance_id).await {
+            Ok(Some(job)) => job,
+            Ok(None) => break,
+            Err(e) => {
+                log::warn!("Failed to claim job: {}", e);
+                break;
+            }
+        };
+
+        let result = execute(db, gateway, &job).await;
+        if let Err(e) = finish(&jobs, &job, instance_id, result).await {
+            log::warn!("Failed to record job result: {}", e);
+        }
+    }
+}
+
+async fn claim_next(jobs: &Collection<Job>, instance_id: &str) -> Result<Option<Job>, mongodb::error::Error> {
+    let now = Utc::now();
+    let filter = doc! {
+        "run_at": { "$lte": now },
+        "$or": [
+            { "status": "pending" },
+            // Jobs whose runner died mid-flight become claimable again
+            { "status": "running", "locked_until": { "$lt": now } },
+        ]
+    };
+    let update = doc! {
+        "$set": {
+            "status": "running",
+            "locked_by": instance_id,
+            "locked_until": now + Duration::minutes(5),
+        },
+        "$inc": { "attempts": 1 }
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "run_at": 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+
+    jobs.find_one_and_update(filter, update, options).await
+}
+
+async fn finish(
+    jobs: &Collection<Job>,
+    job: &Job,
+    instance_id: &str,
+    result: Result<(), String>,
+) -> Result<(), mongodb::error::Error> {
+    let update = match result {
+        Ok(()) => doc! { "$set": { "status": "done", "locked_by": null, "locked_until": null } },
+        Err(e) => {
+            log::warn!("Job {} ({}) failed: {}", job.kind, job.id.map(|id| id.to_hex()).unwrap_or_default(), e);
+            if job.attempts >= MAX_ATTEMPTS {
+                doc! { "$set": { "status": "failed", "last_error": e, "locked_by": null, "locked_until": null } }
+            } else {
+                let retry_at = Utc::now() + Duration::minutes(1 << job.attempts);
+                doc! { "$set": { "status": "pending", "last_error": e, "run_at": retry_at, "locked_by": null, "locked_until": null } }
+            }
+        }
+    };
+
+    jobs.update_one(doc! { "_id": job.id, "locked_by": instance_id }, update, None)
+        .await
+        .map(|_| ())
+}
+
+async fn execute(db: &Database, gateway: &dyn PaymentGateway, job: &Job) -> Result<(), String> {
+    match job.kind.as_str() {
+        "booking_reminder" => send_booking_reminder(db, &job.payload).await,
+        "product_update" => notify_product_update(db, &job.payload).await,
+        "payout_batch" => run_payout_batch(db, &job.payload).await,
+        "subscription_renewal" => renew_subscription(db, gateway, &job.payload).await,
+        "rating_repair" => repair_ratings(db).await,
+        other => Err(format!("Unknown job kind: {}", other)),
+    }
+}
+
+async fn run_maintenance(db: &Database, gateway: &dyn PaymentGateway) {
+    if let Err(e) = expire_pending_bookings(db).await {
+        log::warn!("Failed to expire pending bookings: {}", e);
+    }
+
+    if let Err(e) = mark_awaiting_completion(db).await {
+        log::warn!("Failed to mark past bookings: {}", e);
+    }
+
+    if let Err(e) = escrow::run_due(db, gateway).await {
+        log::warn!("Failed to settle booking escrow: {}", e);
+    }
+
+    if let Err(e) = payouts::schedule_next_batch(db).await {
+        log::warn!("Failed to schedule payout batch: {}", e);
+    }
+
+    if let Err(e) = subscriptions::release_abandoned(db).await {
+        log::warn!("Failed to release abandoned subscriptions: {}", e);
+    }
+
+    if let Err(e) = reviews::schedule_repair(db).await {
+        log::warn!("Failed to schedule rating repair: {}", e);
+    }
+}
+
+async fn send_booking_reminder(db: &Database, payload: &Document) -> Result<(), String> {
+    let booking_id = payload.get_str("booking_id").map_err(|e| e.to_string())?;
+    let lead_hours = payload.get_i64("lead_hours").map_err(|e| e.to_string())?;
+    let booking_oid = ObjectId::parse_str(booking_id).map_err(|e| e.to_string())?;
+
+    let booking = match db
+        .collection::<Booking>("bookings")
+        .find_one(doc! { "_id": booking_oid }, None)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        Some(b) => b,
+        None => return Ok(()),
+    };
+
+    // Cancelled or expired bookings no longer need a reminder
+    if booking.status != "pending" && booking.status != "confirmed" {
+        return Ok(());
+    }
+
+    let service = match ObjectId::parse_str(&booking.service_id) {
+        Ok(oid) => db
+            .collection::<Service>("services")
+            .find_one(doc! { "_id": oid }, None)
+            .await
+            .map_err(|e| e.to_string())?,
+        Err(_) => None,
+    };
+
+    let title = service.as_ref().map(|s| s.title.as_str()).unwrap_or("Your booking");
+    let lead = if lead_hours == 1 { "1 hour".to_string() } else { format!("{} hours", lead_hours) };
+    let message = format!(
+        "Reminder: {} starts in {} ({} at {})",
+        title, lead, booking.booking_date, booking.booking_time
+    );
+
+    let mut recipients = vec![booking.customer_id.clone()];
+    if let Some(service) = &service {
+        recipients.push(service.provider_id.clone());
+    }
+
+    let notifications: Vec<Notification> = recipients
+        .into_iter()
+        .map(|user_id| Notification {
+            id: None,
+            user_id,
+            kind: "booking_reminder".to_string(),
+            message: message.clone(),
+            reference_id: Some(booking_id.to_string()),
+            read: false,
+            created_at: Utc::now(),
+        })
+        .collect();
+
+    db.collection::<Notification>("notifications")
+        .insert_many(notifications, None)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Tells earlier buyers who are entitled to a newly released version about it.
+async fn notify_product_update(db: &Database, payload: &Document) -> Result<(), String> {
+    let product_id = payload.get_str("product_id").map_err(|e| e.to_string())?;
+    let version_number = payload.get_i32("version").map_err(|e| e.to_string())?;
+    let product_oid = ObjectId::parse_str(product_id).map_err(|e| e.to_string())?;
+
+    let product = match db
+        .collection::<Product>("products")
+        .find_one(doc! { "_id": product_oid }, None)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+
+    let version = match db
+        .collection::<ProductVersion>("product_versions")
+        .find_one(doc! { "product_id": product_id, "version": version_number }, None)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    let purchases: Vec<Purchase> = db
+        .collection::<Purchase>("purchases")
+        .find(doc! { "product_id": product_id, "status": "completed" }, None)
+        .await
+        .map_err(|e| e.to_string())?
+        .try_collect()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // A retried job must not notify the same buyer twice
+    let reference_id = format!("{}:{}", product_id, version_number);
+    let already_notified: Vec<String> = db
+        .collection::<Notification>("notifications")
+        .distinct("user_id", doc! { "kind": "product_update", "reference_id": &reference_id }, None)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter_map(|v| v.as_str().map(|s| s.to_string()))
+        .collect();
+
+    let mut recipients: Vec<String> = purchases
+        .iter()
+        .filter(|p| p.version.unwrap_or(1) < version_number && fulfillment::entitles(&product, p, &version))
+        .map(|p| p.customer_id.clone())
+        .filter(|customer_id| !already_notified.contains(customer_id))
+        .collect();
+    recipients.sort();
+    recipients.dedup();
+
+    if recipients.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "{} version {} is available: {}",
+        product.title, version_number, version.changelog
+    );
+    let notifications: Vec<Notification> = recipients
+        .into_iter()
+        .map(|user_id| Notification {
+            id: None,
+            user_id,
+            kind: "product_update".to_string(),
+            message: message.clone(),
+            reference_id: Some(reference_id.clone()),
+            read: false,
+            created_at: Utc::now(),
+        })
+        .collect();
+
+    db.collection::<Notification>("notifications")
+        .insert_many(notifications, None)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+async fn run_payout_batch(db: &Database, payload: &Document) -> Result<(), String> {
+    let batch_id = payload.get_str("batch_id").map_err(|e| e.to_string())?;
+
+    payouts::run_batch(db, batch_id)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+async fn renew_subscription(db: &Database, gateway: &dyn PaymentGateway, payload: &Document) -> Result<(), String> {
+    let subscription_id = payload.get_str("subscription_id").map_err(|e| e.to_string())?;
+    let subscription_oid = ObjectId::parse_str(subscription_id).map_err(|e| e.to_string())?;
+
+    subscriptions::renew(db, gateway, subscription_oid)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn repair_ratings(db: &Database) -> Result<(), String> {
+    let fixed = reviews::repair(db).await.map_err(|e| e.to_string())?;
+    if fixed > 0 {
+        log::info!("Rebuilt rating counters of {} items", fixed);
+    }
+    Ok(())
+}
+
+async fn expire_pending_bookings(db: &Database) -> Result<(), mongodb::error::Error> {
+    let ttl_hours = env_u64("BOOKING_PENDING_TTL_HOURS", 48);
+    let cutoff = Utc::now() - Duration::hours(ttl_hours as i64);
+
+    let result = db
+        .collection::<Booking>("bookings")
+        .update_many(
+            doc! { "status": "pending", "created_at": { "$lt": cutoff } },
+            // Expired bookings are refunded in full (see `escrow::run_due`)
+            vec![doc! { "$set": {
+                "status": "expired",
+                "sequence": { "$add": [{ "$ifNull": ["$sequence", 0] }, 1] },
+                "cancellation_refund": "$amount"
+            } }],
+            None,
+        )
+        .await?;
+
+    if result.modified_count > 0 {
+        log::info!("Expired {} pending bookings", result.modified_count);
+    }
+
+    Ok(())
+}
+
+async fn mark_awaiting_completion(db: &Database) -> Result<(), mongodb::error::Error> {
+    let bookings = db.collection::<Booking>("bookings");
+    let now = Utc::now();
+    // ISO dates compare lexicographically, so this narrows the scan to past days
+    let today = now.format("%Y-%m-%d").to_string();
+
+    let candidates: Vec<Booking> = bookings
+        .find(doc! { "status": "confirmed", "booking_date": { "$lte": today } }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    for booking in candidates {
+        let ended = booking.ends_at_utc().map(|end| end <= now).unwrap_or(false);
+        if !ended {
+            continue;
+        }
+
+        bookings
+            .update_one(
+                doc! { "_id": booking.id, "status": "confirmed" },
+                doc! { "$set": { "status": "awaiting_completion" }, "$inc": { "sequence": 1 } },
+                None,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
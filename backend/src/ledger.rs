@@ -0,0 +1,801 @@
+//! Double-entry record of the money the platform holds on behalf of others.
+//!
+//! Every transaction's entries sum to zero per currency. Debits are positive:
+//! a sale debits `gateway` (funds held at the payment provider) and credits the
+//! seller, the platform's commission and the tax authorities with their shares.
+//! Booking payments sit in `escrow` until the booking is completed.
+//! A seller's account therefore carries a negative balance while the platform
+//! owes them money, and payouts debit it back towards zero.
+
+use mongodb::{ClientSession, Database, bson::{doc, oid::ObjectId, Bson, Document}};
+use mongodb::options::FindOptions;
+use futures::stream::TryStreamExt;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use crate::models::{LedgerEntry, LedgerReconciliation, LedgerTransaction, Payout, Product, Purchase, Refund, SellerBalance, SubscriptionCharge};
+use crate::db::is_duplicate_key;
+use crate::money::Money;
+use crate::refunds::refund_share;
+
+/// Funds held at the payment provider.
+pub const GATEWAY_ACCOUNT: &str = "gateway";
+/// Tax collected and owed to tax authorities.
+pub const TAX_ACCOUNT: &str = "tax";
+/// The platform's own revenue.
+pub const COMMISSION_ACCOUNT: &str = "commission";
+/// Booking payments held until the booking is completed.
+pub const ESCROW_ACCOUNT: &str = "escrow";
+
+const SELLER_PREFIX: &str = "seller:";
+
+/// What the platform owes a seller or provider.
+pub fn seller_account(seller_id: &str) -> String {
+    format!("{}{}", SELLER_PREFIX, seller_id)
+}
+
+/// The platform's cut of a sale in a listing category: `COMMISSION_RATES`
+/// (e.g. `development=0.15,home=0.08`), falling back to `COMMISSION_RATE` (10%).
+pub fn commission_rate(category: &str) -> f64 {
+    let by_category = env::var("COMMISSION_RATES").unwrap_or_default();
+    by_category
+        .split(',')
+        .filter_map(|pair| {
+            let (name, rate) = pair.split_once('=')?;
+            if name.trim().eq_ignore_ascii_case(category) {
+                rate.trim().parse::<f64>().ok()
+            } else {
+                None
+            }
+        })
+        .next()
+        .or_else(|| env::var("COMMISSION_RATE").ok().and_then(|r| r.parse().ok()))
+        .unwrap_or(0.10)
+        .clamp(0.0, 1.0)
+}
+
+/// Records completed purchases inside the caller's transaction.
+pub async fn record_sales(
+    db: &Database,
+    session: &mut ClientSession,
+    purchases: &[Purchase],
+) -> Result<(), mongodb::error::Error> {
+    let products = products_of(db, purchases).await?;
+
+    let mut transactions = Vec::new();
+    for purchase in purchases {
+        let (purchase_id, product) = match (purchase.id, products.get(&purchase.product_id)) {
+            (Some(oid), Some(product)) => (oid.to_hex(), product),
+            _ => continue,
+        };
+
+        transactions.push(sale_transaction(
+            purchase_id,
+            &product.seller_id,
+            &product.category,
+            &purchase.amount,
+            &purchase.tax()?,
+        )?);
+    }
+
+    if !transactions.is_empty() {
+        db.collection::<LedgerTransaction>("ledger")
+            .insert_many_with_session(transactions, None, session)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Records a subscription's billing-period payment like a sale, with commission
+/// at the `subscription` category's rate.
+pub async fn record_subscription_charge(
+    db: &Database,
+    session: &mut ClientSession,
+    charge: &SubscriptionCharge,
+) -> Result<(), mongodb::error::Error> {
+    let charge_id = match charge.id {
+        Some(oid) => oid.to_hex(),
+        None => return Ok(()),
+    };
+
+    let sale = sale_transaction(charge_id, &charge.seller_id, "subscription", &charge.amount, &charge.tax()?)?;
+    db.collection::<LedgerTransaction>("ledger")
+        .insert_one_with_session(LedgerTransaction { kind: "subscription_charge".to_string(), ..sale }, None, session)
+        .await?;
+
+    Ok(())
+}
+
+/// Holds a booking payment in escrow inside the caller's transaction.
+pub async fn record_escrow_hold(
+    db: &Database,
+    session: &mut ClientSession,
+    booking_id: &str,
+    amount: &Money,
+) -> Result<(), mongodb::error::Error> {
+    let entry = transaction(
+        "escrow_hold",
+        booking_id.to_string(),
+        vec![
+            (GATEWAY_ACCOUNT.to_string(), amount.clone()),
+            (ESCROW_ACCOUNT.to_string(), negate(amount)),
+        ],
+    )?;
+
+    db.collection::<LedgerTransaction>("ledger")
+        .insert_one_with_session(entry, None, session)
+        .await?;
+
+    Ok(())
+}
+
+/// Passes what is left of a booking's escrow to its provider, less commission
+/// at the service category's rate. The dispute window has already passed, so
+/// there is no further holding period.
+pub async fn record_escrow_release(
+    db: &Database,
+    session: &mut ClientSession,
+    booking_id: &str,
+    provider_id: &str,
+    category: &str,
+    released: &Money,
+) -> Result<(), mongodb::error::Error> {
+    let commission = released.percentage(commission_rate(category))?;
+    let entry = transaction(
+        "escrow_release",
+        booking_id.to_string(),
+        vec![
+            (ESCROW_ACCOUNT.to_string(), released.clone()),
+            (seller_account(provider_id), negate(&released.checked_sub(&commission)?)),
+            (COMMISSION_ACCOUNT.to_string(), negate(&commission)),
+        ],
+    )?;
+
+    db.collection::<LedgerTransaction>("ledger")
+        .insert_one_with_session(entry, None, session)
+        .await?;
+
+    Ok(())
+}
+
+/// The transaction that paid out a purchase (`sale`) or booking (`escrow_release`).
+pub async fn find_transaction(
+    db: &Database,
+    kind: &str,
+    reference_id: &str,
+) -> Result<Option<LedgerTransaction>, mongodb::error::Error> {
+    db.collection::<LedgerTransaction>("ledger")
+        .find_one(doc! { "kind": kind, "reference_id": reference_id }, None)
+        .await
+}
+
+/// The commission taken by a recorded transaction.
+pub fn commission_of(transaction: &LedgerTransaction) -> Option<Money> {
+    transaction
+        .entries
+        .iter()
+        .find(|e| e.account == COMMISSION_ACCOUNT)
+        .map(|e| negate(&e.amount))
+}
+
+/// Reverses the refunded part of a sale: `tax` and `commission` of the refund
+/// are taken back from those accounts, the rest from the seller. While the sale
+/// is still held the refund comes out of the seller's pending balance.
+pub async fn record_refund(
+    db: &Database,
+    session: &mut ClientSession,
+    refund: &Refund,
+    tax: &Money,
+    commission: &Money,
+    available_at: Option<DateTime<Utc>>,
+) -> Result<(), mongodb::error::Error> {
+    let refund_id = match refund.id {
+        Some(oid) => oid.to_hex(),
+        None => return Ok(()),
+    };
+
+    let entry = refund_transaction(refund_id, refund, tax, commission, available_at)?;
+    db.collection::<LedgerTransaction>("ledger")
+        .insert_one_with_session(entry, None, session)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns a refund of a booking still in escrow straight from the escrow.
+pub async fn record_escrow_refund(
+    db: &Database,
+    session: &mut ClientSession,
+    refund: &Refund,
+) -> Result<(), mongodb::error::Error> {
+    let refund_id = match refund.id {
+        Some(oid) => oid.to_hex(),
+        None => return Ok(()),
+    };
+
+    let entry = transaction(
+        "refund",
+        refund_id,
+        vec![
+            (GATEWAY_ACCOUNT.to_string(), negate(&refund.amount)),
+            (ESCROW_ACCOUNT.to_string(), refund.amount.clone()),
+        ],
+    )?;
+
+    db.collection::<LedgerTransaction>("ledger")
+        .insert_one_with_session(entry, None, session)
+        .await?;
+
+    Ok(())
+}
+
+/// Moves a payout's amount out of the seller's balance as it leaves the gateway.
+pub async fn record_payout(
+    db: &Database,
+    session: &mut ClientSession,
+    payout: &Payout,
+) -> Result<(), mongodb::error::Error> {
+    post_payout(db, session, "payout", payout, 1).await
+}
+
+/// Puts a failed payout's amount back on the seller's balance.
+pub async fn record_payout_reversal(
+    db: &Database,
+    session: &mut ClientSession,
+    payout: &Payout,
+) -> Result<(), mongodb::error::Error> {
+    post_payout(db, session, "payout_reversal", payout, -1).await
+}
+
+async fn post_payout(
+    db: &Database,
+    session: &mut ClientSession,
+    kind: &str,
+    payout: &Payout,
+    sign: i64,
+) -> Result<(), mongodb::error::Error> {
+    let payout_id = match payout.id {
+        Some(oid) => oid.to_hex(),
+        None => return Ok(()),
+    };
+
+    let entry = payout_transaction(kind, payout_id, payout, sign)?;
+    db.collection::<LedgerTransaction>("ledger")
+        .insert_one_with_session(entry, None, session)
+        .await?;
+
+    Ok(())
+}
+
+/// What the platform owes a seller, per currency.
+pub async fn seller_balances(db: &Database, seller_id: &str) -> Result<Vec<SellerBalance>, mongodb::error::Error> {
+    let account = seller_account(seller_id);
+    let balances = account_balances(db, doc! { "entries.account": &account }).await?;
+
+    Ok(balances
+        .into_iter()
+        .map(|(_, currency, balance, pending)| SellerBalance {
+            balance: Money::new(-balance, &currency),
+            pending: Money::new(-pending, &currency),
+            available: Money::new(pending - balance, &currency),
+            currency,
+        })
+        .collect())
+}
+
+/// Every seller's balance that is out of its holding period, per currency.
+pub async fn payable_balances(db: &Database) -> Result<Vec<(String, Money)>, mongodb::error::Error> {
+    let balances = account_balances(db, doc! { "entries.account": { "$regex": format!("^{}", SELLER_PREFIX) } }).await?;
+
+    Ok(balances
+        .into_iter()
+        .filter_map(|(account, currency, balance, pending)| {
+            let seller_id = account.strip_prefix(SELLER_PREFIX)?.to_string();
+            Some((seller_id, Money::new(pending - balance, &currency)))
+        })
+        .collect())
+}
+
+// (account, currency, sum of entries, sum of entries still held) for matching accounts
+async fn account_balances(db: &Database, account_filter: Document) -> Result<Vec<(String, String, i64, i64)>, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! { "$match": account_filter.clone() },
+        doc! { "$unwind": "$entries" },
+        doc! { "$match": account_filter },
+        doc! { "$group": {
+            "_id": { "account": "$entries.account", "currency": "$entries.amount.currency" },
+            "balance": { "$sum": "$entries.amount.amount" },
+            "pending": { "$sum": {
+                "$cond": [{ "$gt": ["$available_at", Utc::now()] }, "$entries.amount.amount", 0_i64]
+            } }
+        } },
+        doc! { "$sort": { "_id.account": 1, "_id.currency": 1 } },
+    ];
+
+    let rows: Vec<Document> = db
+        .collection::<LedgerTransaction>("ledger")
+        .aggregate(pipeline, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let id = row.get_document("_id").ok()?;
+            Some((
+                id.get_str("account").ok()?.to_string(),
+                id.get_str("currency").ok()?.to_string(),
+                as_i64(row.get("balance")),
+                as_i64(row.get("pending")),
+            ))
+        })
+        .collect())
+}
+
+/// Checks that the ledger balances overall and per transaction, and that every
+/// completed sale made it into the ledger.
+pub async fn reconcile(db: &Database) -> Result<LedgerReconciliation, mongodb::error::Error> {
+    let ledger = db.collection::<LedgerTransaction>("ledger");
+    let transactions = ledger.count_documents(None, None).await?;
+
+    let per_transaction: Vec<Document> = ledger
+        .aggregate(
+            vec![
+                doc! { "$unwind": "$entries" },
+                doc! { "$group": {
+                    "_id": { "transaction": "$_id", "currency": "$entries.amount.currency" },
+                    "sum": { "$sum": "$entries.amount.amount" }
+                } },
+            ],
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut totals: BTreeMap<String, i64> = BTreeMap::new();
+    let mut unbalanced: Vec<String> = Vec::new();
+    for row in per_transaction {
+        let id = match row.get_document("_id") {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        let sum = as_i64(row.get("sum"));
+        let currency = id.get_str("currency").unwrap_or_default().to_string();
+        *totals.entry(currency).or_default() += sum;
+
+        if sum != 0 {
+            if let Ok(oid) = id.get_object_id("transaction") {
+                unbalanced.push(oid.to_hex());
+            }
+        }
+    }
+    unbalanced.sort();
+    unbalanced.dedup();
+
+    let unrecorded_sales = unrecorded_sales(db).await?;
+
+    Ok(LedgerReconciliation {
+        balanced: unbalanced.is_empty() && totals.values().all(|sum| *sum == 0),
+        transactions,
+        totals: totals.into_iter().map(|(currency, sum)| Money::new(sum, &currency)).collect(),
+        unbalanced_transactions: unbalanced,
+        unrecorded_sales,
+    })
+}
+
+/// Posts the sales of paid purchases completed before the ledger existed, with the
+/// refunds made on them since, so seller balances and payouts include them.
+/// Returns how many sales were posted.
+pub async fn backfill_sales(db: &Database) -> Result<usize, mongodb::error::Error> {
+    let missing: Vec<ObjectId> = unrecorded_sales(db)
+        .await?
+        .iter()
+        .filter_map(|id| ObjectId::parse_str(id).ok())
+        .collect();
+    if missing.is_empty() {
+        return Ok(0);
+    }
+
+    let purchases: Vec<Purchase> = db
+        .collection::<Purchase>("purchases")
+        .find(doc! { "_id": { "$in": missing } }, None)
+        .await?
+        .try_collect()
+        .await?;
+    let products = products_of(db, &purchases).await?;
+    let recorded_refunds = recorded_references(db, "refund").await?;
+    let ledger = db.collection::<LedgerTransaction>("ledger");
+    let mut posted = 0;
+
+    for purchase in &purchases {
+        let (purchase_id, product) = match (purchase.id, products.get(&purchase.product_id)) {
+            (Some(oid), Some(product)) => (oid.to_hex(), product),
+            _ => continue,
+        };
+
+        let tax = purchase.tax()?;
+        let sale = sale_transaction(purchase_id.clone(), &product.seller_id, &product.category, &purchase.amount, &tax)?;
+        // Dated at the purchase, so its holding period has long passed
+        let held_for = sale.available_at.map(|at| at - sale.created_at).unwrap_or_default();
+        let sale = LedgerTransaction {
+            created_at: purchase.created_at,
+            available_at: Some(purchase.created_at + held_for),
+            ..sale
+        };
+        let commission = commission_of(&sale).unwrap_or_else(|| Money::zero(&purchase.amount.currency));
+
+        let mut transactions = vec![sale];
+        let refunds: Vec<Refund> = db
+            .collection::<Refund>("refunds")
+            .find(
+                doc! { "kind": "purchase", "subject_id": &purchase_id, "status": "succeeded" },
+                FindOptions::builder().sort(doc! { "created_at": 1 }).build(),
+            )
+            .await?
+            .try_collect()
+            .await?;
+        let mut refunded = 0;
+        for refund in refunds {
+            let before = refunded;
+            refunded += refund.amount.amount;
+            let refund_id = match refund.id {
+                Some(oid) => oid.to_hex(),
+                None => continue,
+            };
+            if recorded_refunds.contains(&refund_id) {
+                continue;
+            }
+
+            let currency = &purchase.amount.currency;
+            let share = |part: &Money| {
+                let gross = purchase.amount.amount;
+                Money::new(refund_share(before, refunded, part.amount, gross), currency)
+            };
+            transactions.push(LedgerTransaction {
+                created_at: refund.decided_at.unwrap_or(refund.created_at),
+                ..refund_transaction(refund_id, &refund, &share(&tax), &share(&commission), None)?
+            });
+        }
+
+        match ledger.insert_many(transactions, None).await {
+            Ok(_) => posted += 1,
+            // Posted by another instance starting at the same time
+            Err(e) if is_duplicate_key(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(posted)
+}
+
+// Completed (or since refunded) purchases without a `sale` transaction
+// Paid purchases without a sale; purchases completed before payments were taken
+// never brought in any money, so they aren't sales
+async fn unrecorded_sales(db: &Database) -> Result<Vec<String>, mongodb::error::Error> {
+    let recorded = recorded_references(db, "sale").await?;
+    let filter = doc! {
+        "status": { "$in": ["completed", "refunded"] },
+        "payment_intent_id": { "$type": "string" },
+    };
+
+    Ok(db
+        .collection::<Document>("purchases")
+        .distinct("_id", filter, None)
+        .await?
+        .into_iter()
+        .filter_map(|v| v.as_object_id().map(|oid| oid.to_hex()))
+        .filter(|id| !recorded.contains(id))
+        .collect())
+}
+
+async fn recorded_references(db: &Database, kind: &str) -> Result<HashSet<String>, mongodb::error::Error> {
+    Ok(db
+        .collection::<LedgerTransaction>("ledger")
+        .distinct("reference_id", doc! { "kind": kind }, None)
+        .await?
+        .into_iter()
+        .filter_map(|v| v.as_str().map(|s| s.to_string()))
+        .collect())
+}
+
+// The purchased products, keyed by ID
+async fn products_of(db: &Database, purchases: &[Purchase]) -> Result<HashMap<String, Product>, mongodb::error::Error> {
+    let product_oids: Vec<ObjectId> = purchases
+        .iter()
+        .filter_map(|p| ObjectId::parse_str(&p.product_id).ok())
+        .collect();
+
+    Ok(db
+        .collection::<Product>("products")
+        .find(doc! { "_id": { "$in": product_oids } }, None)
+        .await?
+        .try_collect::<Vec<Product>>()
+        .await?
+        .into_iter()
+        .filter_map(|p| p.id.map(|oid| (oid.to_hex(), p)))
+        .collect())
+}
+
+// The seller share becomes payable once the holding period (`PAYOUT_HOLD_DAYS`) has passed
+fn sale_transaction(
+    reference_id: String,
+    seller_id: &str,
+    category: &str,
+    gross: &Money,
+    tax: &Money,
+) -> Result<LedgerTransaction, mongodb::error::Error> {
+    let net = gross.checked_sub(tax)?;
+    let commission = net.percentage(commission_rate(category))?;
+    let seller_share = net.checked_sub(&commission)?;
+    let hold_days = env::var("PAYOUT_HOLD_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(7);
+
+    let sale = transaction(
+        "sale",
+        reference_id,
+        vec![
+            (GATEWAY_ACCOUNT.to_string(), gross.clone()),
+            (seller_account(seller_id), negate(&seller_share)),
+            (COMMISSION_ACCOUNT.to_string(), negate(&commission)),
+            (TAX_ACCOUNT.to_string(), negate(tax)),
+        ],
+    )?;
+
+    Ok(LedgerTransaction {
+        available_at: Some(sale.created_at + Duration::days(hold_days)),
+        ..sale
+    })
+}
+
+// The seller keeps what is left of the refund after tax and commission are taken back
+fn refund_transaction(
+    refund_id: String,
+    refund: &Refund,
+    tax: &Money,
+    commission: &Money,
+    available_at: Option<DateTime<Utc>>,
+) -> Result<LedgerTransaction, mongodb::error::Error> {
+    let seller_share = refund.amount.checked_sub(tax)?.checked_sub(commission)?;
+    let refund = transaction(
+        "refund",
+        refund_id,
+        vec![
+            (GATEWAY_ACCOUNT.to_string(), negate(&refund.amount)),
+            (seller_account(&refund.seller_id), seller_share),
+            (COMMISSION_ACCOUNT.to_string(), commission.clone()),
+            (TAX_ACCOUNT.to_string(), tax.clone()),
+        ],
+    )?;
+
+    Ok(LedgerTransaction {
+        available_at: available_at.filter(|at| *at > Utc::now()),
+        ..refund
+    })
+}
+
+// `sign` is 1 for a payout and -1 for its reversal
+fn payout_transaction(kind: &str, payout_id: String, payout: &Payout, sign: i64) -> Result<LedgerTransaction, mongodb::error::Error> {
+    let amount = Money::new(payout.amount.amount * sign, &payout.amount.currency);
+    transaction(
+        kind,
+        payout_id,
+        vec![
+            (seller_account(&payout.seller_id), amount.clone()),
+            (GATEWAY_ACCOUNT.to_string(), negate(&amount)),
+        ],
+    )
+}
+
+// Refuses to build a transaction whose entries don't sum to zero in each currency
+fn transaction(kind: &str, reference_id: String, entries: Vec<(String, Money)>) -> Result<LedgerTransaction, mongodb::error::Error> {
+    let mut sums: HashMap<&str, i64> = HashMap::new();
+    for (_, amount) in &entries {
+        *sums.entry(amount.currency.as_str()).or_default() += amount.amount;
+    }
+    if sums.values().any(|sum| *sum != 0) {
+        return Err(mongodb::error::Error::custom(format!(
+            "Unbalanced {} transaction for {}",
+            kind, reference_id
+        )));
+    }
+
+    Ok(LedgerTransaction {
+        id: None,
+        kind: kind.to_string(),
+        reference_id,
+        entries: entries
+            .into_iter()
+            .filter(|(_, amount)| amount.amount != 0)
+            .map(|(account, amount)| LedgerEntry { account, amount })
+            .collect(),
+        created_at: Utc::now(),
+        available_at: None,
+    })
+}
+
+fn negate(money: &Money) -> Money {
+    Money { amount: -money.amount, currency: money.currency.clone() }
+}
+
+fn as_i64(value: Option<&Bson>) -> i64 {
+    match value {
+        Some(Bson::Int64(n)) => *n,
+        Some(Bson::Int32(n)) => *n as i64,
+        Some(Bson::Double(n)) => *n as i64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer_refund(amount: i64) -> Refund {
+        Refund {
+            id: None,
+            kind: "purchase".to_string(),
+            subject_id: "purchase-1".to_string(),
+            customer_id: "customer-1".to_string(),
+            seller_id: "seller-1".to_string(),
+            amount: Money::new(amount, "USD"),
+            reason: "test".to_string(),
+            status: "succeeded".to_string(),
+            requested_by: "customer-1".to_string(),
+            decided_by: None,
+            gateway_refund_id: None,
+            failure_reason: None,
+            rejection_reason: None,
+            created_at: Utc::now(),
+            decided_at: None,
+        }
+    }
+
+    fn seller_payout(amount: i64) -> Payout {
+        Payout {
+            id: None,
+            batch_id: "2025-01-20T00:00Z".to_string(),
+            seller_id: "seller-1".to_string(),
+            amount: Money::new(amount, "USD"),
+            status: "paid".to_string(),
+            failure_reason: None,
+            created_at: Utc::now(),
+            settled_at: None,
+        }
+    }
+
+    // Sum of each (account, currency) over the transactions
+    fn balances(transactions: &[LedgerTransaction]) -> BTreeMap<(String, String), i64> {
+        let mut balances = BTreeMap::new();
+        for entry in transactions.iter().flat_map(|t| &t.entries) {
+            *balances.entry((entry.account.clone(), entry.amount.currency.clone())).or_default() += entry.amount.amount;
+        }
+        balances
+    }
+
+    fn assert_balanced(transaction: &LedgerTransaction) {
+        let mut sums: BTreeMap<&str, i64> = BTreeMap::new();
+        for entry in &transaction.entries {
+            *sums.entry(entry.amount.currency.as_str()).or_default() += entry.amount.amount;
+        }
+        assert!(sums.values().all(|sum| *sum == 0), "{} is unbalanced: {:?}", transaction.kind, sums);
+    }
+
+    fn amount_of(transaction: &LedgerTransaction, account: &str) -> i64 {
+        transaction
+            .entries
+            .iter()
+            .filter(|e| e.account == account)
+            .map(|e| e.amount.amount)
+            .sum()
+    }
+
+    #[test]
+    fn sale_rounds_commission_and_balances() {
+        // 10% of the 16.66 net is 1.666, rounded half up to 1.67
+        let sale = sale_transaction("purchase-1".to_string(), "seller-1", "development", &Money::new(1999, "USD"), &Money::new(333, "USD")).unwrap();
+
+        assert_balanced(&sale);
+        assert_eq!(amount_of(&sale, GATEWAY_ACCOUNT), 1999);
+        assert_eq!(amount_of(&sale, TAX_ACCOUNT), -333);
+        assert_eq!(amount_of(&sale, COMMISSION_ACCOUNT), -167);
+        assert_eq!(amount_of(&sale, &seller_account("seller-1")), -1499);
+        assert_eq!(commission_of(&sale), Some(Money::new(167, "USD")));
+    }
+
+    #[test]
+    fn sale_in_zero_decimal_currency_balances() {
+        let sale = sale_transaction("purchase-1".to_string(), "seller-1", "design", &Money::new(105, "JPY"), &Money::zero("JPY")).unwrap();
+
+        assert_balanced(&sale);
+        assert_eq!(amount_of(&sale, COMMISSION_ACCOUNT), -11);
+        assert_eq!(amount_of(&sale, &seller_account("seller-1")), -94);
+        // Zero entries are left out
+        assert!(sale.entries.iter().all(|e| e.account != TAX_ACCOUNT));
+    }
+
+    #[test]
+    fn partial_refunds_reverse_the_sale_exactly() {
+        let gross = Money::new(1000, "USD");
+        let tax = Money::new(167, "USD");
+        let sale = sale_transaction("purchase-1".to_string(), "seller-1", "development", &gross, &tax).unwrap();
+        let commission = commission_of(&sale).unwrap();
+
+        let mut transactions = vec![sale];
+        let mut refunded = 0;
+        for (n, amount) in [333, 333, 334].into_iter().enumerate() {
+            let before = refunded;
+            refunded += amount;
+            let tax_share = Money::new(refund_share(before, refunded, tax.amount, gross.amount), "USD");
+            let commission_share = Money::new(refund_share(before, refunded, commission.amount, gross.amount), "USD");
+
+            let transaction = refund_transaction(format!("refund-{}", n), &customer_refund(amount), &tax_share, &commission_share, None).unwrap();
+            assert_balanced(&transaction);
+            transactions.push(transaction);
+        }
+
+        // Refunding everything in parts leaves nothing behind in any account
+        assert!(balances(&transactions).values().all(|balance| *balance == 0), "{:?}", balances(&transactions));
+    }
+
+    #[test]
+    fn partial_refund_comes_out_of_the_seller_share() {
+        let gross = Money::new(2000, "EUR");
+        let sale = sale_transaction("purchase-1".to_string(), "seller-1", "development", &gross, &Money::zero("EUR")).unwrap();
+        let commission = commission_of(&sale).unwrap();
+        let commission_share = Money::new(refund_share(0, 500, commission.amount, gross.amount), "EUR");
+        let mut partial = customer_refund(500);
+        partial.amount = Money::new(500, "EUR");
+
+        let refund = refund_transaction("refund-1".to_string(), &partial, &Money::zero("EUR"), &commission_share, None).unwrap();
+        assert_balanced(&refund);
+
+        let balances = balances(&[sale, refund]);
+        assert_eq!(balances[&(GATEWAY_ACCOUNT.to_string(), "EUR".to_string())], 1500);
+        assert_eq!(balances[&(COMMISSION_ACCOUNT.to_string(), "EUR".to_string())], -150);
+        assert_eq!(balances[&(seller_account("seller-1"), "EUR".to_string())], -1350);
+    }
+
+    #[test]
+    fn payout_reversal_restores_the_seller_balance() {
+        let sale = sale_transaction("purchase-1".to_string(), "seller-1", "development", &Money::new(1000, "USD"), &Money::zero("USD")).unwrap();
+        let payout = payout_transaction("payout", "payout-1".to_string(), &seller_payout(900), 1).unwrap();
+        let reversal = payout_transaction("payout_reversal", "payout-1".to_string(), &seller_payout(900), -1).unwrap();
+        assert_balanced(&payout);
+        assert_balanced(&reversal);
+
+        let seller = (seller_account("seller-1"), "USD".to_string());
+        assert_eq!(balances(&[sale.clone(), payout.clone()])[&seller], 0);
+        assert_eq!(balances(&[sale, payout, reversal])[&seller], -900);
+    }
+
+    #[test]
+    fn transactions_balance_per_currency() {
+        let mixed = transaction(
+            "sale",
+            "purchase-1".to_string(),
+            vec![
+                (GATEWAY_ACCOUNT.to_string(), Money::new(100, "USD")),
+                (seller_account("seller-1"), Money::new(-100, "USD")),
+                (GATEWAY_ACCOUNT.to_string(), Money::new(5000, "JPY")),
+                (seller_account("seller-1"), Money::new(-5000, "JPY")),
+            ],
+        );
+        assert!(mixed.is_ok());
+
+        // Equal amounts in different currencies don't offset each other
+        let across_currencies = transaction(
+            "sale",
+            "purchase-1".to_string(),
+            vec![
+                (GATEWAY_ACCOUNT.to_string(), Money::new(100, "USD")),
+                (seller_account("seller-1"), Money::new(-100, "EUR")),
+            ],
+        );
+        assert!(across_currencies.is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use mongodb::Database;
+use mongodb::bson::doc;
+use chrono::Utc;
+use uuid::Uuid;
+use crate::db::with_transaction;
+use crate::models::{LicenseActivation, Purchase};
+
+pub enum Activation {
+    /// The machine was already activated; carries the key's activation count
+    Existing(i32),
+    /// The machine took a new activation slot
+    New(i32),
+    LimitReached(i32),
+}
+
+/// Generates a random key like `3F2A9C1E-7B4D-4E0A-9C55-1D2E3F4A5B6C`.
+pub fn generate_key() -> String {
+    Uuid::new_v4().to_string().to_uppercase()
+}
+
+/// Activates `machine_id` on the purchase's key, respecting `max_activations`.
+pub async fn activate(db: &Database, purchase: &Purchase, machine_id: &str) -> Result<Activation, mongodb::error::Error> {
+    let purchase_oid = purchase.id;
+    let license_key = purchase.license_key.clone().unwrap_or_default();
+    let product_id = purchase.product_id.clone();
+    let machine_id = machine_id.to_string();
+    let db_handle = db.clone();
+
+    with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let license_key = license_key.clone();
+        let product_id = product_id.clone();
+        let machine_id = machine_id.clone();
+        Box::pin(async move {
+            let activations = db.collection::<LicenseActivation>("license_activations");
+            let purchases = db.collection::<Purchase>("purchases");
+            let now = Utc::now();
+
+            let seen = activations
+                .update_one_with_session(
+                    doc! { "license_key": &license_key, "machine_id": &machine_id },
+                    doc! { "$set": { "last_validated_at": now } },
+                    None,
+                    session,
+                )
+                .await?;
+
+            let current = purchases
+                .find_one_with_session(doc! { "_id": purchase_oid }, None, session)
+                .await?
+                .map(|p| p.activation_count)
+                .unwrap_or(0);
+
+            if seen.matched_count > 0 {
+                return Ok(Activation::Existing(current));
+            }
+
+            let counted = purchases
+                .update_one_with_session(
+                    doc! {
+                        "_id": purchase_oid,
+                        "$or": [
+                            { "max_activations": null },
+                            { "$expr": { "$lt": ["$activation_count", "$max_activations"] } },
+                        ]
+                    },
+                    doc! { "$inc": { "activation_count": 1 } },
+                    None,
+                    session,
+                )
+                .await?;
+
+            if counted.modified_count == 0 {
+                return Ok(Activation::LimitReached(current));
+            }
+
+            let activation = LicenseActivation {
+                id: None,
+                license_key,
+                purchase_id: purchase_oid.map(|oid| oid.to_hex()).unwrap_or_default(),
+                product_id,
+                machine_id,
+                activated_at: now,
+                last_validated_at: now,
+            };
+            activations.insert_one_with_session(activation, None, session).await?;
+
+            Ok(Activation::New(current + 1))
+        })
+    })
+    .await
+}
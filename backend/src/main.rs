@@ -1,7 +1,28 @@
 mod models;
 mod handlers;
 mod db;
+mod download_links;
+mod escrow;
+mod fulfillment;
+mod fx;
 mod auth;
+mod bundles;
+mod coupons;
+mod ical;
+mod idempotency;
+mod invoices;
+mod jobs;
+mod ledger;
+mod licenses;
+mod money;
+mod payments;
+mod payouts;
+mod pdf;
+mod refunds;
+mod reviews;
+mod storage;
+mod subscriptions;
+mod tax;
 
 use actix_web::{web, App, HttpServer, middleware::Logger};
 use actix_cors::Cors;
@@ -14,6 +35,15 @@ async fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::init();
 
+    // Settings without safe defaults are checked before anything else starts
+    let gateway = payments::from_env().map_err(std::io::Error::other)?;
+    payments::required_env("DOWNLOAD_SIGNING_SECRET").map_err(std::io::Error::other)?;
+    let storage = storage::from_env().map_err(std::io::Error::other)?;
+    let exchange_rates = fx::from_env().map(web::Data::new).map_err(std::io::Error::other)?;
+    let tax_rules = tax::load_from_env()
+        .map(web::Data::new)
+        .map_err(|e| std::io::Error::other(format!("Failed to load tax rules: {}", e)))?;
+
     let mongodb_uri = env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
     let database_name = env::var("DATABASE_NAME").unwrap_or_else(|_| "marketplace_db".to_string());
 
@@ -26,18 +56,29 @@ async fn main() -> std::io::Result<()> {
     // Initialize collections with indexes
     db::init_db(&database).await.expect("Failed to initialize database");
 
+    // Background jobs (reminders, booking expiry, escrow, payouts) run alongside the HTTP server
+    jobs::start(database.clone(), gateway.clone());
+
     let host = env::var("SERVER_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
     let port = env::var("SERVER_PORT").unwrap_or_else(|_| "8080".to_string());
     let bind_address = format!("{}:{}", host, port);
 
     println!("🚀 Server starting on http://{}", bind_address);
     println!("📦 Connected to MongoDB: {}", database_name);
+    println!("💳 Payment gateway: {}", gateway.name());
+    println!("🗄️  File storage: {}", storage.name());
+    println!("💱 Exchange rates: {}", exchange_rates.provider_name());
+    println!("🧾 Tax rules: {}", tax_rules.version);
 
     HttpServer::new(move || {
         let cors = Cors::permissive();
 
         App::new()
             .app_data(web::Data::new(database.clone()))
+            .app_data(web::Data::from(gateway.clone()))
+            .app_data(web::Data::from(storage.clone()))
+            .app_data(exchange_rates.clone())
+            .app_data(tax_rules.clone())
             .wrap(cors)
             .wrap(Logger::default())
             .service(
@@ -50,13 +91,74 @@ async fn main() -> std::io::Result<()> {
                     .service(handlers::products::get_products)
                     .service(handlers::products::create_product)
                     .service(handlers::products::get_product_by_id)
+                    .service(handlers::products::get_product_access)
+                    .service(handlers::products::get_product_versions)
+                    .service(handlers::products::create_product_version)
                     .service(handlers::niche::get_niche_products)
                     .service(handlers::bookings::create_booking)
                     .service(handlers::bookings::get_user_bookings)
+                    .service(handlers::bookings::update_booking_status)
+                    .service(handlers::calendar::get_booking_ics)
+                    .service(handlers::calendar::create_calendar_feed)
+                    .service(handlers::calendar::get_calendar_feed)
                     .service(handlers::purchases::create_purchase)
                     .service(handlers::purchases::get_user_purchases)
+                    .service(handlers::purchases::download_purchase)
+                    .service(handlers::purchases::get_library)
+                    .service(handlers::purchases::serve_download)
+                    .service(handlers::refunds::refund_purchase)
+                    .service(handlers::refunds::refund_booking)
+                    .service(handlers::refunds::request_purchase_refund)
+                    .service(handlers::refunds::request_booking_refund)
+                    .service(handlers::refunds::get_refunds)
+                    .service(handlers::refunds::approve_refund)
+                    .service(handlers::refunds::reject_refund)
+                    .service(handlers::cart::get_cart)
+                    .service(handlers::cart::add_cart_item)
+                    .service(handlers::cart::remove_cart_item)
+                    .service(handlers::orders::checkout)
+                    .service(handlers::orders::get_orders)
+                    .service(handlers::orders::get_order)
+                    .service(handlers::invoices::get_order_invoice)
+                    .service(handlers::invoices::get_invoices)
+                    .service(handlers::invoices::get_invoice)
                     .service(handlers::reviews::create_review)
                     .service(handlers::reviews::get_reviews)
+                    .service(handlers::reviews::update_review)
+                    .service(handlers::reviews::delete_review)
+                    .service(handlers::notifications::get_notifications)
+                    .service(handlers::notifications::mark_notification_read)
+                    .service(handlers::webhooks::payment_webhook)
+                    .service(handlers::webhooks::replay_payment_events)
+                    .service(handlers::licenses::validate_license)
+                    .service(handlers::licenses::get_seller_licenses)
+                    .service(handlers::tax::get_tax_profile)
+                    .service(handlers::tax::update_tax_profile)
+                    .service(handlers::payouts::get_balance)
+                    .service(handlers::payouts::get_seller_payouts)
+                    .service(handlers::payouts::get_payouts)
+                    .service(handlers::payouts::mark_payout_paid)
+                    .service(handlers::payouts::mark_payout_failed)
+                    .service(handlers::payouts::reconcile_ledger)
+                    .service(handlers::coupons::validate_coupons)
+                    .service(handlers::coupons::create_coupon)
+                    .service(handlers::coupons::get_seller_coupons)
+                    .service(handlers::coupons::deactivate_coupon)
+                    .service(handlers::bundles::create_bundle)
+                    .service(handlers::bundles::get_bundle)
+                    .service(handlers::bundles::purchase_bundle)
+                    .service(handlers::subscriptions::create_subscription_plan)
+                    .service(handlers::subscriptions::get_subscription_plans)
+                    .service(handlers::subscriptions::subscribe)
+                    .service(handlers::subscriptions::get_subscriptions)
+                    .service(handlers::subscriptions::cancel_subscription)
+                    .service(handlers::subscriptions::update_payment_method)
+                    .service(handlers::subscriptions::download_subscription_product)
+                    .service(handlers::subscriptions::serve_subscription_download)
+                    .service(handlers::licenses::revoke_license)
+                    .service(handlers::uploads::upload_product_file)
+                    .service(handlers::uploads::upload_product_image)
+                    .service(handlers::uploads::get_file)
             )
     })
     .bind(&bind_address)?
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
-use mongodb::bson::oid::ObjectId;
-use chrono::{DateTime, Utc};
+use mongodb::bson::{oid::ObjectId, Document};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use crate::money::{Money, MoneyError};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
@@ -10,10 +12,38 @@ pub struct User {
     pub email: String,
     pub password_hash: String,
     pub user_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calendar_token: Option<String>,
+    /// Where a seller is established and registered to collect tax
+    #[serde(default)]
+    pub tax_profile: TaxProfile,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TaxProfile {
+    /// ISO 3166-1 alpha-2 country the seller is established in
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub registrations: Vec<TaxRegistration>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TaxRegistration {
+    /// `GB` for a country-wide tax, `US-WA` for a state or province
+    pub jurisdiction: String,
+    pub tax_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateTaxProfileRequest {
+    pub country: Option<String>,
+    #[serde(default)]
+    pub registrations: Vec<TaxRegistration>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct SignupRequest {
     pub name: String,
@@ -50,22 +80,66 @@ pub struct Service {
     pub title: String,
     pub description: String,
     pub category: String,
-    pub price: f64,
+    pub price: Money,
     pub location: String,
     pub icon: Option<String>,
+    /// Average of the reviews
     pub rating: Option<f64>,
+    #[serde(default)]
+    pub ratings: RatingSummary,
+    /// Average pulled towards `RATING_PRIOR_MEAN` while there are few reviews; used for ranking
+    #[serde(default)]
+    pub weighted_rating: Option<f64>,
+    /// Share of the price charged when booking; the full price when unset
+    #[serde(default)]
+    pub deposit_rate: Option<f64>,
+    /// flexible, moderate (default) or strict; decides refunds when customers cancel
+    #[serde(default = "default_cancellation_policy")]
+    pub cancellation_policy: String,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
 }
 
+/// Review counters kept on a service or product, updated as reviews change.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct RatingSummary {
+    pub count: i64,
+    /// Total of all star ratings
+    pub sum: i64,
+    /// Reviews per star rating, keyed "1" to "5"
+    #[serde(default)]
+    pub histogram: BTreeMap<String, i64>,
+}
+
+pub fn default_cancellation_policy() -> String {
+    "moderate".to_string()
+}
+
+/// A service as returned by the catalog endpoints.
+#[derive(Debug, Serialize)]
+pub struct ServiceResponse {
+    #[serde(flatten)]
+    pub service: Service,
+    /// `price` converted to the requested `?currency=`, for display only
+    pub display_price: Option<Money>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CurrencyQuery {
+    pub currency: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateServiceRequest {
     pub title: String,
     pub description: String,
     pub category: String,
-    pub price: f64,
+    pub price: Money,
     pub location: String,
     pub icon: Option<String>,
+    /// Between 0 and 1, e.g. 0.2 to take a 20% deposit
+    pub deposit_rate: Option<f64>,
+    pub cancellation_policy: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -76,25 +150,311 @@ pub struct Product {
     pub title: String,
     pub description: String,
     pub category: String,
-    pub price: f64,
+    pub price: Money,
     pub file_type: String,
-    pub file_url: String,
+    /// Stored file delivered to buyers (see `StoredFile`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    /// Externally hosted file from before uploads existed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_url: Option<String>,
     pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_file_id: Option<String>,
+    /// Latest released version; 0 for products listed before versioning
+    #[serde(default)]
+    pub current_version: i32,
+    /// Which versions buyers get: lifetime (every update), none (only the version
+    /// they bought) or window (updates released within `update_window_days` of purchase)
+    #[serde(default = "default_update_policy")]
+    pub update_policy: String,
+    #[serde(default)]
+    pub update_window_days: Option<i32>,
+    /// Each purchase gets a license key that the software validates against `/licenses/validate`
+    #[serde(default)]
+    pub license_keys: bool,
+    /// Machines a single key can be activated on; unlimited when unset
+    #[serde(default)]
+    pub max_activations: Option<i32>,
+    /// Consumables (e.g. credit packs) can be bought again by the same customer
+    #[serde(default)]
+    pub allow_repurchase: bool,
+    /// Downloads allowed per purchase; unlimited when unset
+    #[serde(default)]
+    pub download_limit: Option<i32>,
+    /// One of the categories in the tax rules, e.g. digital or ebook
+    #[serde(default = "default_tax_category")]
+    pub tax_category: String,
+    /// Average of the reviews
     pub rating: Option<f64>,
+    #[serde(default)]
+    pub ratings: RatingSummary,
+    /// Average pulled towards `RATING_PRIOR_MEAN` while there are few reviews; used for ranking
+    #[serde(default)]
+    pub weighted_rating: Option<f64>,
+    /// Completed purchases
+    #[serde(default)]
+    pub sales: i32,
+    /// Times buyers actually fetched the file
     pub downloads: i32,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
 }
 
+/// Several of a seller's products sold together for one price.
+///
+/// Buying a bundle places an order with a purchase of each product, the bundle
+/// price split between them in proportion to the products' own prices.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Bundle {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub seller_id: String,
+    pub title: String,
+    pub description: String,
+    pub category: String,
+    pub product_ids: Vec<String>,
+    /// At most the products' combined price, in the same currency
+    pub price: Money,
+    pub icon: Option<String>,
+    /// Completed bundle purchases
+    #[serde(default)]
+    pub sales: i32,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBundleRequest {
+    pub title: String,
+    pub description: String,
+    pub category: String,
+    pub product_ids: Vec<String>,
+    pub price: Money,
+    pub icon: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BundleProduct {
+    pub product_id: String,
+    pub title: String,
+    pub price: Money,
+}
+
+/// Public view of a bundle, listed next to products with `bundle: true`.
+#[derive(Debug, Serialize)]
+pub struct BundleResponse {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub bundle: bool,
+    pub seller_id: String,
+    pub title: String,
+    pub description: String,
+    pub category: String,
+    pub price: Money,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_price: Option<Money>,
+    pub icon: Option<String>,
+    pub products: Vec<BundleProduct>,
+    /// What the products cost bought separately
+    pub products_total: Money,
+    pub sales: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An entry in a product listing: a product or a bundle of products.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum CatalogItem {
+    Product(ProductResponse),
+    Bundle(BundleResponse),
+}
+
+impl CatalogItem {
+    pub fn sales(&self) -> i32 {
+        match self {
+            CatalogItem::Product(p) => p.sales,
+            CatalogItem::Bundle(b) => b.sales,
+        }
+    }
+
+    /// The price shown to the client: converted when a display currency was asked for.
+    pub fn listed_price(&self) -> &Money {
+        let (price, display_price) = match self {
+            CatalogItem::Product(p) => (&p.price, &p.display_price),
+            CatalogItem::Bundle(b) => (&b.price, &b.display_price),
+        };
+        display_price.as_ref().unwrap_or(price)
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        match self {
+            CatalogItem::Product(p) => p.created_at,
+            CatalogItem::Bundle(b) => b.created_at,
+        }
+    }
+
+    /// Bundles aren't reviewed, so they have none.
+    pub fn weighted_rating(&self) -> Option<f64> {
+        match self {
+            CatalogItem::Product(p) => p.weighted_rating,
+            CatalogItem::Bundle(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurchaseBundleRequest {
+    pub payment_method: String,
+    #[serde(default)]
+    pub billing_address: Option<BillingAddress>,
+}
+
+/// Public view of a product. The file URL is only handed out through signed download links.
+#[derive(Debug, Serialize)]
+pub struct ProductResponse {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub seller_id: String,
+    pub title: String,
+    pub description: String,
+    pub category: String,
+    pub price: Money,
+    /// `price` converted to the requested `?currency=`, for display only
+    pub display_price: Option<Money>,
+    pub file_type: String,
+    pub icon: Option<String>,
+    pub image_url: Option<String>,
+    pub current_version: i32,
+    pub update_policy: String,
+    pub update_window_days: Option<i32>,
+    pub license_keys: bool,
+    pub max_activations: Option<i32>,
+    pub allow_repurchase: bool,
+    pub download_limit: Option<i32>,
+    pub rating: Option<f64>,
+    pub ratings: RatingSummary,
+    pub weighted_rating: Option<f64>,
+    pub sales: i32,
+    pub downloads: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Product> for ProductResponse {
+    fn from(product: Product) -> Self {
+        ProductResponse {
+            id: product.id,
+            seller_id: product.seller_id,
+            title: product.title,
+            description: product.description,
+            category: product.category,
+            price: product.price,
+            display_price: None,
+            file_type: product.file_type,
+            icon: product.icon,
+            image_url: product.image_file_id.map(|id| format!("/api/files/{}", id)),
+            current_version: product.current_version,
+            update_policy: product.update_policy,
+            update_window_days: product.update_window_days,
+            license_keys: product.license_keys,
+            max_activations: product.max_activations,
+            allow_repurchase: product.allow_repurchase,
+            download_limit: product.download_limit,
+            rating: product.rating,
+            ratings: product.ratings,
+            weighted_rating: product.weighted_rating,
+            sales: product.sales,
+            downloads: product.downloads,
+            created_at: product.created_at,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateProductRequest {
     pub title: String,
     pub description: String,
     pub category: String,
-    pub price: f64,
+    pub price: Money,
     pub file_type: String,
-    pub file_url: String,
+    /// Uploaded through `POST /uploads/product-file`
+    pub file_id: String,
     pub icon: Option<String>,
+    /// Uploaded through `POST /uploads/product-image`
+    pub image_file_id: Option<String>,
+    /// lifetime (default), none or window
+    pub update_policy: Option<String>,
+    pub update_window_days: Option<i32>,
+    #[serde(default)]
+    pub license_keys: bool,
+    pub max_activations: Option<i32>,
+    #[serde(default)]
+    pub allow_repurchase: bool,
+    pub download_limit: Option<i32>,
+    /// digital (default) or another category from the tax rules
+    pub tax_category: Option<String>,
+}
+
+pub fn default_update_policy() -> String {
+    "lifetime".to_string()
+}
+
+pub fn default_tax_category() -> String {
+    "digital".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProductVersion {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub product_id: String,
+    pub version: i32,
+    pub changelog: String,
+    pub file_id: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub released_at: DateTime<Utc>,
+}
+
+/// Public view of a version; files are only reachable through download links.
+#[derive(Debug, Serialize)]
+pub struct ProductVersionResponse {
+    pub version: i32,
+    pub changelog: String,
+    pub released_at: DateTime<Utc>,
+}
+
+impl From<ProductVersion> for ProductVersionResponse {
+    fn from(version: ProductVersion) -> Self {
+        ProductVersionResponse {
+            version: version.version,
+            changelog: version.changelog,
+            released_at: version.released_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateProductVersionRequest {
+    pub changelog: String,
+    /// Uploaded through `POST /uploads/product-file`
+    pub file_id: String,
+}
+
+/// Metadata for an uploaded object; the bytes live in `storage`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredFile {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub owner_id: String,
+    /// product_file or product_image
+    pub purpose: String,
+    pub original_name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+    pub storage_key: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -107,16 +467,71 @@ pub struct Booking {
     pub booking_time: String,
     pub notes: Option<String>,
     pub status: String,
+    #[serde(default)]
+    pub sequence: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_intent_id: Option<String>,
+    /// Set when the booking is paid through the platform: authorized, paid, failed or refunded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_status: Option<String>,
+    /// Charged through the platform
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Money>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refunded_amount: Option<Money>,
+    /// Paid funds are held until the booking is completed: held, then released or refunded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escrow_status: Option<String>,
+    /// End of the dispute window after completion; the provider is paid after it
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub release_at: Option<DateTime<Utc>>,
+    /// What was passed on to the provider when the escrow was released
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub released_amount: Option<Money>,
+    /// Set on cancellation: the total refunded once the cancellation policy is applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancellation_refund: Option<Money>,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Bookings don't record an end time; every slot is treated as one hour long.
+pub const BOOKING_DURATION_MINUTES: i64 = 60;
+
+impl Booking {
+    /// Start of the booking as entered by the customer (`YYYY-MM-DD` + `HH:MM`).
+    pub fn starts_at(&self) -> Option<NaiveDateTime> {
+        let date = NaiveDate::parse_from_str(&self.booking_date, "%Y-%m-%d").ok()?;
+        let time = NaiveTime::parse_from_str(&self.booking_time, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(&self.booking_time, "%H:%M:%S"))
+            .ok()?;
+        Some(date.and_time(time))
+    }
+
+    /// Booking times carry no timezone; the scheduler interprets them as UTC.
+    pub fn starts_at_utc(&self) -> Option<DateTime<Utc>> {
+        self.starts_at().map(|start| start.and_utc())
+    }
+
+    pub fn ends_at_utc(&self) -> Option<DateTime<Utc>> {
+        self.starts_at_utc()
+            .map(|start| start + chrono::Duration::minutes(BOOKING_DURATION_MINUTES))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CreateBookingRequest {
     pub service_id: String,
     pub booking_date: String,
     pub booking_time: String,
     pub notes: Option<String>,
+    /// Required unless the service is free
+    pub payment_method: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateBookingStatusRequest {
+    pub status: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -126,17 +541,329 @@ pub struct Purchase {
     pub customer_id: String,
     pub product_id: String,
     pub payment_method: String,
-    pub amount: f64,
+    /// Charged total, tax included
+    pub amount: Money,
+    /// Coupons taken off the product's price before tax was added
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discounts: Vec<AppliedDiscount>,
+    /// Tax included in `amount`, one line per jurisdiction
+    #[serde(default)]
+    pub tax_lines: Vec<TaxLine>,
+    /// Version of the tax rules the lines were calculated with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tax_rules_version: Option<String>,
+    /// Refunded so far; a fully refunded purchase moves to `refunded`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refunded_amount: Option<Money>,
+    /// pending → authorized → completed, or failed when the gateway rejects the payment
     pub status: String,
-    pub download_url: String,
+    #[serde(default)]
+    pub payment_intent_id: Option<String>,
+    #[serde(default)]
+    pub failure_reason: Option<String>,
+    /// `customer_id:product_id` while the purchase counts towards ownership of a
+    /// product that can't be bought twice; a unique index enforces one per customer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ownership_key: Option<String>,
+    /// Product version current at purchase time; unset for purchases made before versioning
+    #[serde(default)]
+    pub version: Option<i32>,
+    /// Copied from the product at purchase time
+    #[serde(default)]
+    pub download_limit: Option<i32>,
+    #[serde(default)]
+    pub download_count: i32,
+    /// Issued at purchase time for products with `license_keys`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_key: Option<String>,
+    /// Copied from the product at purchase time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_activations: Option<i32>,
+    #[serde(default)]
+    pub activation_count: i32,
+    /// Set by the seller; revoked keys no longer validate
+    #[serde(default)]
+    pub license_revoked: bool,
+    /// Set when the purchase was bought as part of a cart checkout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<String>,
+    /// Billing details for single-product purchases; orders keep their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub billing_address: Option<BillingAddress>,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
 }
 
+impl Purchase {
+    /// Tax included in `amount`.
+    pub fn tax(&self) -> Result<Money, MoneyError> {
+        Money::sum(&self.amount.currency, self.tax_lines.iter().map(|l| &l.amount))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProductAccessResponse {
+    pub product_id: String,
+    pub owned: bool,
+    pub purchase_id: Option<String>,
+    /// Set when access comes from a subscription rather than a purchase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LibraryItem {
+    /// One of `purchase_id` and `subscription_id` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purchase_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_id: Option<String>,
+    pub purchased_at: DateTime<Utc>,
+    pub license_key: Option<String>,
+    pub product: ProductResponse,
+}
+
 #[derive(Debug, Deserialize)]
+pub struct DownloadLinkQuery {
+    pub expires: i64,
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadEvent {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub purchase_id: String,
+    pub product_id: String,
+    pub customer_id: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CreatePurchaseRequest {
     pub product_id: String,
     pub payment_method: String,
+    /// Determines the tax charged; required
+    #[serde(default)]
+    pub billing_address: Option<BillingAddress>,
+    /// Applied to the product's price before tax
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub coupon_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CartItem {
+    pub product_id: String,
+    pub title: String,
+    /// Price when the item was added; checkout refuses to charge a different one silently
+    pub price: Money,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub added_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Cart {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: String,
+    #[serde(default)]
+    pub items: Vec<CartItem>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddCartItemRequest {
+    pub product_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CartLine {
+    pub product_id: String,
+    pub title: String,
+    pub price: Money,
+    /// `None` when the product has been removed from the catalog
+    pub current_price: Option<Money>,
+    pub price_changed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CartResponse {
+    pub items: Vec<CartLine>,
+    pub total: Money,
+    /// Some item changed price or disappeared since it was added
+    pub has_changes: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckoutRequest {
+    pub payment_method: String,
+    /// Determines the tax charged and is printed on the invoices; required
+    #[serde(default)]
+    pub billing_address: Option<BillingAddress>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BillingAddress {
+    pub name: String,
+    pub line1: String,
+    #[serde(default)]
+    pub line2: Option<String>,
+    pub city: String,
+    pub postal_code: String,
+    /// ISO 3166-1 alpha-2
+    pub country: String,
+    /// State or province code (e.g. `WA`) where taxes differ within the country
+    #[serde(default)]
+    pub region: Option<String>,
+    /// VAT or other tax registration number of a business buyer
+    #[serde(default)]
+    pub tax_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OrderItem {
+    pub product_id: String,
+    pub title: String,
+    pub price: Money,
+    /// Tax charged on top of `price`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tax: Option<Money>,
+    pub purchase_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TaxLine {
+    /// `GB`, `US-WA`, ...
+    pub jurisdiction: String,
+    /// e.g. UK VAT
+    pub name: String,
+    /// vat, gst or sales_tax
+    pub kind: String,
+    pub rate: f64,
+    /// The buyer accounts for the tax themselves, so `amount` is zero
+    #[serde(default)]
+    pub reverse_charge: bool,
+    pub amount: Money,
+}
+
+/// A cart checkout: one payment covering a purchase per line item.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Order {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub customer_id: String,
+    pub items: Vec<OrderItem>,
+    pub total: Money,
+    pub payment_method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub billing_address: Option<BillingAddress>,
+    /// Set when the order is the purchase of a bundle rather than a cart checkout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle_id: Option<String>,
+    /// Mirrors its purchases: pending → authorized → completed, or failed
+    pub status: String,
+    #[serde(default)]
+    pub payment_intent_id: Option<String>,
+    #[serde(default)]
+    pub failure_reason: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InvoiceLine {
+    pub description: String,
+    pub quantity: i32,
+    pub unit_price: Money,
+    pub tax_rate: f64,
+    pub tax_amount: Money,
+    /// Net amount for the line, before tax
+    pub amount: Money,
+}
+
+/// Issued per seller when a sale completes. `sequence` is gap-free per seller.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invoice {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub invoice_number: String,
+    pub sequence: i64,
+    pub seller_id: String,
+    pub seller_name: String,
+    pub customer_id: String,
+    pub customer_name: String,
+    #[serde(default)]
+    pub billing_address: Option<BillingAddress>,
+    #[serde(default)]
+    pub order_id: Option<String>,
+    #[serde(default)]
+    pub purchase_ids: Vec<String>,
+    /// Unset on other invoices, so the unique sparse index skips them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub booking_id: Option<String>,
+    pub lines: Vec<InvoiceLine>,
+    pub subtotal: Money,
+    pub tax_total: Money,
+    pub total: Money,
+    /// Some lines are reverse-charged to a business buyer
+    #[serde(default)]
+    pub reverse_charge: bool,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub issued_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvoiceQuery {
+    /// pdf (default) or json
+    pub format: Option<String>,
+}
+
+/// A machine a license key has been activated on.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LicenseActivation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub license_key: String,
+    pub purchase_id: String,
+    pub product_id: String,
+    /// Opaque identifier chosen by the licensed software, e.g. a hardware fingerprint
+    pub machine_id: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub activated_at: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub last_validated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidateLicenseRequest {
+    pub license_key: String,
+    pub product_id: String,
+    pub machine_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidateLicenseResponse {
+    pub valid: bool,
+    /// Why validation failed: invalid_key, revoked or activation_limit_reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub activations: i32,
+    pub max_activations: Option<i32>,
+}
+
+/// A key as the seller sees it.
+#[derive(Debug, Serialize)]
+pub struct LicenseSummary {
+    pub license_key: String,
+    pub purchase_id: String,
+    pub product_id: String,
+    pub customer_id: String,
+    pub activations: i32,
+    pub max_activations: Option<i32>,
+    pub revoked: bool,
+    pub purchased_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -148,8 +875,26 @@ pub struct Review {
     pub item_type: String,
     pub rating: i32,
     pub comment: String,
+    /// The reviewer bought the product or completed a booking of the service;
+    /// false only for reviews from before this was required
+    #[serde(default)]
+    pub verified: bool,
+    /// Earlier versions of the review, oldest first
+    #[serde(default)]
+    pub edits: Vec<ReviewEdit>,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// A review as it read before the edit made at `edited_at`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReviewEdit {
+    pub rating: i32,
+    pub comment: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub edited_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -160,9 +905,404 @@ pub struct CreateReviewRequest {
     pub comment: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct UpdateReviewRequest {
+    pub rating: i32,
+    pub comment: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notification {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: String,
+    pub kind: String,
+    pub message: String,
+    pub reference_id: Option<String>,
+    pub read: bool,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PaymentEvent {
+    /// The gateway's event id, which makes redelivered events collide on insert.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub gateway: String,
+    pub event_type: String,
+    pub payment_intent_id: Option<String>,
+    pub payload: Document,
+    /// received, processed or failed
+    pub status: String,
+    pub error: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub received_at: DateTime<Utc>,
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub processed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayPaymentEventsRequest {
+    /// Specific events to replay; when omitted every failed or unprocessed event is replayed.
+    pub event_ids: Option<Vec<String>>,
+}
+
+/// A refund of a purchase or platform-paid booking, either requested by the
+/// customer or issued directly by the seller or an admin.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Refund {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// purchase or booking
+    pub kind: String,
+    pub subject_id: String,
+    pub customer_id: String,
+    /// Seller of the product or provider of the service
+    pub seller_id: String,
+    pub amount: Money,
+    pub reason: String,
+    /// requested → processing → succeeded or failed; requests can also be rejected
+    pub status: String,
+    pub requested_by: String,
+    #[serde(default)]
+    pub decided_by: Option<String>,
+    #[serde(default)]
+    pub gateway_refund_id: Option<String>,
+    #[serde(default)]
+    pub failure_reason: Option<String>,
+    #[serde(default)]
+    pub rejection_reason: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub decided_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRefundRequest {
+    /// Minor units in the charge's currency; everything still refundable when omitted
+    pub amount: Option<i64>,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RejectRefundRequest {
+    pub reason: String,
+}
+
+/// A balanced set of ledger entries; entries in each currency sum to zero.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LedgerTransaction {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// sale, subscription_charge, escrow_hold, escrow_release, refund, payout or payout_reversal
+    pub kind: String,
+    /// The purchase, booking, refund or payout the transaction records; unique per kind
+    pub reference_id: String,
+    pub entries: Vec<LedgerEntry>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    /// When a sale's seller share comes out of its holding period and can be paid out
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub available_at: Option<DateTime<Utc>>,
+}
+
+/// Debits are positive and credits negative.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LedgerEntry {
+    /// e.g. `seller:<user id>`, `gateway` or `tax`
+    pub account: String,
+    pub amount: Money,
+}
+
+/// What the platform owes a seller in one currency; `pending` is still in its holding period.
 #[derive(Debug, Serialize)]
-pub struct ApiResponse<T> {
-    pub success: bool,
-    pub data: Option<T>,
-    pub message: Option<String>,
+pub struct SellerBalance {
+    pub currency: String,
+    pub balance: Money,
+    pub pending: Money,
+    pub available: Money,
+}
+
+/// A transfer of a seller's available balance, created by a payout batch.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Payout {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// e.g. `2025-01-20T00:00Z`, the scheduled time of the batch
+    pub batch_id: String,
+    pub seller_id: String,
+    pub amount: Money,
+    /// pending → paid or failed
+    pub status: String,
+    #[serde(default)]
+    pub failure_reason: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub settled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FailPayoutRequest {
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LedgerReconciliation {
+    pub balanced: bool,
+    pub transactions: u64,
+    /// Sum of all entries per currency; zero when the ledger balances
+    pub totals: Vec<Money>,
+    /// Transactions whose own entries don't sum to zero
+    pub unbalanced_transactions: Vec<String>,
+    /// Completed or refunded purchases without a sale transaction
+    pub unrecorded_sales: Vec<String>,
+}
+
+/// A seller's promotional code, taken off the price of their products before tax.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Coupon {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// Stored upper case; unique across the platform
+    pub code: String,
+    /// Only the seller's own products are discounted
+    pub seller_id: String,
+    /// percentage or fixed
+    pub kind: String,
+    /// Share of the price taken off by a percentage coupon, e.g. 0.2 for 20%
+    #[serde(default)]
+    pub rate: Option<f64>,
+    /// Taken off by a fixed coupon; only products priced in its currency qualify
+    #[serde(default)]
+    pub amount_off: Option<Money>,
+    /// Narrow the coupon to these products, categories or niches; empty means any
+    #[serde(default)]
+    pub product_ids: Vec<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    #[serde(default)]
+    pub niches: Vec<String>,
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub starts_at: Option<DateTime<Utc>>,
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub ends_at: Option<DateTime<Utc>>,
+    /// Purchases the code can be used on in total and per customer
+    #[serde(default)]
+    pub max_redemptions: Option<i32>,
+    #[serde(default)]
+    pub max_per_customer: Option<i32>,
+    /// Purchases currently using the code; failed payments give their use back
+    #[serde(default)]
+    pub redemptions: i32,
+    /// Stackable coupons can be combined with each other, others only used alone
+    #[serde(default)]
+    pub stackable: bool,
+    pub active: bool,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCouponRequest {
+    pub code: String,
+    pub kind: String,
+    pub rate: Option<f64>,
+    pub amount_off: Option<Money>,
+    #[serde(default)]
+    pub product_ids: Vec<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    #[serde(default)]
+    pub niches: Vec<String>,
+    pub starts_at: Option<DateTime<Utc>>,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub max_redemptions: Option<i32>,
+    pub max_per_customer: Option<i32>,
+    #[serde(default)]
+    pub stackable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidateCouponRequest {
+    pub product_id: String,
+    pub codes: Vec<String>,
+}
+
+/// A coupon's share of a purchase's discount.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AppliedDiscount {
+    pub coupon_id: String,
+    pub code: String,
+    pub amount: Money,
+}
+
+/// A seller's recurring offer: access to their products for a price per interval.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SubscriptionPlan {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub seller_id: String,
+    pub title: String,
+    pub description: String,
+    /// Charged every interval, before tax
+    pub price: Money,
+    /// month or year
+    pub interval: String,
+    /// Covered products; empty covers every product the seller lists, now or later
+    #[serde(default)]
+    pub product_ids: Vec<String>,
+    #[serde(default = "default_tax_category")]
+    pub tax_category: String,
+    pub active: bool,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSubscriptionPlanRequest {
+    pub title: String,
+    pub description: String,
+    pub price: Money,
+    pub interval: String,
+    #[serde(default)]
+    pub product_ids: Vec<String>,
+    pub tax_category: Option<String>,
+}
+
+/// A customer's subscription to a plan, renewed by the job scheduler.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Subscription {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub plan_id: String,
+    pub seller_id: String,
+    pub customer_id: String,
+    pub payment_method: String,
+    /// Charged each period, tax included; the plan's terms when the customer subscribed
+    pub amount: Money,
+    #[serde(default)]
+    pub tax_lines: Vec<TaxLine>,
+    pub interval: String,
+    /// pending → active ⇄ past_due → cancelled or expired; failed if the first payment fails
+    pub status: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub current_period_start: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub current_period_end: DateTime<Utc>,
+    /// Covered products can be downloaded until then: the period end plus the grace
+    /// period for renewals, or just the period end once cancelled
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub access_until: DateTime<Utc>,
+    #[serde(default)]
+    pub cancel_at_period_end: bool,
+    /// Renewal attempts that failed since the last successful charge
+    #[serde(default)]
+    pub failed_attempts: i32,
+    #[serde(default)]
+    pub failure_reason: Option<String>,
+    /// `customer_id:plan_id` while the subscription is live; a unique index allows one per plan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub live_key: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl Subscription {
+    pub fn grants_access(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.status.as_str(), "active" | "past_due") && now < self.access_until
+    }
+}
+
+/// One billing period's payment for a subscription.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SubscriptionCharge {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub subscription_id: String,
+    pub customer_id: String,
+    pub seller_id: String,
+    pub amount: Money,
+    #[serde(default)]
+    pub tax_lines: Vec<TaxLine>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub period_start: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub period_end: DateTime<Utc>,
+    /// pending → captured → completed, or failed
+    pub status: String,
+    #[serde(default)]
+    pub payment_intent_id: Option<String>,
+    #[serde(default)]
+    pub failure_reason: Option<String>,
+    /// `subscription_id:period_start` until the charge fails; a unique index allows one per period
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period_key: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl SubscriptionCharge {
+    /// Tax included in `amount`.
+    pub fn tax(&self) -> Result<Money, MoneyError> {
+        Money::sum(&self.amount.currency, self.tax_lines.iter().map(|l| &l.amount))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    pub payment_method: String,
+    #[serde(default)]
+    pub billing_address: Option<BillingAddress>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdatePaymentMethodRequest {
+    pub payment_method: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionPlanQuery {
+    pub seller_id: Option<String>,
+}
+
+/// First response to a request sent with an `Idempotency-Key`, replayed to retries.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdempotencyRecord {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: String,
+    pub key: String,
+    pub request_hash: String,
+    /// processing until the response is stored, then completed
+    pub status: String,
+    pub response_status: Option<i32>,
+    pub response_body: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    /// Removed by the TTL index from then on
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Job {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub kind: String,
+    pub payload: Document,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedupe_key: Option<String>,
+    pub status: String,
+    pub attempts: i32,
+    pub locked_by: Option<String>,
+    #[serde(default, with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub locked_until: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub run_at: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
 }
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+
+/// Currencies without minor units, e.g. 500 JPY is stored as 500.
+const ZERO_DECIMAL: &[&str] = &["BIF", "CLP", "DJF", "GNF", "ISK", "JPY", "KMF", "KRW", "PYG", "RWF", "UGX", "VND", "VUV", "XAF", "XOF", "XPF"];
+const THREE_DECIMAL: &[&str] = &["BHD", "IQD", "JOD", "KWD", "LYD", "OMR", "TND"];
+
+/// An exact amount in a currency's minor units (cents for USD), stored in BSON
+/// as `{ amount: Int64, currency: "USD" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Money {
+    pub amount: i64,
+    /// ISO 4217 code, upper case
+    pub currency: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MoneyError {
+    CurrencyMismatch(String, String),
+    Overflow,
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::CurrencyMismatch(a, b) => write!(f, "Cannot combine {} and {} amounts", a, b),
+            MoneyError::Overflow => write!(f, "Amount out of range"),
+        }
+    }
+}
+
+// Money errors inside a transaction abort it like any other write error
+impl From<MoneyError> for mongodb::error::Error {
+    fn from(e: MoneyError) -> Self {
+        mongodb::error::Error::custom(e.to_string())
+    }
+}
+
+impl Money {
+    pub fn new(amount: i64, currency: &str) -> Self {
+        Money { amount, currency: currency.to_uppercase() }
+    }
+
+    pub fn zero(currency: &str) -> Self {
+        Money::new(0, currency)
+    }
+
+    pub fn checked_add(&self, other: &Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        let amount = self.amount.checked_add(other.amount).ok_or(MoneyError::Overflow)?;
+        Ok(Money { amount, currency: self.currency.clone() })
+    }
+
+    pub fn checked_sub(&self, other: &Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        let amount = self.amount.checked_sub(other.amount).ok_or(MoneyError::Overflow)?;
+        Ok(Money { amount, currency: self.currency.clone() })
+    }
+
+    /// Adds up `amounts`, all of which must be in `currency`.
+    pub fn sum<'a>(currency: &str, amounts: impl IntoIterator<Item = &'a Money>) -> Result<Money, MoneyError> {
+        amounts
+            .into_iter()
+            .try_fold(Money::zero(currency), |total, amount| total.checked_add(amount))
+    }
+
+    /// `rate` (e.g. 0.2 for 20%) of the amount, rounded half up to the minor unit.
+    /// Rates are taken to six decimal places.
+    pub fn percentage(&self, rate: f64) -> Result<Money, MoneyError> {
+        let parts_per_million = (rate * 1_000_000.0).round() as i128;
+        let share = (self.amount as i128 * parts_per_million + 500_000).div_euclid(1_000_000);
+        let amount = i64::try_from(share).map_err(|_| MoneyError::Overflow)?;
+        Ok(Money { amount, currency: self.currency.clone() })
+    }
+
+    /// The amount in major units as a plain decimal string, e.g. `15.00`.
+    pub fn to_decimal_string(&self) -> String {
+        let exponent = exponent(&self.currency);
+        if exponent == 0 {
+            return self.amount.to_string();
+        }
+
+        let divisor = 10_i64.pow(exponent);
+        let sign = if self.amount < 0 { "-" } else { "" };
+        let abs = self.amount.unsigned_abs();
+        format!(
+            "{}{}.{:0width$}",
+            sign,
+            abs / divisor as u64,
+            abs % divisor as u64,
+            width = exponent as usize
+        )
+    }
+
+    /// Amounts from clients must be in a supported currency and not negative.
+    pub fn is_valid_price(&self) -> bool {
+        self.amount >= 0 && is_valid_currency(&self.currency)
+    }
+
+    fn same_currency(&self, other: &Money) -> Result<(), MoneyError> {
+        if self.currency == other.currency {
+            Ok(())
+        } else {
+            Err(MoneyError::CurrencyMismatch(self.currency.clone(), other.currency.clone()))
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.to_decimal_string(), self.currency)
+    }
+}
+
+/// Number of minor-unit digits for `currency`.
+pub fn exponent(currency: &str) -> u32 {
+    let currency = currency.to_uppercase();
+    if ZERO_DECIMAL.contains(&currency.as_str()) {
+        0
+    } else if THREE_DECIMAL.contains(&currency.as_str()) {
+        3
+    } else {
+        2
+    }
+}
+
+pub fn is_valid_currency(currency: &str) -> bool {
+    currency.len() == 3 && currency.chars().all(|c| c.is_ascii_uppercase())
+}
+
+/// Currency for amounts recorded before prices carried one (`DEFAULT_CURRENCY`, USD by default).
+pub fn default_currency() -> String {
+    env::var("DEFAULT_CURRENCY")
+        .map(|c| c.to_uppercase())
+        .unwrap_or_else(|_| "USD".to_string())
+}
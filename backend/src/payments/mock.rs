@@ -0,0 +1,157 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+use super::{verify_signed_event, IntentStatus, PaymentError, PaymentGateway, PaymentIntent, Refund, WebhookEvent};
+
+/// In-process gateway for local development and offline checkout testing.
+///
+/// Outcomes are decided by the payment method string so flows are reproducible:
+/// `pm_card_declined` and `pm_card_insufficient_funds` are declined on confirm,
+/// `pm_card_capture_fails` authorizes but fails on capture, anything else succeeds.
+pub struct MockGateway {
+    webhook_secret: String,
+    intents: Mutex<HashMap<String, MockIntent>>,
+}
+
+struct MockIntent {
+    intent: PaymentIntent,
+    payment_method: Option<String>,
+    refunded: i64,
+}
+
+impl MockGateway {
+    pub fn new(webhook_secret: &str) -> Self {
+        MockGateway {
+            webhook_secret: webhook_secret.to_string(),
+            intents: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentGateway for MockGateway {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn create_intent(
+        &self,
+        amount: i64,
+        currency: &str,
+        _metadata: &HashMap<String, String>,
+    ) -> Result<PaymentIntent, PaymentError> {
+        if amount < 0 {
+            return Err(PaymentError::InvalidRequest("Amount must not be negative".to_string()));
+        }
+
+        let intent = PaymentIntent {
+            id: format!("pi_mock_{}", Uuid::new_v4().simple()),
+            amount,
+            currency: currency.to_lowercase(),
+            status: IntentStatus::RequiresConfirmation,
+        };
+
+        self.intents.lock().unwrap().insert(
+            intent.id.clone(),
+            MockIntent {
+                intent: intent.clone(),
+                payment_method: None,
+                refunded: 0,
+            },
+        );
+
+        Ok(intent)
+    }
+
+    async fn confirm(&self, intent_id: &str, payment_method: &str) -> Result<PaymentIntent, PaymentError> {
+        let mut intents = self.intents.lock().unwrap();
+        let entry = intents
+            .get_mut(intent_id)
+            .ok_or_else(|| PaymentError::InvalidRequest(format!("Unknown payment intent {}", intent_id)))?;
+
+        if entry.intent.status != IntentStatus::RequiresConfirmation {
+            return Err(PaymentError::InvalidRequest("Payment intent cannot be confirmed".to_string()));
+        }
+
+        entry.payment_method = Some(payment_method.to_string());
+
+        match payment_method {
+            "pm_card_declined" => {
+                entry.intent.status = IntentStatus::Failed;
+                Err(PaymentError::Declined("Your card was declined".to_string()))
+            }
+            "pm_card_insufficient_funds" => {
+                entry.intent.status = IntentStatus::Failed;
+                Err(PaymentError::Declined("Your card has insufficient funds".to_string()))
+            }
+            _ => {
+                entry.intent.status = IntentStatus::RequiresCapture;
+                Ok(entry.intent.clone())
+            }
+        }
+    }
+
+    async fn capture(&self, intent_id: &str) -> Result<PaymentIntent, PaymentError> {
+        let mut intents = self.intents.lock().unwrap();
+        let entry = intents
+            .get_mut(intent_id)
+            .ok_or_else(|| PaymentError::InvalidRequest(format!("Unknown payment intent {}", intent_id)))?;
+
+        if entry.intent.status != IntentStatus::RequiresCapture {
+            return Err(PaymentError::InvalidRequest("Payment intent is not authorized".to_string()));
+        }
+
+        if entry.payment_method.as_deref() == Some("pm_card_capture_fails") {
+            entry.intent.status = IntentStatus::Failed;
+            return Err(PaymentError::Gateway("Capture failed".to_string()));
+        }
+
+        entry.intent.status = IntentStatus::Succeeded;
+        Ok(entry.intent.clone())
+    }
+
+    async fn cancel(&self, intent_id: &str) -> Result<PaymentIntent, PaymentError> {
+        let mut intents = self.intents.lock().unwrap();
+        let entry = intents
+            .get_mut(intent_id)
+            .ok_or_else(|| PaymentError::InvalidRequest(format!("Unknown payment intent {}", intent_id)))?;
+
+        if entry.intent.status == IntentStatus::Succeeded {
+            return Err(PaymentError::InvalidRequest("Captured payments must be refunded".to_string()));
+        }
+
+        entry.intent.status = IntentStatus::Canceled;
+        Ok(entry.intent.clone())
+    }
+
+    async fn refund(&self, intent_id: &str, amount: Option<i64>) -> Result<Refund, PaymentError> {
+        let mut intents = self.intents.lock().unwrap();
+        let entry = intents
+            .get_mut(intent_id)
+            .ok_or_else(|| PaymentError::InvalidRequest(format!("Unknown payment intent {}", intent_id)))?;
+
+        if entry.intent.status != IntentStatus::Succeeded {
+            return Err(PaymentError::InvalidRequest("Only captured payments can be refunded".to_string()));
+        }
+
+        let refundable = entry.intent.amount - entry.refunded;
+        let amount = amount.unwrap_or(refundable);
+        if amount <= 0 || amount > refundable {
+            return Err(PaymentError::InvalidRequest("Refund exceeds the refundable amount".to_string()));
+        }
+
+        entry.refunded += amount;
+
+        Ok(Refund {
+            id: format!("re_mock_{}", Uuid::new_v4().simple()),
+            payment_intent_id: intent_id.to_string(),
+            amount,
+            status: "succeeded".to_string(),
+        })
+    }
+
+    fn verify_webhook(&self, payload: &[u8], signature: &str) -> Result<WebhookEvent, PaymentError> {
+        verify_signed_event(&self.webhook_secret, payload, signature)
+    }
+}
@@ -0,0 +1,185 @@
+mod mock;
+mod stripe;
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+
+pub use mock::MockGateway;
+pub use stripe::StripeGateway;
+
+/// Signed webhooks older than this are rejected to limit replay attacks.
+const WEBHOOK_TOLERANCE_SECONDS: i64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntentStatus {
+    RequiresConfirmation,
+    RequiresCapture,
+    Succeeded,
+    Failed,
+    Canceled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentIntent {
+    pub id: String,
+    /// Amount in the currency's minor unit (cents).
+    pub amount: i64,
+    pub currency: String,
+    pub status: IntentStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Refund {
+    pub id: String,
+    pub payment_intent_id: String,
+    pub amount: i64,
+    pub status: String,
+}
+
+/// A verified event delivered to the payments webhook.
+#[derive(Debug, Clone)]
+pub struct WebhookEvent {
+    pub id: String,
+    pub event_type: String,
+    pub payment_intent_id: Option<String>,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Debug)]
+pub enum PaymentError {
+    Declined(String),
+    InvalidRequest(String),
+    InvalidSignature,
+    Gateway(String),
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentError::Declined(msg) => write!(f, "Payment declined: {}", msg),
+            PaymentError::InvalidRequest(msg) => write!(f, "Invalid payment request: {}", msg),
+            PaymentError::InvalidSignature => write!(f, "Invalid webhook signature"),
+            PaymentError::Gateway(msg) => write!(f, "Payment gateway error: {}", msg),
+        }
+    }
+}
+
+#[async_trait]
+pub trait PaymentGateway: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn create_intent(
+        &self,
+        amount: i64,
+        currency: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<PaymentIntent, PaymentError>;
+
+    /// Authorizes the intent against a payment method; funds are held, not captured.
+    async fn confirm(&self, intent_id: &str, payment_method: &str) -> Result<PaymentIntent, PaymentError>;
+
+    async fn capture(&self, intent_id: &str) -> Result<PaymentIntent, PaymentError>;
+
+    /// Voids an intent that hasn't been captured, releasing any held funds.
+    async fn cancel(&self, intent_id: &str) -> Result<PaymentIntent, PaymentError>;
+
+    /// Refunds `amount` (or everything still captured when `None`).
+    async fn refund(&self, intent_id: &str, amount: Option<i64>) -> Result<Refund, PaymentError>;
+
+    fn verify_webhook(&self, payload: &[u8], signature: &str) -> Result<WebhookEvent, PaymentError>;
+}
+
+/// Builds the gateway selected by `PAYMENT_GATEWAY` (`mock` or `stripe`).
+///
+/// There is no default: the mock approves every payment, so it has to be chosen
+/// explicitly, and a typo must not quietly fall back to it.
+pub fn from_env() -> Result<Arc<dyn PaymentGateway>, String> {
+    match env::var("PAYMENT_GATEWAY").as_deref() {
+        Ok("stripe") => Ok(Arc::new(StripeGateway::from_env()?)),
+        Ok("mock") => {
+            let secret = required_env("PAYMENT_WEBHOOK_SECRET")?;
+            Ok(Arc::new(MockGateway::new(&secret)))
+        }
+        Ok(other) => Err(format!("PAYMENT_GATEWAY must be mock or stripe, not {:?}", other)),
+        Err(_) => Err("PAYMENT_GATEWAY must be set to mock or stripe".to_string()),
+    }
+}
+
+/// Reads a setting that has no safe default, such as a signing secret.
+pub(crate) fn required_env(name: &str) -> Result<String, String> {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => Ok(value),
+        _ => Err(format!("{} must be set", name)),
+    }
+}
+
+/// Verifies a `t=...,v1=...` signature header and parses the Stripe-shaped event body.
+pub(crate) fn verify_signed_event(secret: &str, payload: &[u8], header: &str) -> Result<WebhookEvent, PaymentError> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.push(value.to_string()),
+            _ => {}
+        }
+    }
+
+    let timestamp = timestamp.ok_or(PaymentError::InvalidSignature)?;
+    if (chrono::Utc::now().timestamp() - timestamp).abs() > WEBHOOK_TOLERANCE_SECONDS {
+        return Err(PaymentError::InvalidSignature);
+    }
+
+    // verify_slice compares in constant time
+    let valid = signatures.iter().any(|sig| {
+        hex::decode(sig)
+            .map(|bytes| signature_mac(secret, timestamp, payload).verify_slice(&bytes).is_ok())
+            .unwrap_or(false)
+    });
+
+    if !valid {
+        return Err(PaymentError::InvalidSignature);
+    }
+
+    let payload: serde_json::Value = serde_json::from_slice(payload)
+        .map_err(|e| PaymentError::InvalidRequest(e.to_string()))?;
+
+    let id = payload["id"]
+        .as_str()
+        .ok_or_else(|| PaymentError::InvalidRequest("Event has no id".to_string()))?
+        .to_string();
+    let event_type = payload["type"]
+        .as_str()
+        .ok_or_else(|| PaymentError::InvalidRequest("Event has no type".to_string()))?
+        .to_string();
+    let object = &payload["data"]["object"];
+    // Refund and charge objects point back at their intent; intent events carry it as id
+    let payment_intent_id = object["payment_intent"]
+        .as_str()
+        .or_else(|| object["id"].as_str().filter(|id| id.starts_with("pi_")))
+        .map(|id| id.to_string());
+
+    Ok(WebhookEvent {
+        id,
+        event_type,
+        payment_intent_id,
+        payload,
+    })
+}
+
+// Stripe signs "<timestamp>.<raw body>" with the endpoint secret
+fn signature_mac(secret: &str, timestamp: i64, payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload);
+    mac
+}
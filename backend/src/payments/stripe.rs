@@ -0,0 +1,175 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use super::{required_env, verify_signed_event, IntentStatus, PaymentError, PaymentGateway, PaymentIntent, Refund, WebhookEvent};
+
+/// Adapter for the Stripe PaymentIntents API (or anything speaking it, e.g. stripe-mock).
+///
+/// Intents are created with `capture_method=manual` so confirm only authorizes.
+pub struct StripeGateway {
+    client: reqwest::Client,
+    api_base: String,
+    secret_key: String,
+    webhook_secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StripeIntent {
+    id: String,
+    amount: i64,
+    currency: String,
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StripeRefund {
+    id: String,
+    payment_intent: String,
+    amount: i64,
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StripeErrorBody {
+    error: StripeError,
+}
+
+#[derive(Debug, Deserialize)]
+struct StripeError {
+    #[serde(rename = "type")]
+    error_type: String,
+    message: Option<String>,
+}
+
+impl StripeGateway {
+    /// Both keys are required: without the webhook secret any request could
+    /// forge payment events, and without the API key every call would fail.
+    pub fn from_env() -> Result<Self, String> {
+        Ok(StripeGateway {
+            client: reqwest::Client::new(),
+            api_base: env::var("STRIPE_API_BASE").unwrap_or_else(|_| "https://api.stripe.com".to_string()),
+            secret_key: required_env("STRIPE_SECRET_KEY")?,
+            webhook_secret: required_env("STRIPE_WEBHOOK_SECRET")?,
+        })
+    }
+
+    async fn post<T: for<'de> Deserialize<'de>>(&self, path: &str, form: &[(String, String)]) -> Result<T, PaymentError> {
+        let response = self
+            .client
+            .post(format!("{}{}", self.api_base.trim_end_matches('/'), path))
+            .bearer_auth(&self.secret_key)
+            .form(form)
+            .send()
+            .await
+            .map_err(|e| PaymentError::Gateway(e.to_string()))?;
+
+        if response.status().is_success() {
+            return response.json::<T>().await.map_err(|e| PaymentError::Gateway(e.to_string()));
+        }
+
+        let status = response.status();
+        let error = response
+            .json::<StripeErrorBody>()
+            .await
+            .map(|body| body.error)
+            .map_err(|_| PaymentError::Gateway(format!("Stripe returned {}", status)))?;
+        let message = error.message.unwrap_or_else(|| error.error_type.clone());
+
+        Err(match error.error_type.as_str() {
+            "card_error" => PaymentError::Declined(message),
+            "invalid_request_error" => PaymentError::InvalidRequest(message),
+            _ => PaymentError::Gateway(message),
+        })
+    }
+}
+
+impl From<StripeIntent> for PaymentIntent {
+    fn from(intent: StripeIntent) -> Self {
+        let status = match intent.status.as_str() {
+            "requires_capture" => IntentStatus::RequiresCapture,
+            "succeeded" => IntentStatus::Succeeded,
+            "canceled" => IntentStatus::Canceled,
+            _ => IntentStatus::RequiresConfirmation,
+        };
+
+        PaymentIntent {
+            id: intent.id,
+            amount: intent.amount,
+            currency: intent.currency,
+            status,
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentGateway for StripeGateway {
+    fn name(&self) -> &'static str {
+        "stripe"
+    }
+
+    async fn create_intent(
+        &self,
+        amount: i64,
+        currency: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<PaymentIntent, PaymentError> {
+        let mut form = vec![
+            ("amount".to_string(), amount.to_string()),
+            ("currency".to_string(), currency.to_lowercase()),
+            ("capture_method".to_string(), "manual".to_string()),
+        ];
+        for (key, value) in metadata {
+            form.push((format!("metadata[{}]", key), value.clone()));
+        }
+
+        self.post::<StripeIntent>("/v1/payment_intents", &form).await.map(Into::into)
+    }
+
+    async fn confirm(&self, intent_id: &str, payment_method: &str) -> Result<PaymentIntent, PaymentError> {
+        let form = vec![("payment_method".to_string(), payment_method.to_string())];
+        let intent: PaymentIntent = self
+            .post::<StripeIntent>(&format!("/v1/payment_intents/{}/confirm", intent_id), &form)
+            .await?
+            .into();
+
+        // Anything other than an authorization (e.g. 3DS required) can't complete server-side
+        if intent.status != IntentStatus::RequiresCapture {
+            return Err(PaymentError::Declined("Payment requires additional authentication".to_string()));
+        }
+
+        Ok(intent)
+    }
+
+    async fn capture(&self, intent_id: &str) -> Result<PaymentIntent, PaymentError> {
+        self.post::<StripeIntent>(&format!("/v1/payment_intents/{}/capture", intent_id), &[])
+            .await
+            .map(Into::into)
+    }
+
+    async fn cancel(&self, intent_id: &str) -> Result<PaymentIntent, PaymentError> {
+        self.post::<StripeIntent>(&format!("/v1/payment_intents/{}/cancel", intent_id), &[])
+            .await
+            .map(Into::into)
+    }
+
+    async fn refund(&self, intent_id: &str, amount: Option<i64>) -> Result<Refund, PaymentError> {
+        let mut form = vec![("payment_intent".to_string(), intent_id.to_string())];
+        if let Some(amount) = amount {
+            form.push(("amount".to_string(), amount.to_string()));
+        }
+
+        let refund = self.post::<StripeRefund>("/v1/refunds", &form).await?;
+
+        Ok(Refund {
+            id: refund.id,
+            payment_intent_id: refund.payment_intent,
+            amount: refund.amount,
+            status: refund.status,
+        })
+    }
+
+    fn verify_webhook(&self, payload: &[u8], signature: &str) -> Result<WebhookEvent, PaymentError> {
+        verify_signed_event(&self.webhook_secret, payload, signature)
+    }
+}
@@ -0,0 +1,175 @@
+//! Scheduled payout batches of sellers' available balances.
+//!
+//! A batch takes what each seller is owed beyond the holding period and posts
+//! it to the ledger as a pending payout. Settling the transfer is recorded by an
+//! admin; a failed payout puts the money back on the seller's balance.
+
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use chrono::{DateTime, TimeZone, Utc};
+use std::env;
+use crate::db::{is_duplicate_key, with_transaction};
+use crate::jobs;
+use crate::ledger;
+use crate::models::{Notification, Payout};
+
+/// Queues the next batch; batches run every `PAYOUT_INTERVAL_HOURS` (default 24).
+pub async fn schedule_next_batch(db: &Database) -> Result<(), mongodb::error::Error> {
+    let interval = env_i64("PAYOUT_INTERVAL_HOURS", 24).max(1) * 3600;
+    let next = (Utc::now().timestamp() / interval + 1) * interval;
+    let run_at: DateTime<Utc> = match Utc.timestamp_opt(next, 0).single() {
+        Some(at) => at,
+        None => return Ok(()),
+    };
+    let batch_id = run_at.format("%Y-%m-%dT%H:%MZ").to_string();
+
+    jobs::schedule(
+        db,
+        "payout_batch",
+        run_at,
+        doc! { "batch_id": &batch_id },
+        Some(format!("payout_batch:{}", batch_id)),
+    )
+    .await
+}
+
+/// Creates a payout for every available balance of at least `PAYOUT_MINIMUM`
+/// minor units. Running a batch again only pays sellers it missed.
+pub async fn run_batch(db: &Database, batch_id: &str) -> Result<usize, mongodb::error::Error> {
+    let minimum = env_i64("PAYOUT_MINIMUM", 1000);
+    let mut created = 0;
+
+    for (seller_id, available) in ledger::payable_balances(db).await? {
+        if available.amount < minimum.max(1) {
+            continue;
+        }
+
+        let payout = Payout {
+            id: Some(ObjectId::new()),
+            batch_id: batch_id.to_string(),
+            seller_id,
+            amount: available,
+            status: "pending".to_string(),
+            failure_reason: None,
+            created_at: Utc::now(),
+            settled_at: None,
+        };
+
+        let db_handle = db.clone();
+        let record = payout.clone();
+        let result = with_transaction(db, move |session| {
+            let db = db_handle.clone();
+            let payout = record.clone();
+            Box::pin(async move {
+                db.collection::<Payout>("payouts")
+                    .insert_one_with_session(&payout, None, &mut *session)
+                    .await?;
+                ledger::record_payout(&db, session, &payout).await
+            })
+        })
+        .await;
+
+        match result {
+            Ok(()) => created += 1,
+            // Already paid in this batch by an earlier attempt
+            Err(e) if is_duplicate_key(&e) => continue,
+            Err(e) => return Err(e),
+        }
+
+        notify(
+            db,
+            &payout,
+            "payout_scheduled",
+            format!("A payout of {} is on its way", payout.amount),
+        )
+        .await;
+    }
+
+    if created > 0 {
+        log::info!("Payout batch {} created {} payouts", batch_id, created);
+    }
+
+    Ok(created)
+}
+
+/// Records that the transfer reached the seller.
+pub async fn mark_paid(db: &Database, payout_oid: ObjectId) -> Result<Option<Payout>, mongodb::error::Error> {
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+
+    db.collection::<Payout>("payouts")
+        .find_one_and_update(
+            doc! { "_id": payout_oid, "status": "pending" },
+            doc! { "$set": { "status": "paid", "settled_at": Utc::now() } },
+            options,
+        )
+        .await
+}
+
+/// Records a failed transfer and returns the amount to the seller's balance.
+pub async fn mark_failed(db: &Database, payout_oid: ObjectId, reason: &str) -> Result<Option<Payout>, mongodb::error::Error> {
+    let db_handle = db.clone();
+    let reason = reason.to_string();
+
+    let failed = with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let reason = reason.clone();
+        Box::pin(async move {
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let payout = db
+                .collection::<Payout>("payouts")
+                .find_one_and_update_with_session(
+                    doc! { "_id": payout_oid, "status": "pending" },
+                    doc! { "$set": { "status": "failed", "failure_reason": &reason, "settled_at": Utc::now() } },
+                    options,
+                    &mut *session,
+                )
+                .await?;
+
+            if let Some(payout) = &payout {
+                ledger::record_payout_reversal(&db, session, payout).await?;
+            }
+
+            Ok(payout)
+        })
+    })
+    .await?;
+
+    if let Some(payout) = &failed {
+        notify(
+            db,
+            payout,
+            "payout_failed",
+            format!("Your payout of {} failed and is back in your balance", payout.amount),
+        )
+        .await;
+    }
+
+    Ok(failed)
+}
+
+async fn notify(db: &Database, payout: &Payout, kind: &str, message: String) {
+    let notification = Notification {
+        id: None,
+        user_id: payout.seller_id.clone(),
+        kind: kind.to_string(),
+        message,
+        reference_id: payout.id.map(|oid| oid.to_hex()),
+        read: false,
+        created_at: Utc::now(),
+    };
+
+    if let Err(e) = db.collection::<Notification>("notifications").insert_one(notification, None).await {
+        log::warn!("Failed to send {} notification: {}", kind, e);
+    }
+}
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
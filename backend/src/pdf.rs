@@ -0,0 +1,115 @@
+//! Just enough PDF to print text documents such as invoices: A4 pages, the
+//! standard Helvetica fonts and straight lines.
+
+pub const PAGE_WIDTH: f32 = 595.0;
+pub const PAGE_HEIGHT: f32 = 842.0;
+
+#[derive(Default)]
+pub struct Page {
+    content: String,
+}
+
+impl Page {
+    pub fn new() -> Self {
+        Page::default()
+    }
+
+    /// Draws `text` with its baseline starting at (`x`, `y`), measured from the bottom-left corner.
+    pub fn text(&mut self, x: f32, y: f32, size: f32, bold: bool, text: &str) {
+        let font = if bold { "F2" } else { "F1" };
+        self.content.push_str(&format!(
+            "BT /{} {} Tf {} {} Td ({}) Tj ET\n",
+            font,
+            size,
+            x,
+            y,
+            escape(text)
+        ));
+    }
+
+    /// Like `text`, but ends at `x` instead of starting there.
+    pub fn text_right(&mut self, x: f32, y: f32, size: f32, bold: bool, text: &str) {
+        self.text(x - text_width(text, size), y, size, bold, text);
+    }
+
+    pub fn line(&mut self, x1: f32, y1: f32, x2: f32, y2: f32) {
+        self.content.push_str(&format!("0.5 w {} {} m {} {} l S\n", x1, y1, x2, y2));
+    }
+}
+
+/// Serializes the pages into a complete PDF file.
+pub fn render(pages: &[Page]) -> Vec<u8> {
+    // Fixed objects: 1 catalog, 2 page tree, 3 regular font, 4 bold font;
+    // then a page object and a content stream per page
+    let page_ids: Vec<usize> = (0..pages.len()).map(|i| 5 + i * 2).collect();
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{} 0 R", id)).collect();
+
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), pages.len()),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_string(),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_string(),
+    ];
+
+    for (page, id) in pages.iter().zip(&page_ids) {
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+            PAGE_WIDTH,
+            PAGE_HEIGHT,
+            id + 1
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            page.content.len(),
+            page.content
+        ));
+    }
+
+    let mut out = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+    }
+
+    let xref_offset = out.len();
+    out.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_offset
+        )
+        .as_bytes(),
+    );
+
+    out
+}
+
+// The standard fonts only cover Latin-1 here, and string delimiters need escaping
+fn escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '(' | ')' | '\\' => format!("\\{}", c),
+            c if (' '..='~').contains(&c) => c.to_string(),
+            c if ('\u{a0}'..='\u{ff}').contains(&c) => format!("\\{:03o}", c as u32),
+            _ => "?".to_string(),
+        })
+        .collect()
+}
+
+// Rough Helvetica metrics, good enough for right-aligning numbers
+fn text_width(text: &str, size: f32) -> f32 {
+    text.chars()
+        .map(|c| match c {
+            '.' | ',' | ' ' => 0.278,
+            '0'..='9' | '$' => 0.556,
+            'A'..='Z' => 0.667,
+            _ => 0.5,
+        })
+        .sum::<f32>()
+        * size
+}
@@ -0,0 +1,581 @@
+//! Refunds of purchases and platform-paid bookings through the payment gateway.
+//!
+//! The refunded amount is reserved on the purchase or booking before the
+//! gateway is called, so concurrent refunds can never exceed what was charged.
+
+use mongodb::{Database, bson::{doc, oid::ObjectId, Bson, Document}};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use crate::db::{is_duplicate_key, with_transaction};
+use crate::ledger;
+use crate::models::{Booking, Notification, Product, Purchase, Refund, Service};
+use crate::money::{Money, MoneyError};
+use crate::payments::{PaymentError, PaymentGateway};
+
+pub const KIND_PURCHASE: &str = "purchase";
+pub const KIND_BOOKING: &str = "booking";
+
+#[derive(Debug)]
+pub enum RefundError {
+    NotFound,
+    InvalidAmount(String),
+    NotRefundable(String),
+    Payment(PaymentError),
+    Database(mongodb::error::Error),
+}
+
+impl fmt::Display for RefundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefundError::NotFound => write!(f, "Not found"),
+            RefundError::InvalidAmount(msg) | RefundError::NotRefundable(msg) => write!(f, "{}", msg),
+            RefundError::Payment(e) => write!(f, "{}", e),
+            RefundError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for RefundError {
+    fn from(e: mongodb::error::Error) -> Self {
+        RefundError::Database(e)
+    }
+}
+
+impl From<MoneyError> for RefundError {
+    fn from(e: MoneyError) -> Self {
+        RefundError::InvalidAmount(e.to_string())
+    }
+}
+
+/// A purchase or booking payment that refunds are taken from.
+pub struct Charge {
+    pub kind: &'static str,
+    pub subject_oid: ObjectId,
+    pub customer_id: String,
+    pub seller_id: String,
+    payment_intent_id: Option<String>,
+    /// Charged total, tax included
+    amount: Money,
+    tax: Money,
+    /// Platform commission taken on the sale
+    commission: Money,
+    /// End of the sale's holding period
+    available_at: Option<DateTime<Utc>>,
+    refunded: Money,
+    /// What a booking's escrow passed on to the provider
+    released: Option<Money>,
+    paid: bool,
+}
+
+impl Charge {
+    fn collection(&self) -> &'static str {
+        if self.kind == KIND_PURCHASE { "purchases" } else { "bookings" }
+    }
+
+    fn status_field(&self) -> &'static str {
+        if self.kind == KIND_PURCHASE { "status" } else { "payment_status" }
+    }
+
+    fn paid_status(&self) -> &'static str {
+        if self.kind == KIND_PURCHASE { "completed" } else { "paid" }
+    }
+
+    fn refundable(&self) -> Result<Money, MoneyError> {
+        self.amount.checked_sub(&self.refunded)
+    }
+
+    // Tax and commission are refunded in proportion to the gross amount; working from
+    // the running total means a series of partial refunds ends up returning exactly all of it.
+    fn share_of(&self, part: &Money, amount: &Money) -> Result<Money, MoneyError> {
+        // Commission on a booking was only taken on what its escrow released, and refunds
+        // made before the release came out of the escrow
+        let gross = self.released.as_ref().unwrap_or(&self.amount);
+        let before = self.refunded.checked_sub(&self.amount.checked_sub(gross)?)?;
+        let after = before.checked_add(amount)?;
+        let share = refund_share(before.amount, after.amount, part.amount, gross.amount);
+        Ok(Money::new(share, &self.amount.currency))
+    }
+}
+
+/// The part of `part` (tax or commission on a `gross` charge) that goes back with a
+/// refund taking the refunded total from `before` to `after`.
+pub fn refund_share(before: i64, after: i64, part: i64, gross: i64) -> i64 {
+    proportional(after, part, gross) - proportional(before, part, gross)
+}
+
+fn proportional(refunded: i64, part: i64, gross: i64) -> i64 {
+    if gross <= 0 {
+        return 0;
+    }
+    let (refunded, part, gross) = (refunded as i128, part as i128, gross as i128);
+    ((2 * refunded * part + gross) / (2 * gross)) as i64
+}
+
+/// Loads the purchase or booking `subject_id` together with who sold it.
+pub async fn load_charge(db: &Database, kind: &str, subject_id: &str) -> Result<Charge, RefundError> {
+    let subject_oid = ObjectId::parse_str(subject_id).map_err(|_| RefundError::NotFound)?;
+    let paid_out_by = if kind == KIND_PURCHASE { "sale" } else { "escrow_release" };
+    let sale = ledger::find_transaction(db, paid_out_by, subject_id).await?;
+    let available_at = sale.as_ref().and_then(|s| s.available_at);
+    let commission = sale.as_ref().and_then(ledger::commission_of);
+
+    if kind == KIND_PURCHASE {
+        let purchase = db
+            .collection::<Purchase>("purchases")
+            .find_one(doc! { "_id": subject_oid }, None)
+            .await?
+            .ok_or(RefundError::NotFound)?;
+        let product_oid = ObjectId::parse_str(&purchase.product_id).map_err(|_| RefundError::NotFound)?;
+        let product = db
+            .collection::<Product>("products")
+            .find_one(doc! { "_id": product_oid }, None)
+            .await?
+            .ok_or(RefundError::NotFound)?;
+
+        let currency = purchase.amount.currency.clone();
+        return Ok(Charge {
+            kind: KIND_PURCHASE,
+            subject_oid,
+            seller_id: product.seller_id,
+            tax: purchase.tax()?,
+            commission: commission.unwrap_or_else(|| Money::zero(&currency)),
+            available_at,
+            refunded: purchase.refunded_amount.clone().unwrap_or_else(|| Money::zero(&currency)),
+            released: None,
+            paid: purchase.status == "completed",
+            customer_id: purchase.customer_id,
+            payment_intent_id: purchase.payment_intent_id,
+            amount: purchase.amount,
+        });
+    }
+
+    let booking = db
+        .collection::<Booking>("bookings")
+        .find_one(doc! { "_id": subject_oid }, None)
+        .await?
+        .ok_or(RefundError::NotFound)?;
+    let service_oid = ObjectId::parse_str(&booking.service_id).map_err(|_| RefundError::NotFound)?;
+    let service = db
+        .collection::<Service>("services")
+        .find_one(doc! { "_id": service_oid }, None)
+        .await?
+        .ok_or(RefundError::NotFound)?;
+    let amount = booking
+        .amount
+        .ok_or_else(|| RefundError::NotRefundable("Booking was not paid through the platform".to_string()))?;
+
+    Ok(Charge {
+        kind: KIND_BOOKING,
+        subject_oid,
+        customer_id: booking.customer_id,
+        seller_id: service.provider_id,
+        payment_intent_id: booking.payment_intent_id,
+        tax: Money::zero(&amount.currency),
+        commission: commission.unwrap_or_else(|| Money::zero(&amount.currency)),
+        available_at,
+        refunded: booking.refunded_amount.unwrap_or_else(|| Money::zero(&amount.currency)),
+        released: booking.released_amount,
+        paid: booking.payment_status.as_deref() == Some("paid"),
+        amount,
+    })
+}
+
+/// Records a customer's request; the seller (or an admin) approves or rejects it.
+pub async fn request(
+    db: &Database,
+    charge: &Charge,
+    amount: Option<i64>,
+    reason: &str,
+) -> Result<Refund, RefundError> {
+    let amount = refund_amount(charge, amount)?;
+    let mut refund = new_refund(charge, amount, reason, &charge.customer_id, "requested");
+
+    // A partial unique index allows one open request per purchase or booking
+    match db.collection::<Refund>("refunds").insert_one(&refund, None).await {
+        Ok(result) => refund.id = result.inserted_id.as_object_id(),
+        Err(e) if is_duplicate_key(&e) => {
+            return Err(RefundError::NotRefundable(format!("A refund request for this {} is already open", charge.kind)));
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    notify(
+        db,
+        &charge.seller_id,
+        "refund_requested",
+        format!("A customer requested a refund of {}: {}", refund.amount, refund.reason),
+        &refund,
+    )
+    .await;
+
+    Ok(refund)
+}
+
+/// Refunds straight away on behalf of the seller or an admin.
+pub async fn issue(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    charge: &Charge,
+    amount: Option<i64>,
+    reason: &str,
+    actor: &str,
+) -> Result<Refund, RefundError> {
+    let amount = refund_amount(charge, amount)?;
+    let mut refund = new_refund(charge, amount, reason, actor, "processing");
+    refund.decided_by = Some(actor.to_string());
+    refund.decided_at = Some(Utc::now());
+
+    let result = db.collection::<Refund>("refunds").insert_one(&refund, None).await?;
+    refund.id = result.inserted_id.as_object_id();
+
+    execute(db, gateway, refund, charge).await
+}
+
+/// Approves a pending request and pays it out.
+pub async fn approve(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    refund_oid: ObjectId,
+    actor: &str,
+) -> Result<Refund, RefundError> {
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let refund = db
+        .collection::<Refund>("refunds")
+        .find_one_and_update(
+            doc! { "_id": refund_oid, "status": "requested" },
+            doc! { "$set": { "status": "processing", "decided_by": actor, "decided_at": Utc::now() } },
+            options,
+        )
+        .await?
+        .ok_or_else(|| RefundError::NotRefundable("Refund is no longer pending".to_string()))?;
+
+    let charge = match load_charge(db, &refund.kind, &refund.subject_id).await {
+        Ok(charge) => charge,
+        Err(e) => {
+            mark_failed(db, refund_oid, &e.to_string()).await?;
+            return Err(e);
+        }
+    };
+
+    execute(db, gateway, refund, &charge).await
+}
+
+pub async fn reject(db: &Database, refund_oid: ObjectId, actor: &str, reason: &str) -> Result<Refund, RefundError> {
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let refund = db
+        .collection::<Refund>("refunds")
+        .find_one_and_update(
+            doc! { "_id": refund_oid, "status": "requested" },
+            doc! { "$set": {
+                "status": "rejected",
+                "rejection_reason": reason,
+                "decided_by": actor,
+                "decided_at": Utc::now()
+            } },
+            options,
+        )
+        .await?
+        .ok_or_else(|| RefundError::NotRefundable("Refund is no longer pending".to_string()))?;
+
+    notify(
+        db,
+        &refund.customer_id,
+        "refund_rejected",
+        format!("Your refund request was declined: {}", reason),
+        &refund,
+    )
+    .await;
+
+    Ok(refund)
+}
+
+async fn execute(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    refund: Refund,
+    charge: &Charge,
+) -> Result<Refund, RefundError> {
+    let refund_oid = refund.id.ok_or(RefundError::NotFound)?;
+
+    let refundable = charge.refundable()?;
+    if !charge.paid || refund.amount.currency != refundable.currency || refund.amount.amount > refundable.amount {
+        let message = "The refund exceeds what is left to refund".to_string();
+        mark_failed(db, refund_oid, &message).await?;
+        return Err(RefundError::NotRefundable(message));
+    }
+
+    let intent_id = match &charge.payment_intent_id {
+        Some(id) => id.clone(),
+        None => {
+            let message = "There is no payment to refund".to_string();
+            mark_failed(db, refund_oid, &message).await?;
+            return Err(RefundError::NotRefundable(message));
+        }
+    };
+
+    let refunded_after = reserve(db, charge, &refund, refund_oid).await?;
+
+    let gateway_refund = match gateway.refund(&intent_id, Some(refund.amount.amount)).await {
+        Ok(r) if r.status == "failed" || r.status == "canceled" => Err(PaymentError::Declined(format!("Refund {} {}", r.id, r.status))),
+        other => other,
+    };
+    let gateway_refund = match gateway_refund {
+        Ok(r) => r,
+        Err(e) => {
+            release(db, charge, &refund).await?;
+            mark_failed(db, refund_oid, &e.to_string()).await?;
+            return Err(RefundError::Payment(e));
+        }
+    };
+
+    let gateway_refund_id = gateway_refund.id;
+    match record(db, charge, refund, &refunded_after, Some(gateway_refund_id.clone())).await {
+        Ok(completed) => Ok(completed),
+        // The money has already gone back to the customer, so this needs a person to look at it
+        Err(e) => {
+            log::error!("Refund {} succeeded at the gateway but was not recorded: {}", gateway_refund_id, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Records a refund made at the gateway itself (e.g. from its dashboard) for
+/// whatever the platform hasn't refunded yet. Nothing is recorded when the
+/// charge is already fully refunded, so repeated events are harmless.
+pub async fn record_gateway_refund(
+    db: &Database,
+    charge: &Charge,
+    gateway_refund_id: Option<String>,
+) -> Result<Option<Refund>, RefundError> {
+    let amount = charge.refundable()?;
+    if !charge.paid || amount.amount <= 0 {
+        return Ok(None);
+    }
+
+    let mut refund = new_refund(charge, amount, "Refunded at the payment gateway", "gateway", "processing");
+    refund.decided_by = Some("gateway".to_string());
+    refund.decided_at = Some(Utc::now());
+
+    let result = db.collection::<Refund>("refunds").insert_one(&refund, None).await?;
+    let refund_oid = result.inserted_id.as_object_id().ok_or(RefundError::NotFound)?;
+    refund.id = Some(refund_oid);
+
+    let refunded_after = reserve(db, charge, &refund, refund_oid).await?;
+    match record(db, charge, refund.clone(), &refunded_after, gateway_refund_id).await {
+        Ok(completed) => Ok(Some(completed)),
+        // Nothing was recorded, so undo the reservation and let the event be retried
+        Err(e) => {
+            release(db, charge, &refund).await?;
+            mark_failed(db, refund_oid, &e.to_string()).await?;
+            Err(e.into())
+        }
+    }
+}
+
+// Reserves the refund on the purchase or booking, only if nobody else refunded in
+// the meantime, and returns the refunded total after it.
+async fn reserve(db: &Database, charge: &Charge, refund: &Refund, refund_oid: ObjectId) -> Result<Money, RefundError> {
+    let mut filter = doc! { "_id": charge.subject_oid, charge.status_field(): charge.paid_status() };
+    if charge.refunded.amount == 0 {
+        filter.insert("$or", vec![
+            doc! { "refunded_amount": Bson::Null },
+            doc! { "refunded_amount.amount": 0_i64 },
+        ]);
+    } else {
+        filter.insert("refunded_amount.amount", charge.refunded.amount);
+    }
+    let refunded_after = charge.refunded.checked_add(&refund.amount)?;
+    let reserved = db
+        .collection::<Document>(charge.collection())
+        .update_one(
+            filter,
+            doc! { "$set": { "refunded_amount": { "amount": refunded_after.amount, "currency": &refunded_after.currency } } },
+            None,
+        )
+        .await?;
+    if reserved.modified_count == 0 {
+        let message = "The payment changed while refunding; try again".to_string();
+        mark_failed(db, refund_oid, &message).await?;
+        return Err(RefundError::NotRefundable(message));
+    }
+
+    Ok(refunded_after)
+}
+
+async fn release(db: &Database, charge: &Charge, refund: &Refund) -> Result<(), mongodb::error::Error> {
+    db.collection::<Document>(charge.collection())
+        .update_one(
+            doc! { "_id": charge.subject_oid },
+            doc! { "$inc": { "refunded_amount.amount": -refund.amount.amount } },
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+// Marks a reserved refund as paid out: the refund, the purchase or booking and the
+// ledger are updated together, then the customer is told.
+async fn record(
+    db: &Database,
+    charge: &Charge,
+    refund: Refund,
+    refunded_after: &Money,
+    gateway_refund_id: Option<String>,
+) -> Result<Refund, mongodb::error::Error> {
+    let refund_oid = refund.id.ok_or_else(|| mongodb::error::Error::custom("Refund has no id"))?;
+    let fully_refunded = refunded_after.amount >= charge.amount.amount;
+    let tax_share = charge.share_of(&charge.tax, &refund.amount).map_err(mongodb::error::Error::custom)?;
+    let commission_share = charge.share_of(&charge.commission, &refund.amount).map_err(mongodb::error::Error::custom)?;
+    let available_at = charge.available_at;
+    let completed = Refund {
+        status: "succeeded".to_string(),
+        gateway_refund_id,
+        ..refund
+    };
+
+    let db_handle = db.clone();
+    let record = completed.clone();
+    let (collection, status_field, paid_status, kind, subject_oid) = (
+        charge.collection(),
+        charge.status_field(),
+        charge.paid_status(),
+        charge.kind,
+        charge.subject_oid,
+    );
+    with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        let refund = record.clone();
+        let tax_share = tax_share.clone();
+        let commission_share = commission_share.clone();
+        Box::pin(async move {
+            db.collection::<Refund>("refunds")
+                .update_one_with_session(
+                    doc! { "_id": refund_oid, "status": "processing" },
+                    doc! { "$set": { "status": "succeeded", "gateway_refund_id": &refund.gateway_refund_id } },
+                    None,
+                    &mut *session,
+                )
+                .await?;
+
+            // Bookings not yet completed still have their payment in escrow
+            let in_escrow = kind == KIND_BOOKING
+                && db
+                    .collection::<Document>(collection)
+                    .find_one_with_session(doc! { "_id": subject_oid, "escrow_status": "held" }, None, &mut *session)
+                    .await?
+                    .is_some();
+
+            if fully_refunded {
+                let mut update = doc! { "$set": { status_field: "refunded" } };
+                // Fully refunded purchases lose access and no longer count as owning the product
+                if kind == KIND_PURCHASE {
+                    update.insert("$unset", doc! { "ownership_key": "" });
+                }
+                if in_escrow {
+                    update.insert("$set", doc! { status_field: "refunded", "escrow_status": "refunded" });
+                }
+                db.collection::<Document>(collection)
+                    .update_one_with_session(
+                        doc! { "_id": subject_oid, status_field: paid_status },
+                        update,
+                        None,
+                        &mut *session,
+                    )
+                    .await?;
+            }
+
+            if in_escrow {
+                ledger::record_escrow_refund(&db, session, &refund).await
+            } else {
+                ledger::record_refund(&db, session, &refund, &tax_share, &commission_share, available_at).await
+            }
+        })
+    })
+    .await?;
+
+    notify(
+        db,
+        &completed.customer_id,
+        "refund_processed",
+        format!("You have been refunded {}", completed.amount),
+        &completed,
+    )
+    .await;
+
+    Ok(completed)
+}
+
+fn refund_amount(charge: &Charge, requested: Option<i64>) -> Result<Money, RefundError> {
+    if !charge.paid {
+        return Err(RefundError::NotRefundable(
+            "Only completed purchases and paid bookings can be refunded".to_string(),
+        ));
+    }
+
+    let refundable = charge.refundable()?;
+    if refundable.amount <= 0 {
+        return Err(RefundError::NotRefundable("Already fully refunded".to_string()));
+    }
+
+    let amount = requested.unwrap_or(refundable.amount);
+    if amount <= 0 || amount > refundable.amount {
+        return Err(RefundError::InvalidAmount(format!(
+            "amount must be between 1 and {} (minor units of {})",
+            refundable.amount, refundable.currency
+        )));
+    }
+
+    Ok(Money::new(amount, &refundable.currency))
+}
+
+fn new_refund(charge: &Charge, amount: Money, reason: &str, requested_by: &str, status: &str) -> Refund {
+    Refund {
+        id: None,
+        kind: charge.kind.to_string(),
+        subject_id: charge.subject_oid.to_hex(),
+        customer_id: charge.customer_id.clone(),
+        seller_id: charge.seller_id.clone(),
+        amount,
+        reason: reason.to_string(),
+        status: status.to_string(),
+        requested_by: requested_by.to_string(),
+        decided_by: None,
+        gateway_refund_id: None,
+        failure_reason: None,
+        rejection_reason: None,
+        created_at: Utc::now(),
+        decided_at: None,
+    }
+}
+
+async fn mark_failed(db: &Database, refund_oid: ObjectId, reason: &str) -> Result<(), mongodb::error::Error> {
+    db.collection::<Refund>("refunds")
+        .update_one(
+            doc! { "_id": refund_oid },
+            doc! { "$set": { "status": "failed", "failure_reason": reason } },
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+async fn notify(db: &Database, user_id: &str, kind: &str, message: String, refund: &Refund) {
+    let notification = Notification {
+        id: None,
+        user_id: user_id.to_string(),
+        kind: kind.to_string(),
+        message,
+        reference_id: refund.id.map(|oid| oid.to_hex()),
+        read: false,
+        created_at: Utc::now(),
+    };
+
+    if let Err(e) = db.collection::<Notification>("notifications").insert_one(notification, None).await {
+        log::warn!("Failed to send {} notification: {}", kind, e);
+    }
+}
@@ -0,0 +1,275 @@
+//! Keeps the ratings shown on services and products in line with their reviews.
+//!
+//! Each review change adjusts the item's `ratings` counters and derives `rating`
+//! and `weighted_rating` from them in a single pipeline update, so concurrent
+//! reviews of the same item never lose an update. A scheduled repair job
+//! rebuilds the counters from the reviews themselves.
+
+use mongodb::{Database, bson::{self, doc, oid::ObjectId, Document}};
+use mongodb::options::{FindOneOptions, FindOptions};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::stream::TryStreamExt;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use crate::jobs;
+use crate::models::RatingSummary;
+
+const ITEM_COLLECTIONS: [(&str, &str); 2] = [("service", "services"), ("product", "products")];
+
+/// Applies a review change to the item's counters: `removed` is the rating that
+/// no longer counts (an edited or deleted review), `added` the one that now does.
+pub async fn record(
+    db: &Database,
+    item_type: &str,
+    item_id: &str,
+    removed: Option<i32>,
+    added: Option<i32>,
+) -> Result<(), mongodb::error::Error> {
+    let (collection, item_oid) = match item(item_type, item_id) {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+
+    let mut deltas: BTreeMap<String, i64> = BTreeMap::new();
+    for (rating, sign) in [(removed, -1), (added, 1)] {
+        if let Some(rating) = rating {
+            *deltas.entry("ratings.count".to_string()).or_default() += sign;
+            *deltas.entry("ratings.sum".to_string()).or_default() += sign * rating as i64;
+            *deltas.entry(format!("ratings.histogram.{}", rating)).or_default() += sign;
+        }
+    }
+    if deltas.values().all(|delta| *delta == 0) {
+        return Ok(());
+    }
+    // `$inc` as a pipeline stage, so the ratings are derived in the same write
+    let counters: Document = deltas
+        .into_iter()
+        .map(|(field, delta)| {
+            let current = doc! { "$ifNull": [format!("${}", field), 0_i64] };
+            (field, doc! { "$add": [current, delta] }.into())
+        })
+        .collect();
+
+    db.collection::<Document>(collection)
+        .update_one(doc! { "_id": item_oid }, vec![doc! { "$set": counters }, derived_ratings()], None)
+        .await
+        .map(|_| ())
+}
+
+/// Rebuilds one item's counters from its reviews.
+pub async fn rebuild(db: &Database, item_type: &str, item_id: &str) -> Result<(), mongodb::error::Error> {
+    let (collection, item_oid) = match item(item_type, item_id) {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+
+    let options = FindOneOptions::builder().projection(doc! { "ratings": 1 }).build();
+    let stored = match db.collection::<Document>(collection).find_one(doc! { "_id": item_oid }, options).await? {
+        Some(stored) => stored,
+        None => return Ok(()),
+    };
+    let summary = summarize(db, doc! { "item_type": item_type, "item_id": item_id })
+        .await?
+        .remove(&(item_type.to_string(), item_id.to_string()))
+        .unwrap_or_default();
+
+    set_summary(db, collection, &stored, &summary).await.map(|_| ())
+}
+
+/// Rebuilds the counters of every service and product whose stored counters
+/// don't match their reviews, returning how many were fixed.
+pub async fn repair(db: &Database) -> Result<usize, mongodb::error::Error> {
+    // Items are read before the reviews, so a review that lands in between changes
+    // the stored counters and the item is skipped rather than overwritten
+    let mut stored_items = Vec::new();
+    for (item_type, collection) in ITEM_COLLECTIONS {
+        let options = FindOptions::builder().projection(doc! { "ratings": 1 }).build();
+        let items: Vec<Document> = db
+            .collection::<Document>(collection)
+            .find(doc! {}, options)
+            .await?
+            .try_collect()
+            .await?;
+        stored_items.push((item_type, collection, items));
+    }
+
+    let mut summaries = summarize(db, doc! {}).await?;
+    let mut fixed = 0;
+
+    for (item_type, collection, items) in stored_items {
+        for stored in items {
+            let item_oid = match stored.get_object_id("_id") {
+                Ok(oid) => oid,
+                Err(_) => continue,
+            };
+            let expected = summaries
+                .remove(&(item_type.to_string(), item_oid.to_hex()))
+                .unwrap_or_default();
+            let current = stored
+                .get_document("ratings")
+                .ok()
+                .and_then(|d| bson::from_document::<RatingSummary>(d.clone()).ok())
+                .map(without_empty_stars);
+
+            if current.as_ref() != Some(&expected) && set_summary(db, collection, &stored, &expected).await? {
+                fixed += 1;
+            }
+        }
+    }
+
+    Ok(fixed)
+}
+
+/// Queues the next rating repair every `RATING_REPAIR_INTERVAL_HOURS`.
+pub async fn schedule_repair(db: &Database) -> Result<(), mongodb::error::Error> {
+    let interval = env::var("RATING_REPAIR_INTERVAL_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(24)
+        .max(1)
+        * 3600;
+    let next = (Utc::now().timestamp() / interval + 1) * interval;
+    let run_at: DateTime<Utc> = match Utc.timestamp_opt(next, 0).single() {
+        Some(at) => at,
+        None => return Ok(()),
+    };
+
+    jobs::schedule(
+        db,
+        "rating_repair",
+        run_at,
+        doc! {},
+        Some(format!("rating_repair:{}", run_at.format("%Y-%m-%dT%H:%MZ"))),
+    )
+    .await
+}
+
+/// Whether an item predates the counters; they are rebuilt once on startup.
+pub async fn needs_backfill(db: &Database) -> Result<bool, mongodb::error::Error> {
+    for (_, collection) in ITEM_COLLECTIONS {
+        if db
+            .collection::<Document>(collection)
+            .find_one(doc! { "ratings": { "$exists": false } }, None)
+            .await?
+            .is_some()
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Counters per (item_type, item_id) for the reviews matching `filter`.
+async fn summarize(
+    db: &Database,
+    filter: Document,
+) -> Result<HashMap<(String, String), RatingSummary>, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! { "$group": {
+            "_id": { "item_type": "$item_type", "item_id": "$item_id", "rating": "$rating" },
+            "count": { "$sum": 1_i64 },
+        } },
+    ];
+    let groups: Vec<Document> = db
+        .collection::<Document>("reviews")
+        .aggregate(pipeline, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut summaries: HashMap<(String, String), RatingSummary> = HashMap::new();
+    for group in groups {
+        let key = match group.get_document("_id") {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        let (item_type, item_id, rating) = match (key.get_str("item_type"), key.get_str("item_id"), key.get_i32("rating")) {
+            (Ok(item_type), Ok(item_id), Ok(rating)) => (item_type, item_id, rating),
+            _ => continue,
+        };
+        let count = group.get_i64("count").unwrap_or(0);
+
+        let summary = summaries.entry((item_type.to_string(), item_id.to_string())).or_default();
+        summary.count += count;
+        summary.sum += count * rating as i64;
+        *summary.histogram.entry(rating.to_string()).or_default() += count;
+    }
+
+    Ok(summaries)
+}
+
+// Replaces the counters read in `stored`, unless a review changed them since;
+// returns whether they were replaced.
+async fn set_summary(
+    db: &Database,
+    collection: &str,
+    stored: &Document,
+    summary: &RatingSummary,
+) -> Result<bool, mongodb::error::Error> {
+    let item_oid = stored.get_object_id("_id").map_err(mongodb::error::Error::custom)?;
+    let mut filter = doc! { "_id": item_oid };
+    match stored.get("ratings") {
+        Some(ratings) => filter.insert("ratings", ratings.clone()),
+        None => filter.insert("ratings", doc! { "$exists": false }),
+    };
+
+    let summary = bson::to_document(summary).map_err(mongodb::error::Error::custom)?;
+    db.collection::<Document>(collection)
+        .update_one(
+            filter,
+            vec![doc! { "$set": { "ratings": { "$literal": summary } } }, derived_ratings()],
+            None,
+        )
+        .await
+        .map(|result| result.modified_count == 1)
+}
+
+// Pipeline stage setting `rating` and `weighted_rating` from the stored counters.
+// The weighted rating is a Bayesian average: each item starts with
+// RATING_PRIOR_WEIGHT imaginary reviews of RATING_PRIOR_MEAN stars, so a single
+// five-star review doesn't outrank a hundred four-star ones.
+fn derived_ratings() -> Document {
+    let prior_mean = env_f64("RATING_PRIOR_MEAN", 3.0);
+    let prior_weight = env_f64("RATING_PRIOR_WEIGHT", 5.0).max(0.0);
+    let count = doc! { "$ifNull": ["$ratings.count", 0] };
+    let sum = doc! { "$ifNull": ["$ratings.sum", 0] };
+
+    doc! { "$set": {
+        "rating": { "$cond": [
+            { "$gt": [count.clone(), 0] },
+            { "$divide": [sum.clone(), count.clone()] },
+            null
+        ] },
+        "weighted_rating": { "$cond": [
+            { "$gt": [{ "$add": [count.clone(), prior_weight] }, 0] },
+            { "$divide": [
+                { "$add": [prior_mean * prior_weight, sum] },
+                { "$add": [count, prior_weight] }
+            ] },
+            null
+        ] },
+    } }
+}
+
+fn item(item_type: &str, item_id: &str) -> Option<(&'static str, ObjectId)> {
+    let collection = ITEM_COLLECTIONS
+        .iter()
+        .find(|(kind, _)| *kind == item_type)
+        .map(|(_, collection)| *collection)?;
+    Some((collection, ObjectId::parse_str(item_id).ok()?))
+}
+
+// Stars whose reviews were all edited or deleted keep a zero count; ignore those
+fn without_empty_stars(mut summary: RatingSummary) -> RatingSummary {
+    summary.histogram.retain(|_, count| *count != 0);
+    summary
+}
+
+fn env_f64(name: &str, default: f64) -> f64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,63 @@
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use super::{validate_key, Storage, StorageError};
+
+/// Stores objects as files under a root directory.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    fn path_for(&self, key: &str) -> Result<PathBuf, StorageError> {
+        validate_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, key: &str, _content_type: &str, data: Bytes) -> Result<(), StorageError> {
+        let path = self.path_for(key)?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| StorageError::Backend(e.to_string()))?;
+        }
+
+        // Write to a temporary file first so readers never see a partial object
+        let tmp_path = path.with_extension("partial");
+        tokio::fs::write(&tmp_path, &data)
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        match tokio::fs::read(self.path_for(key)?).await {
+            Ok(data) => Ok(Bytes::from(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(StorageError::NotFound),
+            Err(e) => Err(StorageError::Backend(e.to_string())),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match tokio::fs::remove_file(self.path_for(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(StorageError::Backend(e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+mod local;
+mod s3;
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound,
+    InvalidKey,
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "Object not found"),
+            StorageError::InvalidKey => write!(f, "Invalid object key"),
+            StorageError::Backend(msg) => write!(f, "Storage error: {}", msg),
+        }
+    }
+}
+
+/// Object storage for uploaded files. Keys are `/`-separated relative paths.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn put(&self, key: &str, content_type: &str, data: Bytes) -> Result<(), StorageError>;
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError>;
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+}
+
+/// Builds the backend selected by `STORAGE_BACKEND` (`local` when unset, or `s3`).
+///
+/// A typo is rejected rather than quietly storing uploads on local disk.
+pub fn from_env() -> Result<Arc<dyn Storage>, String> {
+    match env::var("STORAGE_BACKEND").as_deref() {
+        Ok("s3") => Ok(Arc::new(S3Storage::from_env()?)),
+        Ok("local") | Err(_) => {
+            let root = env::var("STORAGE_LOCAL_PATH").unwrap_or_else(|_| "./uploads".to_string());
+            Ok(Arc::new(LocalStorage::new(root)))
+        }
+        Ok(other) => Err(format!("STORAGE_BACKEND must be local or s3, not {:?}", other)),
+    }
+}
+
+// Keys come from our own upload handler, but never let one escape the storage root
+fn validate_key(key: &str) -> Result<(), StorageError> {
+    let valid = !key.is_empty()
+        && !key.starts_with('/')
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidKey)
+    }
+}
@@ -0,0 +1,131 @@
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::env;
+use crate::payments::required_env;
+use super::{validate_key, Storage, StorageError};
+
+/// S3-compatible object storage (AWS S3, MinIO, ...) using path-style URLs and
+/// SigV4 request signing.
+pub struct S3Storage {
+    client: reqwest::Client,
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Storage {
+    /// Fails unless the access keys are set, since every request would be rejected.
+    pub fn from_env() -> Result<Self, String> {
+        Ok(S3Storage {
+            client: reqwest::Client::new(),
+            endpoint: env::var("S3_ENDPOINT").unwrap_or_else(|_| "http://localhost:9000".to_string()),
+            bucket: env::var("S3_BUCKET").unwrap_or_else(|_| "marketplace".to_string()),
+            region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key: required_env("S3_ACCESS_KEY")?,
+            secret_key: required_env("S3_SECRET_KEY")?,
+        })
+    }
+
+    async fn send(&self, method: Method, key: &str, content_type: Option<&str>, body: Bytes) -> Result<reqwest::Response, StorageError> {
+        validate_key(key)?;
+
+        let url = Url::parse(&format!("{}/{}/{}", self.endpoint.trim_end_matches('/'), self.bucket, key))
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => return Err(StorageError::Backend("S3 endpoint has no host".to_string())),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method.as_str(),
+            url.path(),
+            host,
+            payload_hash,
+            amz_date,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+        let signature = hex::encode(hmac(&signing_key, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            self.access_key, scope, signature
+        );
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("Authorization", authorization)
+            .body(body);
+        if let Some(content_type) = content_type {
+            request = request.header("Content-Type", content_type);
+        }
+
+        request.send().await.map_err(|e| StorageError::Backend(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, key: &str, content_type: &str, data: Bytes) -> Result<(), StorageError> {
+        let response = self.send(Method::PUT, key, Some(content_type), data).await?;
+        check_status(response.status())
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        let response = self.send(Method::GET, key, None, Bytes::new()).await?;
+        check_status(response.status())?;
+        response.bytes().await.map_err(|e| StorageError::Backend(e.to_string()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let response = self.send(Method::DELETE, key, None, Bytes::new()).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            status => check_status(status),
+        }
+    }
+}
+
+fn check_status(status: StatusCode) -> Result<(), StorageError> {
+    match status {
+        s if s.is_success() => Ok(()),
+        StatusCode::NOT_FOUND => Err(StorageError::NotFound),
+        s => Err(StorageError::Backend(format!("S3 returned {}", s))),
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
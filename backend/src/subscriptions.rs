@@ -0,0 +1,659 @@
+//! Recurring access to a seller's products.
+//!
+//! Each billing period is charged through the payment gateway with the payment
+//! method the customer subscribed with; the job scheduler runs the renewal when
+//! a period ends. A failed renewal leaves the subscription `past_due` and keeps
+//! access open for a grace period (`SUBSCRIPTION_GRACE_DAYS`) while the charge
+//! is retried every `SUBSCRIPTION_RETRY_HOURS`; after that it expires.
+
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use futures::stream::TryStreamExt;
+use chrono::{DateTime, Duration, Months, Utc};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use crate::db::{is_duplicate_key, with_transaction};
+use crate::jobs;
+use crate::ledger;
+use crate::models::{Notification, Product, Subscription, SubscriptionCharge, SubscriptionPlan};
+use crate::money::MoneyError;
+use crate::payments::{PaymentError, PaymentGateway};
+
+pub const INTERVALS: [&str; 2] = ["month", "year"];
+
+// A first payment takes seconds; one still pending after this was abandoned
+const PENDING_TIMEOUT_MINUTES: i64 = 60;
+
+#[derive(Debug)]
+pub enum SubscriptionError {
+    NotFound,
+    Invalid(String),
+    Conflict(String),
+    Payment(PaymentError),
+    Database(mongodb::error::Error),
+}
+
+impl fmt::Display for SubscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionError::NotFound => write!(f, "Not found"),
+            SubscriptionError::Invalid(msg) | SubscriptionError::Conflict(msg) => write!(f, "{}", msg),
+            SubscriptionError::Payment(e) => write!(f, "{}", e),
+            SubscriptionError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for SubscriptionError {
+    fn from(e: mongodb::error::Error) -> Self {
+        SubscriptionError::Database(e)
+    }
+}
+
+impl From<MoneyError> for SubscriptionError {
+    fn from(e: MoneyError) -> Self {
+        SubscriptionError::Invalid(e.to_string())
+    }
+}
+
+/// End of a billing period starting at `start`.
+pub fn period_end(start: DateTime<Utc>, interval: &str) -> Option<DateTime<Utc>> {
+    let months = if interval == "year" { 12 } else { 1 };
+    start.checked_add_months(Months::new(months))
+}
+
+/// How long covered products stay available after a renewal fails.
+pub fn grace_period() -> Duration {
+    Duration::days(env_i64("SUBSCRIPTION_GRACE_DAYS", 3).max(0))
+}
+
+fn retry_delay() -> Duration {
+    Duration::hours(env_i64("SUBSCRIPTION_RETRY_HOURS", 24).max(1))
+}
+
+/// Whether `plan` includes `product`.
+pub fn covers(plan: &SubscriptionPlan, product: &Product) -> bool {
+    let product_id = product.id.map(|oid| oid.to_hex()).unwrap_or_default();
+    plan.seller_id == product.seller_id && (plan.product_ids.is_empty() || plan.product_ids.contains(&product_id))
+}
+
+/// The customer's subscriptions that currently grant access, with their plans.
+pub async fn live_subscriptions(
+    db: &Database,
+    customer_id: &str,
+) -> Result<Vec<(Subscription, SubscriptionPlan)>, mongodb::error::Error> {
+    let subscriptions: Vec<Subscription> = db
+        .collection::<Subscription>("subscriptions")
+        .find(
+            doc! {
+                "customer_id": customer_id,
+                "status": { "$in": ["active", "past_due"] },
+                "access_until": { "$gt": Utc::now() }
+            },
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+
+    let plan_oids: Vec<ObjectId> = subscriptions
+        .iter()
+        .filter_map(|s| ObjectId::parse_str(&s.plan_id).ok())
+        .collect();
+    let mut plans: HashMap<String, SubscriptionPlan> = db
+        .collection::<SubscriptionPlan>("subscription_plans")
+        .find(doc! { "_id": { "$in": plan_oids } }, None)
+        .await?
+        .try_collect::<Vec<SubscriptionPlan>>()
+        .await?
+        .into_iter()
+        .filter_map(|p| p.id.map(|oid| (oid.to_hex(), p)))
+        .collect();
+
+    Ok(subscriptions
+        .into_iter()
+        .filter_map(|s| {
+            let plan = plans.remove(&s.plan_id)?;
+            Some((s, plan))
+        })
+        .collect())
+}
+
+/// A subscription of the customer's that covers `product`, if any.
+pub async fn covering(
+    db: &Database,
+    customer_id: &str,
+    product: &Product,
+) -> Result<Option<Subscription>, mongodb::error::Error> {
+    Ok(live_subscriptions(db, customer_id)
+        .await?
+        .into_iter()
+        .find(|(_, plan)| covers(plan, product))
+        .map(|(subscription, _)| subscription))
+}
+
+/// Every product the customer's live subscriptions cover, with the subscription covering it.
+pub async fn covered_products(
+    db: &Database,
+    customer_id: &str,
+) -> Result<Vec<(Subscription, Product)>, mongodb::error::Error> {
+    let live = live_subscriptions(db, customer_id).await?;
+    if live.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let seller_ids: Vec<&str> = live.iter().map(|(_, plan)| plan.seller_id.as_str()).collect();
+    let products: Vec<Product> = db
+        .collection::<Product>("products")
+        .find(doc! { "seller_id": { "$in": seller_ids } }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(products
+        .into_iter()
+        .filter_map(|product| {
+            let (subscription, _) = live.iter().find(|(_, plan)| covers(plan, &product))?;
+            Some((subscription.clone(), product))
+        })
+        .collect())
+}
+
+/// Stores the subscription and charges its first period; it only becomes
+/// active once that payment goes through.
+pub async fn start(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    subscription: Subscription,
+) -> Result<Subscription, SubscriptionError> {
+    let subscriptions = db.collection::<Subscription>("subscriptions");
+
+    let subscription_oid = match subscriptions.insert_one(&subscription, None).await {
+        Ok(result) => result.inserted_id.as_object_id().ok_or(SubscriptionError::NotFound)?,
+        Err(e) if is_duplicate_key(&e) => {
+            return Err(SubscriptionError::Conflict("You are already subscribed to this plan".to_string()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let subscription = Subscription { id: Some(subscription_oid), ..subscription };
+
+    let charged = charge(
+        db,
+        gateway,
+        &subscription,
+        subscription.current_period_start,
+        subscription.current_period_end,
+    )
+    .await;
+
+    if let Err(e) = charged {
+        let reason = e.to_string();
+        subscriptions
+            .update_one(
+                doc! { "_id": subscription_oid, "status": "pending" },
+                doc! {
+                    "$set": { "status": "failed", "failure_reason": reason },
+                    "$unset": { "live_key": "" }
+                },
+                None,
+            )
+            .await?;
+        return Err(e);
+    }
+
+    schedule_renewal(db, subscription_oid, subscription.current_period_end).await?;
+
+    subscriptions
+        .find_one(doc! { "_id": subscription_oid }, None)
+        .await?
+        .ok_or(SubscriptionError::NotFound)
+}
+
+/// Fails subscriptions whose first payment never finished (e.g. the server
+/// stopped mid-charge), so they stop holding the customer's place on the plan.
+pub async fn release_abandoned(db: &Database) -> Result<(), mongodb::error::Error> {
+    let cutoff = Utc::now() - Duration::minutes(PENDING_TIMEOUT_MINUTES);
+    let result = db
+        .collection::<Subscription>("subscriptions")
+        .update_many(
+            doc! { "status": "pending", "created_at": { "$lt": cutoff } },
+            doc! {
+                "$set": { "status": "failed", "failure_reason": "The first payment was not completed" },
+                "$unset": { "live_key": "" }
+            },
+            None,
+        )
+        .await?;
+
+    if result.modified_count > 0 {
+        log::info!("Released {} abandoned subscriptions", result.modified_count);
+    }
+
+    Ok(())
+}
+
+/// Charges the period that starts when the current one ends. Run by the
+/// `subscription_renewal` job; a no-op for subscriptions that are no longer live
+/// or not yet due.
+pub async fn renew(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    subscription_oid: ObjectId,
+) -> Result<(), SubscriptionError> {
+    let subscriptions = db.collection::<Subscription>("subscriptions");
+    let subscription = match subscriptions.find_one(doc! { "_id": subscription_oid }, None).await? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    let now = Utc::now();
+    if !matches!(subscription.status.as_str(), "active" | "past_due") || now < subscription.current_period_end {
+        return Ok(());
+    }
+
+    if subscription.cancel_at_period_end {
+        subscriptions
+            .update_one(
+                doc! { "_id": subscription_oid, "status": &subscription.status },
+                doc! { "$set": { "status": "cancelled" }, "$unset": { "live_key": "" } },
+                None,
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let start = subscription.current_period_end;
+    let end = period_end(start, &subscription.interval)
+        .ok_or_else(|| SubscriptionError::Invalid("Billing period out of range".to_string()))?;
+
+    match charge(db, gateway, &subscription, start, end).await {
+        Ok(()) => {
+            schedule_renewal(db, subscription_oid, end).await?;
+            Ok(())
+        }
+        // Outages aren't the customer's fault; the job is retried with backoff
+        Err(SubscriptionError::Payment(PaymentError::Gateway(e))) => {
+            Err(SubscriptionError::Payment(PaymentError::Gateway(e)))
+        }
+        Err(SubscriptionError::Payment(e)) => renewal_failed(db, &subscription, &e.to_string()).await,
+        Err(e) => Err(e),
+    }
+}
+
+async fn renewal_failed(db: &Database, subscription: &Subscription, reason: &str) -> Result<(), SubscriptionError> {
+    let subscription_oid = subscription.id.ok_or(SubscriptionError::NotFound)?;
+    let subscriptions = db.collection::<Subscription>("subscriptions");
+    let now = Utc::now();
+
+    if now >= subscription.access_until {
+        subscriptions
+            .update_one(
+                doc! { "_id": subscription_oid, "status": { "$in": ["active", "past_due"] } },
+                doc! {
+                    "$set": { "status": "expired", "failure_reason": reason },
+                    "$inc": { "failed_attempts": 1 },
+                    "$unset": { "live_key": "" }
+                },
+                None,
+            )
+            .await?;
+        notify(
+            db,
+            subscription,
+            "subscription_expired",
+            format!("Your subscription has ended because the renewal payment failed: {}", reason),
+        )
+        .await;
+        return Ok(());
+    }
+
+    subscriptions
+        .update_one(
+            doc! { "_id": subscription_oid, "status": { "$in": ["active", "past_due"] } },
+            doc! {
+                "$set": { "status": "past_due", "failure_reason": reason },
+                "$inc": { "failed_attempts": 1 }
+            },
+            None,
+        )
+        .await?;
+
+    // The last attempt runs when the grace period ends and expires the subscription if it fails too
+    let retry_at = (now + retry_delay()).min(subscription.access_until);
+    jobs::schedule(
+        db,
+        "subscription_renewal",
+        retry_at,
+        doc! { "subscription_id": subscription_oid.to_hex() },
+        Some(format!("subscription_retry:{}:{}", subscription_oid.to_hex(), subscription.failed_attempts + 1)),
+    )
+    .await?;
+
+    notify(
+        db,
+        subscription,
+        "subscription_payment_failed",
+        format!(
+            "Your subscription renewal of {} failed: {}. Update your payment method before {} to keep access",
+            subscription.amount,
+            reason,
+            subscription.access_until.format("%Y-%m-%d")
+        ),
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Charges one billing period and, once paid, moves the subscription onto it.
+///
+/// A period has at most one charge that hasn't failed, so a retry picks up where
+/// the last attempt stopped instead of charging again: a captured payment is only
+/// recorded, and an attempt that never got that far is voided and started over.
+/// A payment that can't be recorded is refunded.
+async fn charge(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    subscription: &Subscription,
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+) -> Result<(), SubscriptionError> {
+    let subscription_oid = subscription.id.ok_or(SubscriptionError::NotFound)?;
+    let charges = db.collection::<SubscriptionCharge>("subscription_charges");
+    let period_key = format!("{}:{}", subscription_oid.to_hex(), period_start.timestamp());
+
+    let charge = match charges.find_one(doc! { "period_key": &period_key }, None).await? {
+        Some(charge) if charge.status == "completed" => return Ok(()),
+        Some(charge) => charge,
+        None => {
+            let charge = SubscriptionCharge {
+                id: Some(ObjectId::new()),
+                subscription_id: subscription_oid.to_hex(),
+                customer_id: subscription.customer_id.clone(),
+                seller_id: subscription.seller_id.clone(),
+                amount: subscription.amount.clone(),
+                tax_lines: subscription.tax_lines.clone(),
+                period_start,
+                period_end,
+                status: "pending".to_string(),
+                payment_intent_id: None,
+                failure_reason: None,
+                period_key: Some(period_key),
+                created_at: Utc::now(),
+            };
+            match charges.insert_one(&charge, None).await {
+                Ok(_) => charge,
+                Err(e) if is_duplicate_key(&e) => {
+                    return Err(SubscriptionError::Conflict("This billing period is already being charged".to_string()));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    };
+    let charge_oid = charge.id.ok_or(SubscriptionError::NotFound)?;
+
+    let intent_id = match (charge.status.as_str(), charge.payment_intent_id.clone()) {
+        ("captured", Some(intent_id)) => intent_id,
+        (_, previous) => {
+            // An earlier attempt stopped before capturing; make sure it never does
+            if let Some(previous) = previous {
+                if let Err(e) = gateway.cancel(&previous).await {
+                    log::error!("Subscription charge {} has an unresolved payment {}: {}", charge_oid.to_hex(), previous, e);
+                    return Err(SubscriptionError::Payment(PaymentError::Gateway(format!(
+                        "The previous payment attempt could not be voided: {}",
+                        e
+                    ))));
+                }
+            }
+
+            match pay(db, gateway, subscription, &charge).await {
+                Ok(intent_id) => intent_id,
+                Err(e) => {
+                    fail_charge(db, charge_oid, &e.to_string()).await?;
+                    return Err(SubscriptionError::Payment(e));
+                }
+            }
+        }
+    };
+
+    if let Err(e) = record_charge(db, subscription_oid, charge_oid, period_start, period_end).await {
+        let ended = e.get_custom::<SubscriptionEnded>().is_some();
+        let reason = if ended {
+            "Refunded because the subscription ended while it was being charged".to_string()
+        } else {
+            format!("Refunded because it could not be recorded: {}", e)
+        };
+        // The customer paid for a period they didn't get
+        match gateway.refund(&intent_id, None).await {
+            Ok(_) => fail_charge(db, charge_oid, &reason).await?,
+            Err(refund_error) => log::error!(
+                "Subscription charge {} was captured but neither recorded ({}) nor refunded ({})",
+                charge_oid.to_hex(),
+                e,
+                refund_error
+            ),
+        }
+        if ended {
+            return Err(SubscriptionError::Conflict("The subscription ended while it was being charged".to_string()));
+        }
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+// Takes the payment for a pending charge and marks it captured, returning the intent
+async fn pay(
+    db: &Database,
+    gateway: &dyn PaymentGateway,
+    subscription: &Subscription,
+    charge: &SubscriptionCharge,
+) -> Result<String, PaymentError> {
+    let charges = db.collection::<SubscriptionCharge>("subscription_charges");
+    let charge_oid = charge.id.ok_or_else(|| PaymentError::InvalidRequest("Charge has no id".to_string()))?;
+    let metadata = HashMap::from([
+        ("subscription_id".to_string(), charge.subscription_id.clone()),
+        ("subscription_charge_id".to_string(), charge_oid.to_hex()),
+        ("customer_id".to_string(), subscription.customer_id.clone()),
+    ]);
+
+    let intent = gateway.create_intent(charge.amount.amount, &charge.amount.currency, &metadata).await?;
+    // Webhooks and refunds find the payment through its intent, so don't charge without it
+    charges
+        .update_one(doc! { "_id": charge_oid }, doc! { "$set": { "payment_intent_id": &intent.id } }, None)
+        .await
+        .map_err(|_| PaymentError::Gateway("Failed to record the payment".to_string()))?;
+    gateway.confirm(&intent.id, &subscription.payment_method).await?;
+    gateway.capture(&intent.id).await?;
+
+    let captured = charges
+        .update_one(
+            doc! { "_id": charge_oid, "status": "pending" },
+            doc! { "$set": { "status": "captured" } },
+            None,
+        )
+        .await;
+    if captured.is_err() {
+        // A retry couldn't tell the payment went through, so give it back now
+        gateway.refund(&intent.id, None).await?;
+        return Err(PaymentError::Gateway("Failed to record the payment".to_string()));
+    }
+
+    Ok(intent.id)
+}
+
+// Aborts `record_charge` when the subscription was cancelled or expired meanwhile
+struct SubscriptionEnded;
+
+// Completes the charge and moves the subscription onto its period, all or nothing
+async fn record_charge(
+    db: &Database,
+    subscription_oid: ObjectId,
+    charge_oid: ObjectId,
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+) -> Result<(), mongodb::error::Error> {
+    let db_handle = db.clone();
+    let access_until = period_end + grace_period();
+
+    with_transaction(db, move |session| {
+        let db = db_handle.clone();
+        Box::pin(async move {
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let charge = match db
+                .collection::<SubscriptionCharge>("subscription_charges")
+                .find_one_and_update_with_session(
+                    doc! { "_id": charge_oid, "status": { "$in": ["pending", "captured"] } },
+                    doc! { "$set": { "status": "completed" } },
+                    options,
+                    &mut *session,
+                )
+                .await?
+            {
+                Some(c) => c,
+                None => return Ok(()),
+            };
+
+            // Never brings back a subscription that ended while it was being charged
+            let moved = db
+                .collection::<Subscription>("subscriptions")
+                .update_one_with_session(
+                    doc! { "_id": subscription_oid, "status": { "$in": ["pending", "active", "past_due"] } },
+                    doc! {
+                        "$set": {
+                            "status": "active",
+                            "current_period_start": period_start,
+                            "current_period_end": period_end,
+                            "access_until": access_until,
+                            "failed_attempts": 0,
+                            "failure_reason": null
+                        }
+                    },
+                    None,
+                    &mut *session,
+                )
+                .await?;
+            if moved.matched_count == 0 {
+                return Err(mongodb::error::Error::custom(SubscriptionEnded));
+            }
+
+            ledger::record_subscription_charge(&db, session, &charge).await
+        })
+    })
+    .await
+}
+
+// A failed charge gives up its period, so the next attempt starts a new one
+async fn fail_charge(db: &Database, charge_oid: ObjectId, reason: &str) -> Result<(), mongodb::error::Error> {
+    db.collection::<SubscriptionCharge>("subscription_charges")
+        .update_one(
+            doc! { "_id": charge_oid, "status": { "$in": ["pending", "captured"] } },
+            doc! {
+                "$set": { "status": "failed", "failure_reason": reason },
+                "$unset": { "period_key": "" }
+            },
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+/// Stops renewing at the end of the paid period; a past-due subscription ends now.
+pub async fn cancel(db: &Database, subscription_oid: ObjectId, customer_id: &str) -> Result<Subscription, SubscriptionError> {
+    let subscriptions = db.collection::<Subscription>("subscriptions");
+    let subscription = subscriptions
+        .find_one(doc! { "_id": subscription_oid, "customer_id": customer_id }, None)
+        .await?
+        .ok_or(SubscriptionError::NotFound)?;
+
+    let update = match subscription.status.as_str() {
+        "active" => doc! {
+            "$set": { "cancel_at_period_end": true, "access_until": subscription.current_period_end }
+        },
+        "past_due" => doc! {
+            "$set": { "status": "cancelled", "access_until": Utc::now() },
+            "$unset": { "live_key": "" }
+        },
+        _ => return Err(SubscriptionError::Invalid("Subscription is not active".to_string())),
+    };
+
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    subscriptions
+        .find_one_and_update(doc! { "_id": subscription_oid, "status": &subscription.status }, update, options)
+        .await?
+        .ok_or_else(|| SubscriptionError::Conflict("Subscription changed; try again".to_string()))
+}
+
+/// Switches the payment method; a past-due subscription is retried straight away.
+pub async fn update_payment_method(
+    db: &Database,
+    subscription_oid: ObjectId,
+    customer_id: &str,
+    payment_method: &str,
+) -> Result<Subscription, SubscriptionError> {
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let subscription = db
+        .collection::<Subscription>("subscriptions")
+        .find_one_and_update(
+            doc! { "_id": subscription_oid, "customer_id": customer_id, "status": { "$in": ["active", "past_due"] } },
+            doc! { "$set": { "payment_method": payment_method } },
+            options,
+        )
+        .await?
+        .ok_or(SubscriptionError::NotFound)?;
+
+    if subscription.status == "past_due" {
+        let now = Utc::now();
+        jobs::schedule(
+            db,
+            "subscription_renewal",
+            now,
+            doc! { "subscription_id": subscription_oid.to_hex() },
+            Some(format!("subscription_retry:{}:method:{}", subscription_oid.to_hex(), now.timestamp())),
+        )
+        .await?;
+    }
+
+    Ok(subscription)
+}
+
+async fn schedule_renewal(db: &Database, subscription_oid: ObjectId, at: DateTime<Utc>) -> Result<(), mongodb::error::Error> {
+    jobs::schedule(
+        db,
+        "subscription_renewal",
+        at,
+        doc! { "subscription_id": subscription_oid.to_hex() },
+        Some(format!("subscription_renewal:{}:{}", subscription_oid.to_hex(), at.timestamp())),
+    )
+    .await
+}
+
+async fn notify(db: &Database, subscription: &Subscription, kind: &str, message: String) {
+    let notification = Notification {
+        id: None,
+        user_id: subscription.customer_id.clone(),
+        kind: kind.to_string(),
+        message,
+        reference_id: subscription.id.map(|oid| oid.to_hex()),
+        read: false,
+        created_at: Utc::now(),
+    };
+
+    if let Err(e) = db.collection::<Notification>("notifications").insert_one(notification, None).await {
+        log::warn!("Failed to send {} notification: {}", kind, e);
+    }
+}
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,162 @@
+//! Sales tax, VAT and GST for purchases, driven by the rules in `TAX_RULES_FILE`.
+
+use mongodb::{Database, bson::{doc, oid::ObjectId}};
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use crate::models::{BillingAddress, TaxLine, TaxProfile, User};
+use crate::money::{Money, MoneyError};
+
+/// A versioned set of tax rules; purchases record the version they were taxed under.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaxRules {
+    pub version: String,
+    /// Tax categories products can be assigned
+    pub categories: Vec<String>,
+    pub jurisdictions: Vec<Jurisdiction>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Jurisdiction {
+    /// ISO 3166-1 alpha-2
+    pub country: String,
+    /// State or province; unset for taxes levied across the whole country
+    #[serde(default)]
+    pub region: Option<String>,
+    pub name: String,
+    /// vat, gst or sales_tax
+    pub kind: String,
+    /// Rate per tax category; categories missing here are not taxed
+    pub rates: HashMap<String, f64>,
+    /// The marketplace collects the tax whether or not the seller is registered
+    #[serde(default)]
+    pub marketplace_collects: bool,
+    /// Business buyers with a tax ID, buying from a seller established elsewhere,
+    /// account for the tax themselves
+    #[serde(default)]
+    pub reverse_charge: bool,
+}
+
+impl Jurisdiction {
+    /// `GB`, or `US-WA` for a region; what seller registrations refer to.
+    pub fn code(&self) -> String {
+        match &self.region {
+            Some(region) => format!("{}-{}", self.country, region),
+            None => self.country.clone(),
+        }
+    }
+
+    fn applies_to(&self, buyer: &BillingAddress) -> bool {
+        self.country.eq_ignore_ascii_case(&buyer.country)
+            && match &self.region {
+                Some(region) => buyer.region.as_deref().is_some_and(|r| r.eq_ignore_ascii_case(region)),
+                None => true,
+            }
+    }
+}
+
+/// The tax due on one sale.
+pub struct TaxCalculation {
+    pub rules_version: String,
+    pub lines: Vec<TaxLine>,
+    /// Net price plus tax
+    pub total: Money,
+}
+
+impl TaxRules {
+    pub fn is_category(&self, category: &str) -> bool {
+        self.categories.iter().any(|c| c == category)
+    }
+
+    /// Taxes `net` for a buyer at `buyer` purchasing from a seller with `seller`'s profile.
+    pub fn calculate(
+        &self,
+        net: &Money,
+        category: &str,
+        buyer: &BillingAddress,
+        seller: &TaxProfile,
+    ) -> Result<TaxCalculation, MoneyError> {
+        let mut lines = Vec::new();
+
+        for jurisdiction in self.jurisdictions.iter().filter(|j| j.applies_to(buyer)) {
+            let rate = match jurisdiction.rates.get(category) {
+                Some(rate) => *rate,
+                None => continue,
+            };
+
+            let code = jurisdiction.code();
+            let registered = seller.registrations.iter().any(|r| r.jurisdiction.eq_ignore_ascii_case(&code));
+            if !registered && !jurisdiction.marketplace_collects {
+                continue;
+            }
+
+            let established_elsewhere = seller
+                .country
+                .as_deref()
+                .is_none_or(|country| !country.eq_ignore_ascii_case(&buyer.country));
+            let reverse_charge = jurisdiction.reverse_charge
+                && buyer.tax_id.as_deref().is_some_and(|id| !id.trim().is_empty())
+                && established_elsewhere;
+
+            let amount = if reverse_charge {
+                Money::zero(&net.currency)
+            } else {
+                net.percentage(rate)?
+            };
+
+            lines.push(TaxLine {
+                jurisdiction: code,
+                name: jurisdiction.name.clone(),
+                kind: jurisdiction.kind.clone(),
+                rate,
+                reverse_charge,
+                amount,
+            });
+        }
+
+        let tax = Money::sum(&net.currency, lines.iter().map(|l| &l.amount))?;
+        Ok(TaxCalculation {
+            rules_version: self.version.clone(),
+            total: net.checked_add(&tax)?,
+            lines,
+        })
+    }
+}
+
+/// Loads the rules from `TAX_RULES_FILE` (default `tax_rules.json`), relative to
+/// the working directory.
+pub fn load_from_env() -> Result<TaxRules, String> {
+    let path = env::var("TAX_RULES_FILE").unwrap_or_else(|_| "tax_rules.json".to_string());
+    let data = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let rules: TaxRules = serde_json::from_slice(&data).map_err(|e| format!("{}: {}", path, e))?;
+
+    for jurisdiction in &rules.jurisdictions {
+        if let Some(category) = jurisdiction.rates.keys().find(|c| !rules.is_category(c)) {
+            return Err(format!("{}: {} taxes unknown category {}", path, jurisdiction.code(), category));
+        }
+        if jurisdiction.rates.values().any(|rate| !(0.0..1.0).contains(rate)) {
+            return Err(format!("{}: {} has a rate outside 0..1", path, jurisdiction.code()));
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Tax profiles of the given sellers, keyed by user ID.
+pub async fn seller_profiles(db: &Database, seller_ids: &[String]) -> Result<HashMap<String, TaxProfile>, mongodb::error::Error> {
+    let oids: Vec<ObjectId> = seller_ids.iter().filter_map(|id| ObjectId::parse_str(id).ok()).collect();
+
+    let sellers: Vec<User> = db
+        .collection::<User>("users")
+        .find(doc! { "_id": { "$in": oids } }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(sellers
+        .into_iter()
+        .filter_map(|u| u.id.map(|oid| (oid.to_hex(), u.tax_profile)))
+        .collect())
+}
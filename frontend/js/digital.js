@@ -1,4 +1,5 @@
 let currentProductId = null;
+let currentIsBundle = false;
 
 async function loadProducts() {
     try {
@@ -19,12 +20,14 @@ function displayProducts(products) {
                 <h3 class="listing-title">${product.title}</h3>
                 <p class="listing-description">${product.description}</p>
                 <div class="listing-meta">
-                    <span class="listing-price">$${product.price}</span>
+                    <span class="listing-price">${formatPrice(product.price)}</span>
                     <span class="listing-rating">⭐ ${product.rating || '5.0'}</span>
                 </div>
-                <p style="font-size: 0.9rem; color: #6b7280;">💾 ${product.file_type} • ${product.downloads || 0} downloads</p>
+                ${product.bundle
+                    ? `<p style="font-size: 0.9rem; color: #6b7280;">📦 Bundle of ${product.products.length} products • ${formatPrice(product.products_total)} separately</p>`
+                    : `<p style="font-size: 0.9rem; color: #6b7280;">💾 ${product.file_type} • ${product.downloads || 0} downloads</p>`}
                 <div class="listing-actions">
-                    <button class="btn-primary btn-small" onclick="openPurchaseModal(${product.id})">Buy Now</button>
+                    <button class="btn-primary btn-small" onclick="openPurchaseModal(${product.id}, ${!!product.bundle})">Buy Now</button>
                     <button class="btn-secondary btn-small" onclick="viewProductDetails(${product.id})">Preview</button>
                 </div>
             </div>
@@ -32,13 +35,14 @@ function displayProducts(products) {
     `).join('');
 }
 
-function openPurchaseModal(productId) {
+function openPurchaseModal(productId, isBundle = false) {
     const token = localStorage.getItem('token');
     if (!token) {
         alert('Please login to purchase');
         return;
     }
     currentProductId = productId;
+    currentIsBundle = isBundle;
     document.getElementById('purchaseModal').style.display = 'block';
 }
 
@@ -49,13 +53,28 @@ function closePurchaseModal() {
 document.getElementById('purchaseForm').onsubmit = async (e) => {
     e.preventDefault();
     
+    // Tax is calculated from the billing address, so the API requires it
     const purchaseData = {
         product_id: currentProductId,
-        payment_method: document.getElementById('paymentMethod').value
+        payment_method: document.getElementById('paymentMethod').value,
+        billing_address: {
+            name: document.getElementById('billingName').value.trim(),
+            line1: document.getElementById('billingLine1').value.trim(),
+            line2: document.getElementById('billingLine2').value.trim() || null,
+            city: document.getElementById('billingCity').value.trim(),
+            postal_code: document.getElementById('billingPostalCode').value.trim(),
+            country: document.getElementById('billingCountry').value.trim().toUpperCase(),
+            region: document.getElementById('billingRegion').value.trim().toUpperCase() || null
+        }
     };
+    const couponCode = document.getElementById('couponCode').value.trim();
+    if (couponCode) {
+        purchaseData.coupon_codes = [couponCode];
+    }
 
     try {
-        const response = await fetch(`${API_URL}/purchases`, {
+        const url = currentIsBundle ? `${API_URL}/bundles/${currentProductId}/purchase` : `${API_URL}/purchases`;
+        const response = await fetch(url, {
             method: 'POST',
             headers: getAuthHeaders(),
             body: JSON.stringify(purchaseData)
@@ -64,7 +83,9 @@ document.getElementById('purchaseForm').onsubmit = async (e) => {
         const data = await response.json();
 
         if (response.ok) {
-            alert('Purchase successful! Download link: ' + data.download_url);
+            alert(data.items
+                ? `Purchase successful! ${data.items.length} products were added to your library.`
+                : 'Purchase successful! Download link: ' + data.download_url);
             closePurchaseModal();
         } else {
             alert('Purchase failed. Please try again.');
@@ -0,0 +1,6 @@
+// Prices come from the API as { amount, currency } in the currency's minor units
+function formatPrice(price) {
+    const format = new Intl.NumberFormat(undefined, { style: 'currency', currency: price.currency });
+    const digits = format.resolvedOptions().maximumFractionDigits;
+    return format.format(price.amount / 10 ** digits);
+}
@@ -35,10 +35,10 @@ function displayNicheProducts(products) {
                 <h3 class="listing-title">${product.title}</h3>
                 <p class="listing-description">${product.description}</p>
                 <div class="listing-meta">
-                    <span class="listing-price">$${product.price}</span>
+                    <span class="listing-price">${formatPrice(product.price)}</span>
                     <span class="listing-rating">⭐ ${product.rating || '5.0'}</span>
                 </div>
-                <p style="font-size: 0.9rem; color: #6b7280;">${product.category} • ${product.downloads || 0} sales</p>
+                <p style="font-size: 0.9rem; color: #6b7280;">${product.category} • ${product.sales || 0} sales</p>
                 <div class="listing-actions">
                     <button class="btn-primary btn-small" onclick="purchaseNicheProduct(${product.id})">Purchase</button>
                     <button class="btn-secondary btn-small" onclick="viewNicheDetails(${product.id})">View</button>
@@ -19,7 +19,7 @@ function displayServices(services) {
                 <h3 class="listing-title">${service.title}</h3>
                 <p class="listing-description">${service.description}</p>
                 <div class="listing-meta">
-                    <span class="listing-price">$${service.price}/hr</span>
+                    <span class="listing-price">${formatPrice(service.price)}/hr</span>
                     <span class="listing-rating">⭐ ${service.rating || '5.0'}</span>
                 </div>
                 <p style="font-size: 0.9rem; color: #6b7280;">📍 ${service.location}</p>
@@ -53,7 +53,8 @@ document.getElementById('bookingForm').onsubmit = async (e) => {
         service_id: currentServiceId,
         booking_date: document.getElementById('bookingDate').value,
         booking_time: document.getElementById('bookingTime').value,
-        notes: document.getElementById('bookingNotes').value
+        notes: document.getElementById('bookingNotes').value,
+        payment_method: document.getElementById('bookingPaymentMethod').value
     };
 
     try {